@@ -15,7 +15,9 @@ pub struct Args {
     #[clap(short, long, parse(from_os_str), default_value = "out/out.json")]
     pub out: PathBuf,
 
-    /// the format of the input file, e.g. 'programs-list' for a simple JSON array of programs
+    /// the format of the input file, e.g. 'programs-list' for a simple JSON array of programs,
+    /// 'weighted-programs-list' for a JSON array of {"program", "task", "weight"} objects (task and weight optional),
+    /// 'jsonl' for one program string or such object per line (identical programs are merged as they're read)
     /// or 'dreamcoder' for a JSON in the style expected by the original dreamcoder codebase.
     /// See [formats.rs] for options or to add new ones.
     #[clap(long, arg_enum, default_value = "programs-list")]
@@ -25,23 +27,84 @@ pub struct Args {
     #[clap(long)]
     pub save_rewritten: Option<PathBuf>,
 
+    /// json file of held out programs (in the same format as the input file) to rewrite with the library after
+    /// each iteration, reporting their compression in the output json alongside the train compression
+    #[clap(long, parse(from_os_str))]
+    pub test_file: Option<PathBuf>,
+
+    /// writes the input DreamCoder DSL with a new production for each abstraction (with its type, whether it is
+    /// reversible, and an initial log probability) to this file. Requires --fmt=dreamcoder and a DSL whose productions
+    /// have types
+    #[clap(long, parse(from_os_str))]
+    pub dreamcoder_dsl_out: Option<PathBuf>,
+
+    /// the log probability to give new productions in --dreamcoder-dsl-out
+    #[clap(long, default_value = "0.0")]
+    pub dreamcoder_dsl_log_probability: f64,
+
+    #[clap(flatten)]
+    pub weights: PosteriorWeightsConfig,
+
     #[clap(flatten)]
     pub multistep: MultistepCompressionConfig,
 }
 
-fn main() {
-    let args = Args::parse();
+fn main() -> Result<(), StitchError> {
+    let mut args = Args::parse();
+    if args.dreamcoder_dsl_out.is_some() && !matches!(args.fmt, InputFormat::Dreamcoder) {
+        return Err(StitchError::InvalidConfig(
+            "--dreamcoder-dsl-out requires --fmt=dreamcoder".to_string(),
+        ));
+    }
 
-    let input = args.fmt.load_programs_and_tasks(&args.file).unwrap();
+    let mut input = args.fmt.load_programs_and_tasks(&args.file)?;
+    args.weights.apply(&mut input, &mut args.multistep)?;
+    args.multistep
+        .step
+        .cost
+        .apply_log_probabilities(&input.log_probabilities)?;
+    args.multistep
+        .step
+        .apply_dreamcoder_types(&input.prim_types)?;
+    args.multistep
+        .step
+        .apply_dreamcoder_reversibility(&input.reversible_prims);
+    let test_programs = match &args.test_file {
+        Some(test_file) => {
+            let mut test_input = args.fmt.load_programs_and_tasks(test_file)?;
+            test_input.expand_program_set();
+            Some(test_input.train_programs)
+        }
+        None => None,
+    };
+    if args.multistep.named_lambdas {
+        // named lambdas are converted to de Bruijn form from the program strings
+        input.expand_program_set();
+    }
 
-    let (step_results, json_res) = multistep_compression(
-        &input.train_programs,
-        input.tasks,
-        None,
-        input.name_mapping,
-        None,
-        &args.multistep,
-    );
+    let (step_results, json_res) = match input.program_set.take() {
+        // hand over the programs that were read straight into a set without going through strings
+        Some(program_set) => multistep_compression_parsed(
+            program_set,
+            input.tasks,
+            input.weights,
+            input.name_mapping,
+            None,
+            test_programs,
+            &args.multistep,
+            std::sync::Arc::new(NoObserver),
+            &CancellationHandle::new(),
+        ),
+        None => multistep_compression(
+            &input.train_programs,
+            input.tasks,
+            input.weights,
+            input.name_mapping,
+            None,
+            test_programs,
+            &args.multistep,
+        ),
+    }?;
 
     let out_path = &args.out;
     if let Some(out_path_dir) = out_path.parent() {
@@ -54,12 +117,24 @@ fn main() {
     if !args.multistep.silent {
         println!("Wrote to {out_path:?}")
     };
-    if let Some(out_path) = args.save_rewritten {
+    // --dreamcoder-dsl-out was checked to come with --fmt=dreamcoder up front, which always has a DSL
+    if let (Some(out_path), Some(dsl)) = (&args.dreamcoder_dsl_out, &input.dsl) {
+        let dsl = dreamcoder_dsl_of_step_results(
+            dsl,
+            &step_results,
+            args.dreamcoder_dsl_log_probability,
+        )?;
+        std::fs::write(out_path, serde_json::to_string_pretty(&dsl).unwrap()).unwrap();
+        if !args.multistep.silent {
+            println!("Wrote DreamCoder DSL to {out_path:?}")
+        };
+    }
+    if let Some(out_path) = &args.save_rewritten {
         if !args.multistep.silent {
             println!("Wrote rewritten things to {out_path:?}")
         };
         std::fs::write(
-            &out_path,
+            out_path,
             serde_json::to_string_pretty(
                 &step_results
                     .iter()
@@ -67,11 +142,18 @@ fn main() {
                     .unwrap()
                     .rewritten
                     .iter()
-                    .map(|p| p.to_string())
+                    .map(|p| {
+                        if args.multistep.named_lambdas {
+                            de_bruijn_to_named(&p.to_string()).unwrap()
+                        } else {
+                            p.to_string()
+                        }
+                    })
                     .collect::<Vec<String>>(),
             )
             .unwrap(),
         )
         .unwrap();
     }
+    Ok(())
 }
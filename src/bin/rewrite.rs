@@ -47,7 +47,9 @@ pub struct RewriteArgs {
     )]
     pub out: PathBuf,
 
-    /// the format of the input file, e.g. 'programs-list' for a simple JSON array of programs
+    /// the format of the input file, e.g. 'programs-list' for a simple JSON array of programs,
+    /// 'weighted-programs-list' for a JSON array of {"program", "task", "weight"} objects (task and weight optional),
+    /// 'jsonl' for one program string or such object per line (identical programs are merged as they're read)
     /// or 'dreamcoder' for a JSON in the style expected by the original dreamcoder codebase.
     /// See [formats.rs] for options or to add new ones.
     #[clap(long, arg_enum, default_value = "programs-list")]
@@ -57,6 +59,18 @@ pub struct RewriteArgs {
     #[clap(long)]
     pub dreamcoder_output: bool,
 
+    /// rewrite by matching the invention bodies directly against the programs instead of rerunning compression
+    #[clap(long)]
+    pub direct: bool,
+
+    /// rewrite with the whole library at once, choosing the minimum cost cover of each program instead of
+    /// applying inventions one at a time in order
+    #[clap(long)]
+    pub joint: bool,
+
+    #[clap(flatten)]
+    pub weights: PosteriorWeightsConfig,
+
     #[clap(flatten)]
     pub cost: MultistepCompressionConfig,
 }
@@ -72,14 +86,28 @@ pub struct DcFrontier {
     pub programs: Vec<DcProgram>,
 }
 
-fn main() {
-    let args = RewriteArgs::parse();
+fn main() -> Result<(), StitchError> {
+    let mut args = RewriteArgs::parse();
 
     // Read in the programs and any previous inventions from the DSL.
-    let input = args
-        .fmt
-        .load_programs_and_tasks(&args.program_file)
-        .unwrap();
+    let mut input = args.fmt.load_programs_and_tasks(&args.program_file)?;
+    args.weights.apply(&mut input, &mut args.cost)?;
+    // rewriting goes through program strings
+    input.expand_program_set();
+    // rewriting works on de Bruijn programs, the names are put back at the end
+    let named_lambdas = args.cost.named_lambdas;
+    if named_lambdas {
+        input.train_programs = programs_from_named(&input.train_programs)?;
+        args.cost.named_lambdas = false;
+    }
+    args.cost
+        .step
+        .cost
+        .apply_log_probabilities(&input.log_probabilities)?;
+    args.cost.step.apply_dreamcoder_types(&input.prim_types)?;
+    args.cost
+        .step
+        .apply_dreamcoder_reversibility(&input.reversible_prims);
 
     // Read in library to rewrite.
     // This should be in {abstractions: [{name: , body:}]}
@@ -111,12 +139,43 @@ fn main() {
             name: invention["name"].as_str().unwrap().parse().unwrap(),
         })
         .collect();
-    println!("Number of inventions: {}", inventions.len());
+    if !args.cost.silent {
+        println!("Number of inventions: {}", inventions.len());
+    }
 
     let mut rewritten_frontiers: HashMap<String, Vec<String>> = HashMap::new();
 
-    let rewritten: Vec<String> =
-        rewrite_with_inventions(&input.train_programs, &inventions[..], &args.cost).0;
+    let rewritten: Vec<String> = if args.direct || args.joint {
+        let programs: Vec<ExprOwned> = input.train_exprs()?;
+        let (rewritten, uses) = if args.joint {
+            rewrite_with_library(&programs, &inventions[..], &args.cost.step)?
+        } else {
+            rewrite_with_inventions_direct(&programs, &inventions[..], &args.cost.step)
+        };
+        if !args.cost.silent {
+            for (inv, uses) in inventions.iter().zip(uses.iter()) {
+                println!("{}: {} uses", inv.name, uses);
+            }
+        }
+        rewritten.iter().map(|e| e.to_string()).collect()
+    } else {
+        rewrite_with_inventions(
+            &input.train_programs,
+            input.tasks.clone(),
+            input.weights.clone(),
+            &inventions[..],
+            &args.cost,
+        )?
+        .0
+    };
+    let rewritten: Vec<String> = if named_lambdas {
+        rewritten
+            .iter()
+            .map(|p| de_bruijn_to_named(p).unwrap())
+            .collect()
+    } else {
+        rewritten
+    };
 
     match args.fmt {
         InputFormat::Dreamcoder => {
@@ -167,5 +226,20 @@ fn main() {
             let json: Value = json!({ "rewritten": rewritten.iter().map(|p| p.to_string()).collect::<Vec<String>>() });
             std::fs::write(&args.out, serde_json::to_string_pretty(&json).unwrap()).unwrap();
         }
+        InputFormat::WeightedProgramsList | InputFormat::Jsonl => {
+            // keep the task and weight of each program so the output can be read back in the same format
+            let rewritten: Vec<WeightedProgram> = rewritten
+                .iter()
+                .enumerate()
+                .map(|(i, p)| WeightedProgram {
+                    program: p.to_string(),
+                    task: input.tasks.as_ref().map(|tasks| tasks[i].clone()),
+                    weight: input.weights.as_ref().map(|weights| weights[i]),
+                })
+                .collect();
+            let json: Value = json!({ "rewritten": rewritten });
+            std::fs::write(&args.out, serde_json::to_string_pretty(&json).unwrap()).unwrap();
+        }
     }
+    Ok(())
 }
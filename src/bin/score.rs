@@ -0,0 +1,91 @@
+/*
+score.rs: Utility entrypoint for scoring hand written abstractions against a corpus without running the search,
+eg to see why compression didn't pick one.
+
+Sample command: cargo run --bin=score --release -- data/cogsci/nuts-bolts.json "(T (l #0) (M 1 0 #1 #2))"
+*/
+
+use clap::Parser;
+use serde::Serialize;
+use std::path::PathBuf;
+use stitch_core::*;
+
+/// Score abstractions
+#[derive(Parser, Debug, Serialize)]
+#[clap(name = "Score")]
+pub struct ScoreArgs {
+    /// json file to read the programs from
+    #[clap(parse(from_os_str))]
+    pub file: PathBuf,
+
+    /// abstraction bodies to score, like "(+ #0 (* #1 #0))"
+    #[clap(required = true)]
+    pub bodies: Vec<String>,
+
+    /// json output file, otherwise the scores are printed
+    #[clap(short, long, parse(from_os_str))]
+    pub out: Option<PathBuf>,
+
+    /// the format of the input file, e.g. 'programs-list' for a simple JSON array of programs,
+    /// 'weighted-programs-list' for a JSON array of {"program", "task", "weight"} objects (task and weight optional),
+    /// 'jsonl' for one program string or such object per line (identical programs are merged as they're read)
+    /// or 'dreamcoder' for a JSON in the style expected by the original dreamcoder codebase.
+    /// See [formats.rs] for options or to add new ones.
+    #[clap(long, arg_enum, default_value = "programs-list")]
+    pub fmt: InputFormat,
+
+    #[clap(flatten)]
+    pub weights: PosteriorWeightsConfig,
+
+    #[clap(flatten)]
+    pub multistep: MultistepCompressionConfig,
+}
+
+fn main() {
+    let mut args = ScoreArgs::parse();
+    args.multistep.silent = true;
+
+    let mut input = args.fmt.load_programs_and_tasks(&args.file).unwrap();
+    args.weights.apply(&mut input, &mut args.multistep).unwrap();
+    // scoring goes through program strings
+    input.expand_program_set();
+    args.multistep
+        .step
+        .cost
+        .apply_log_probabilities(&input.log_probabilities)
+        .unwrap();
+    args.multistep
+        .step
+        .apply_dreamcoder_types(&input.prim_types)
+        .unwrap();
+
+    let scores: Vec<serde_json::Value> = args
+        .bodies
+        .iter()
+        .map(|body| {
+            score_abstraction(
+                &input.train_programs,
+                input.tasks.clone(),
+                input.weights.clone(),
+                body,
+                &args.multistep,
+            )
+            .unwrap()
+            .json()
+        })
+        .collect();
+    let json_res = serde_json::to_string_pretty(&scores).unwrap();
+
+    match &args.out {
+        Some(out_path) => {
+            if let Some(out_path_dir) = out_path.parent() {
+                if !out_path_dir.exists() {
+                    std::fs::create_dir_all(out_path_dir).unwrap();
+                }
+            }
+            std::fs::write(out_path, json_res).unwrap();
+            println!("Wrote to {out_path:?}");
+        }
+        None => println!("{json_res}"),
+    }
+}
@@ -31,20 +31,12 @@ fn get_stats(data: &[i32]) -> (f64, f64) {
 
 fn main() {
     let args = Args::parse();
-    let programs = args
+    let programs: Vec<ExprOwned> = args
         .fmt
         .load_programs_and_tasks(&args.file)
         .unwrap()
-        .train_programs;
-
-    let programs: Vec<ExprOwned> = programs
-        .iter()
-        .map(|p| {
-            let mut set = ExprSet::empty(Order::ChildFirst, false, false);
-            let idx = set.parse_extend(p).unwrap();
-            ExprOwned::new(set, idx)
-        })
-        .collect();
+        .train_exprs()
+        .unwrap();
 
     let cost_fn = ExprCost::dreamcoder();
     let length_fn = ExprCost::num_terminals();
@@ -13,6 +13,7 @@ use std::convert::TryInto;
 use std::fmt::{self, Display, Formatter};
 use std::hash::Hash;
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 
@@ -58,6 +59,42 @@ pub struct MultistepCompressionConfig {
     #[clap(long)]
     pub verbose_rewrite: bool,
 
+    /// Instead of greedily committing to the best abstraction each iteration, keep the this many partial
+    /// libraries with the lowest corpus cost, extend each with its top `inv_candidates` abstractions, and
+    /// return the library with the lowest final corpus cost. A beam of 1 is the usual greedy search. Ignored with `follow`
+    #[clap(long, default_value = "1")]
+    pub lookahead_beam: usize,
+
+    /// Learn up to this many inventions per iteration: along with the best invention, take the next best candidates
+    /// whose uses don't overlap the uses of any invention already taken, and rewrite with them directly instead of
+    /// rebuilding the corpus index for each. They count towards `iterations`. `inv_candidates` gets raised to at
+    /// least this, and raising it further gives more candidates to choose from. Ignored with `follow`
+    #[clap(long, default_value = "1")]
+    pub batch_size: usize,
+
+    /// Keep the corpus index around between iterations so that each iteration only indexes the parts of the corpus
+    /// the last rewrite changed. With --lookahead-beam each partial library keeps its own. Ties between equally good
+    /// inventions may be broken differently than without it
+    #[clap(long)]
+    pub incremental_index: bool,
+
+    /// After compression, try removing each invention by inlining it back into the other invention bodies and the
+    /// corpus, and drop it if the corpus cost plus the size of the library doesn't get worse. The cleaned up library
+    /// and corpus go in "pruned_library" in the json output
+    #[clap(long)]
+    pub prune_library: bool,
+
+    /// After compression, rewrite the body of each invention with the other inventions wherever that makes it
+    /// smaller, so earlier inventions can use structure that later ones captured. Runs before `prune_library`
+    #[clap(long)]
+    pub refactor_library: bool,
+
+    /// The programs use named lambdas like `(lambda (x y) (f x y))` instead of de Bruijn indices. They get converted
+    /// before compression, and the json output gets "rewritten_named" and a "body_named" for each abstraction (likewise
+    /// under "pruned_library"), and "refactored_abstractions_named" with the named bodies of the refactored ones
+    #[clap(long)]
+    pub named_lambdas: bool,
+
     #[clap(flatten)]
     pub step: CompressionStepConfig,
 }
@@ -88,6 +125,22 @@ pub struct CompressionStepConfig {
     #[clap(long)]
     pub dynamic_batch: bool,
 
+    /// Stop searching after this many seconds and return the best abstraction found so far, which is marked
+    /// as not proven optimal in the output
+    #[clap(long)]
+    pub time_limit: Option<f64>,
+
+    /// Stop searching after this many worklist items have been processed and return the best abstraction found
+    /// so far, which is marked as not proven optimal in the output
+    #[clap(long)]
+    pub step_limit: Option<usize>,
+
+    /// Approximate search with bounded memory: cap the worklist at this many patterns, evicting the ones with
+    /// the lowest upper bounds when it grows past that. The output reports how much upper bound was evicted,
+    /// and the result is marked as not proven optimal if an evicted pattern could have beaten it
+    #[clap(long)]
+    pub max_worklist: Option<usize>,
+
     /// Puts result into eta-long form when rewriting (also requires beta-normal form). This
     /// can be useful for programs that will be used to train top down synthesizers, but it also
     /// restricts what abstractions can be found a bit (i.e. only those that can be put in beta-normal
@@ -103,12 +156,33 @@ pub struct CompressionStepConfig {
     #[clap(long)]
     pub no_curried_bodies: bool,
 
-    /// [currently not used] Number of invention candidates compression_step should return in a *single* step. Note that
+    /// Allow abstraction bodies to contain Let and RevLet bindings. Named variables in the body have to be
+    /// bound within it, so any other named variables can only appear in the arguments, except for the input of a
+    /// RevLet which is kept as is (and stays bound wherever the abstraction is used). Not supported with eta_long
+    #[clap(long)]
+    pub let_bodies: bool,
+
+    /// Number of invention candidates compression_step should return in a *single* step. Note that
     /// these will be the top n optimal candidates modulo subsumption pruning (and the top-1 is guaranteed
-    /// to be globally optimal)
+    /// to be globally optimal). With `lookahead_beam` each partial library is extended with each of these
     #[clap(short = 'n', long, default_value = "1")]
     pub inv_candidates: usize,
 
+    /// Make the `inv_candidates` returned in a step differ from each other, by suppressing any candidate that is
+    /// similar to one with higher utility. Two candidates are similar if at least this fraction of the match
+    /// locations of the one with fewer matches are shared, or if their bodies anti-unify to a skeleton covering
+    /// at least this fraction of the larger body. Something like 0.5 works well. Since a candidate can be suppressed
+    /// by one found later, the candidates are picked from a pool of the best ones found (see `diversity_pool`), and
+    /// only the worst candidate in the pool can be used to prune the search, which makes it slower
+    #[clap(long)]
+    pub diversity_threshold: Option<f64>,
+
+    /// With `diversity_threshold`, the size of the pool of best candidates to pick the diverse ones from, as a
+    /// multiple of `inv_candidates`. Candidates outside the pool are never returned, even if most of the pool
+    /// ends up suppressed
+    #[clap(long, default_value = "10")]
+    pub diversity_pool: usize,
+
     /// Method for choosing hole to expand at each step. Doesn't have a huge effect.
     #[clap(long, arg_enum, default_value = "depth-first")]
     pub hole_choice: HoleChoice,
@@ -144,6 +218,11 @@ pub struct CompressionStepConfig {
     #[clap(long, default_value = "0")]
     pub print_stats: usize,
 
+    /// When using an observer (see `multistep_compression_with_observer()`), report progress to it every time
+    /// this many worklist items have been processed (0 means never)
+    #[clap(long, default_value = "1000")]
+    pub progress_interval: usize,
+
     /// Print out programs rewritten under abstraction
     #[clap(long, short = 'r')]
     pub show_rewritten: bool,
@@ -168,6 +247,12 @@ pub struct CompressionStepConfig {
     #[clap(long)]
     pub allow_single_task: bool,
 
+    /// Score each task by the sum of the weighted costs of its programs instead of by its cheapest program. When the
+    /// weights of each task's programs are a distribution over them (like with --posterior-weights) this is the
+    /// expected cost of the task, so likely programs count for more instead of the least likely one winning the min
+    #[clap(long)]
+    pub expected_cost: bool,
+
     /// Disable the single structurally hashed subtree match pruning. This is a very minor optimization that allows
     /// discarding certain abstractions that only match at a single unique subtree as long as that subtree lacks free
     /// variables, because arity zero abstractions are always superior in this case
@@ -226,11 +311,88 @@ pub struct CompressionStepConfig {
     // Fused lambda tags
     #[clap(long, value_parser = clap::value_parser!(FusedLambdaTags), default_value="")]
     pub fused_lambda_tags: FusedLambdaTags,
+
+    /// json file mapping primitive names to DreamCoder type strings like "(t0 -> t1) -> list(t0) -> list(t1)".
+    /// When given, only abstractions that are well typed (with one type for each ivar across all of its uses)
+    /// are found, and the inferred type of each abstraction is included in the output
+    #[clap(long, value_parser = clap::value_parser!(PrimTypes), default_value="")]
+    pub prim_types: PrimTypes,
+
+    /// Use the types of the productions in the DreamCoder DSL as --prim-types. Requires --fmt=dreamcoder
+    #[clap(long)]
+    pub typed: bool,
+
+    /// json file mapping primitive names to whether they are reversible, like {"cons": true, "car": false}.
+    /// When given, the output says whether each abstraction is reversible. DreamCoder DSLs that mark their
+    /// productions with "is_reversible" are used by default
+    #[clap(long, value_parser = clap::value_parser!(ReversiblePrims), default_value="")]
+    pub reversible_prims: ReversiblePrims,
+
+    /// Restrict the search to reversible abstractions (`only`), or prefer the best reversible abstraction over
+    /// any non-reversible one and fall back to the latter when there isn't one (`first`). With `first`, an
+    /// iteration that finds no reversible abstraction runs a second full search, so it can take twice as long.
+    /// Requires --reversible-prims or a DreamCoder DSL that marks which productions are reversible
+    #[clap(long, arg_enum, default_value = "any")]
+    pub reversible_abstractions: ReversibleAbstractions,
+}
+
+/// whether to search for abstractions that can be used in the definitions of a RevLet
+#[derive(Debug, Clone, PartialEq, Eq, clap::ArgEnum, Serialize)]
+pub enum ReversibleAbstractions {
+    Any,
+    Only,
+    First,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReversiblePrims {
+    pub prims: Vec<(String, bool)>,
+}
+
+// parse from the path to a json file like {"cons": true, "car": false}
+impl std::str::FromStr for ReversiblePrims {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(ReversiblePrims { prims: vec![] });
+        }
+        let contents = std::fs::read_to_string(s)
+            .map_err(|e| format!("failed to read reversible prims file {s}: {e}"))?;
+        let prims: FxHashMap<String, bool> = serde_json::from_str(&contents).map_err(|e| {
+            format!("reversible prims file {s} should be a json object mapping primitives to booleans: {e}")
+        })?;
+        let mut prims: Vec<(String, bool)> = prims.into_iter().collect();
+        prims.sort();
+        Ok(ReversiblePrims { prims })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrimTypes {
+    pub types: Vec<(String, String)>,
+}
+
+// parse from the path to a json file like {"map": "(t0 -> t1) -> list(t0) -> list(t1)"}
+impl std::str::FromStr for PrimTypes {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(PrimTypes { types: vec![] });
+        }
+        let contents = std::fs::read_to_string(s)
+            .map_err(|e| format!("failed to read prim types file {s}: {e}"))?;
+        let types: FxHashMap<String, String> = serde_json::from_str(&contents).map_err(|e| {
+            format!("prim types file {s} should be a json object mapping primitives to types: {e}")
+        })?;
+        let mut types: Vec<(String, String)> = types.into_iter().collect();
+        types.sort();
+        Ok(PrimTypes { types })
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct FusedLambdaTags {
-    tags: Option<FxHashSet<Tag>>,
+    pub tags: Option<FxHashSet<Tag>>,
 }
 
 // parse from a string like "1,2,3"
@@ -246,6 +408,37 @@ impl std::str::FromStr for FusedLambdaTags {
 }
 
 impl CompressionStepConfig {
+    /// Sets the primitive types from the DreamCoder DSL if `typed` is set
+    pub fn apply_dreamcoder_types(
+        &mut self,
+        prim_types: &Option<Vec<(String, String)>>,
+    ) -> Result<(), StitchError> {
+        if !self.typed {
+            return Ok(());
+        }
+        self.prim_types = PrimTypes {
+            types: prim_types.clone().ok_or_else(|| {
+                StitchError::InvalidConfig(
+                    "--typed requires an input with DreamCoder production types".to_string(),
+                )
+            })?,
+        };
+        Ok(())
+    }
+    /// Sets which primitives are reversible from the DreamCoder DSL, unless --reversible-prims was given
+    pub fn apply_dreamcoder_reversibility(
+        &mut self,
+        reversible_prims: &Option<Vec<(String, bool)>>,
+    ) {
+        if !self.reversible_prims.prims.is_empty() {
+            return;
+        }
+        if let Some(reversible_prims) = reversible_prims {
+            self.reversible_prims = ReversiblePrims {
+                prims: reversible_prims.clone(),
+            };
+        }
+    }
     pub fn no_opt(&mut self) {
         self.no_opt_upper_bound = true;
         self.no_opt_force_multiuse = true;
@@ -260,6 +453,37 @@ impl MultistepCompressionConfig {
     pub fn new() -> Self {
         Self::parse_from("compress".split_whitespace())
     }
+    /// Checks for combinations of arguments that compression can't run with
+    pub fn validate(&self) -> Result<(), StitchError> {
+        let invalid = |message: &str| Err(StitchError::InvalidConfig(message.to_string()));
+        if self.lookahead_beam > 1 && self.batch_size > 1 {
+            return invalid("--lookahead-beam is not supported with --batch-size");
+        }
+        if self.step.eta_long && !(self.step.utility_by_rewrite || self.step.no_mismatch_check) {
+            return invalid("eta long form requires utility_by_rewrite or no_mismatch_check");
+        }
+        if self.step.eta_long && self.step.let_bodies {
+            return invalid("eta long form isn't supported with let_bodies");
+        }
+        if self.step.reversible_abstractions != ReversibleAbstractions::Any
+            && self.step.reversible_prims.prims.is_empty()
+        {
+            return invalid(
+                "--reversible-abstractions requires knowing which primitives are reversible",
+            );
+        }
+        if !self.step.prim_types.types.is_empty() {
+            parse_prim_types(&self.step.prim_types.types)
+                .map_err(|e| StitchError::InvalidConfig(format!("bad primitive types: {e}")))?;
+        }
+        if let Some(follow) = &self.step.follow {
+            let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+            set.parse_extend(follow).map_err(|e| {
+                StitchError::InvalidConfig(format!("couldn't parse --follow {follow}: {e}"))
+            })?;
+        }
+        Ok(())
+    }
 }
 
 // we use these manual implementations - deriving would set things to zero instead of
@@ -284,7 +508,8 @@ pub struct Pattern {
     pub match_locations: Vec<(Idx, i32)>, // places where it applies
     pub utility_upper_bound: i32,
     pub body_utility: i32, // the size (in `cost`) of a single use of the pattern body so far
-    pub tracked: bool,     // for debugging
+    pub free_named_vars: Vec<Symbol>, // inputs of RevLets in the body that aren't bound within it, these stay bound wherever the pattern is used
+    pub tracked: bool,                // for debugging
 }
 
 /// only used during tracking - gets the zippers to args of a pattern
@@ -330,9 +555,15 @@ fn zids_of_ivar_of_expr(
                 helper(expr.get(*x), curr_zip, zids_of_ivar, zid_of_zip)?;
                 curr_zip.pop();
             }
-            Node::NVar(_) | Node::NLinkVar(_, _) => unreachable!(),
-            Node::Let { .. } => unreachable!(),
-            Node::RevLet { .. } => unreachable!(),
+            Node::NVar(_) | Node::NLinkVar(_, _) => {}
+            Node::Let { def, body, .. } | Node::RevLet { def, body, .. } => {
+                curr_zip.push(ZNode::Func);
+                helper(expr.get(*body), curr_zip, zids_of_ivar, zid_of_zip)?;
+                curr_zip.pop();
+                curr_zip.push(ZNode::Arg);
+                helper(expr.get(*def), curr_zip, zids_of_ivar, zid_of_zip)?;
+                curr_zip.pop();
+            }
         }
         Ok(())
     }
@@ -379,9 +610,80 @@ pub struct CostConfig {
     /// Sets cost for reverse let expressions
     #[clap(long, default_value = "1")]
     pub cost_revlet: usize,
+
+    /// json file mapping primitive names to costs, e.g. {"+": 50, "if": 200}. Primitives that aren't
+    /// in the file cost `cost_prim_default`
+    #[clap(long, value_parser = clap::value_parser!(PrimCosts), default_value="")]
+    pub prim_costs: PrimCosts,
+
+    /// Derive primitive, variable and lambda costs from the log probabilities in the DreamCoder DSL
+    /// (cost = -log p * log_prob_scale), overriding the other cost flags. Applications cost 0, as in
+    /// DreamCoder's description length. Requires --fmt=dreamcoder
+    #[clap(long)]
+    pub costs_from_log_probs: bool,
+
+    /// What to multiply -log p by when turning log probabilities into integer costs with
+    /// --costs-from-log-probs
+    #[clap(long, default_value = "100")]
+    pub log_prob_scale: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrimCosts {
+    costs: FxHashMap<String, usize>,
+}
+
+// parse from the path to a json file like {"+": 50, "if": 200}
+impl std::str::FromStr for PrimCosts {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(PrimCosts {
+                costs: Default::default(),
+            });
+        }
+        let contents = std::fs::read_to_string(s)
+            .map_err(|e| format!("failed to read prim costs file {s}: {e}"))?;
+        let costs = serde_json::from_str(&contents).map_err(|e| {
+            format!("prim costs file {s} should be a json object mapping primitives to costs: {e}")
+        })?;
+        Ok(PrimCosts { costs })
+    }
 }
 
 impl CostConfig {
+    /// Sets the costs from DreamCoder log probabilities if `costs_from_log_probs` is set
+    pub fn apply_log_probabilities(
+        &mut self,
+        log_probabilities: &Option<LogProbabilities>,
+    ) -> Result<(), StitchError> {
+        if !self.costs_from_log_probs {
+            return Ok(());
+        }
+        let log_probabilities = log_probabilities.as_ref().ok_or_else(|| {
+            StitchError::InvalidConfig(
+                "--costs-from-log-probs requires an input with DreamCoder log probabilities"
+                    .to_string(),
+            )
+        })?;
+        let scale = self.log_prob_scale;
+        let cost_of = |log_prob: f64| (-log_prob * scale).round() as usize;
+        self.cost_app = 0;
+        self.cost_lam = log_probabilities.log_lambda.map(cost_of).unwrap_or(0);
+        self.cost_var = cost_of(log_probabilities.log_variable);
+        if let Some(log_free_var) = log_probabilities.log_free_var {
+            self.cost_nvar = cost_of(log_free_var);
+        }
+        self.prim_costs = PrimCosts {
+            costs: log_probabilities
+                .productions
+                .iter()
+                .map(|(name, log_prob)| (name.clone(), cost_of(*log_prob)))
+                .collect(),
+        };
+        Ok(())
+    }
+
     pub fn expr_cost(&self) -> ExprCost {
         ExprCost {
             cost_lam: self.cost_lam.try_into().unwrap(),
@@ -389,7 +691,12 @@ impl CostConfig {
             cost_var: self.cost_var.try_into().unwrap(),
             cost_ivar: self.cost_ivar.try_into().unwrap(),
             cost_prim_default: self.cost_prim_default.try_into().unwrap(),
-            cost_prim: Default::default(),
+            cost_prim: self
+                .prim_costs
+                .costs
+                .iter()
+                .map(|(prim, cost)| (Symbol::from(prim.as_str()), (*cost).try_into().unwrap()))
+                .collect(),
             cost_nvar: self.cost_nvar.try_into().unwrap(),
             cost_let: self.cost_let.try_into().unwrap(),
             cost_revlet: self.cost_revlet.try_into().unwrap(),
@@ -408,7 +715,7 @@ impl Pattern {
         analyzed_free_vars: &mut AnalyzedExpr<FreeVarAnalysis>,
         analyzed_cost: &mut AnalyzedExpr<ExprCost>,
         set: &ExprSet,
-        cost_fn: &ExprCost,
+        inv_cost: i32,
         cfg: &CompressionStepConfig,
     ) -> Self {
         let body_utility = 0;
@@ -430,10 +737,15 @@ impl Pattern {
             .retain(|node| !invalid_match_location(set, &cfg.fused_lambda_tags.tags, *node));
 
         if cfg.eta_long {
+            // ruled out by MultistepCompressionConfig::validate()
             assert!(
                 cfg.utility_by_rewrite || cfg.no_mismatch_check,
                 "eta long form requires utility_by_rewrite or no_mismatch_check"
             );
+            assert!(
+                !cfg.let_bodies,
+                "eta long form isn't supported with let_bodies"
+            );
 
             let rev_fix_param_symbol = Symbol::from("rev_fix_param");
 
@@ -504,7 +816,7 @@ impl Pattern {
             body_utility,
             cost_of_node_all,
             num_paths_to_node,
-            cost_fn,
+            inv_cost,
             cfg,
         );
         Pattern {
@@ -514,6 +826,7 @@ impl Pattern {
             match_locations, // single hole matches everywhere
             utility_upper_bound,
             body_utility, // 0 body utility
+            free_named_vars: vec![],
             tracked: cfg.follow.is_some(),
         }
     }
@@ -545,6 +858,7 @@ impl Pattern {
             curr_node: Idx,
             curr_zip: &mut Vec<ZNode>,
             zips: &[(Vec<ZNode>, Node)],
+            links: &mut FxHashMap<Symbol, Idx>,
             shared: &SharedData,
         ) -> Idx {
             if let Some((_, e)) = zips.iter().find(|(zip, _)| zip == curr_zip) {
@@ -556,21 +870,58 @@ impl Pattern {
                 Node::Var(v, tag) => set.add(Node::Var(*v, *tag)),
                 Node::Lam(b, tag) => {
                     curr_zip.push(ZNode::Body);
-                    let b_idx = helper(set, *b, curr_zip, zips, shared);
+                    let b_idx = helper(set, *b, curr_zip, zips, links, shared);
                     curr_zip.pop();
                     set.add(Node::Lam(b_idx, *tag))
                 }
                 Node::App(f, x) => {
                     curr_zip.push(ZNode::Func);
-                    let f_idx = helper(set, *f, curr_zip, zips, shared);
+                    let f_idx = helper(set, *f, curr_zip, zips, links, shared);
                     curr_zip.pop();
                     curr_zip.push(ZNode::Arg);
-                    let x_idx = helper(set, *x, curr_zip, zips, shared);
+                    let x_idx = helper(set, *x, curr_zip, zips, links, shared);
                     curr_zip.pop();
                     set.add(Node::App(f_idx, x_idx))
                 }
-                Node::NVar(_) => unreachable!(),
-                Node::NLinkVar(_, l) => helper(set, *l, curr_zip, zips, shared),
+                Node::NVar(name) => set.add(Node::NVar(name.clone())),
+                Node::NLinkVar(name, l) => match links.get(name) {
+                    // bound within the pattern
+                    Some(link) => set.add(Node::NLinkVar(name.clone(), *link)),
+                    None => helper(set, *l, curr_zip, zips, links, shared),
+                },
+                Node::Let { var, def, body } => {
+                    curr_zip.push(ZNode::Arg);
+                    let def_idx = helper(set, *def, curr_zip, zips, links, shared);
+                    curr_zip.pop();
+                    links.insert(var.clone(), def_idx);
+                    curr_zip.push(ZNode::Func);
+                    let body_idx = helper(set, *body, curr_zip, zips, links, shared);
+                    curr_zip.pop();
+                    set.add(Node::Let {
+                        var: var.clone(),
+                        def: def_idx,
+                        body: body_idx,
+                    })
+                }
+                Node::RevLet {
+                    inp_var,
+                    def_vars,
+                    def,
+                    body,
+                } => {
+                    curr_zip.push(ZNode::Arg);
+                    let def_idx = helper(set, *def, curr_zip, zips, links, shared);
+                    curr_zip.pop();
+                    curr_zip.push(ZNode::Func);
+                    let body_idx = helper(set, *body, curr_zip, zips, links, shared);
+                    curr_zip.pop();
+                    set.add(Node::RevLet {
+                        inp_var: inp_var.clone(),
+                        def_vars: def_vars.clone(),
+                        def: def_idx,
+                        body: body_idx,
+                    })
+                }
                 _ => unreachable!(),
             }
         }
@@ -580,11 +931,50 @@ impl Pattern {
             self.match_locations[0].0,
             &mut curr_zip,
             &zips,
+            &mut Default::default(),
             shared,
         );
         ExprOwned { set, idx }
     }
 
+    /// When running with types, checks that the pattern has a type where each ivar has a single type, and
+    /// that the arguments at every match location are consistent with that type. Holes can have any type.
+    fn check_types(&self, shared: &SharedData) -> bool {
+        let prim_types = match &shared.prim_types {
+            Some(prim_types) => prim_types,
+            None => return true,
+        };
+        let arity = self.first_zid_of_ivar.len();
+        let inv_type = match infer_invention_type(self.to_expr(shared).immut(), arity, prim_types) {
+            Ok(inv_type) => inv_type,
+            Err(_) => return false,
+        };
+        self.match_locations.iter().all(|(loc, _)| {
+            let args: Option<Vec<&NodeTyping>> = self
+                .first_zid_of_ivar
+                .iter()
+                .map(|zid| shared.typings[shared.arg_of_zid_node[*zid][loc].shifted_id].as_ref())
+                .collect();
+            match args {
+                Some(args) => check_invention_use(&inv_type, arity, &args),
+                // parts of the corpus we couldn't type don't constrain anything
+                None => true,
+            }
+        })
+    }
+
+    /// When running with `--reversible-abstractions only`, checks that the pattern is a reversible abstraction
+    fn check_reversible(&self, shared: &SharedData) -> bool {
+        match &shared.reversible_prims {
+            Some(reversible_prims)
+                if shared.cfg.reversible_abstractions == ReversibleAbstractions::Only =>
+            {
+                invention_is_reversible(self.to_expr(shared).immut(), reversible_prims)
+            }
+            _ => true,
+        }
+    }
+
     fn check_rev_fixer_compression(&self, shared: &SharedData) -> bool {
         fn has_var(set: &ExprSet, fix_func: usize, fixer_var: usize) -> bool {
             if fix_func == fixer_var {
@@ -623,6 +1013,20 @@ impl Pattern {
                 }
                 Node::Var(_v, _) => (true, -1, 0),
                 Node::IVar(_) => (true, -1, 0),
+                Node::NVar(_) | Node::NLinkVar(_, _) => (true, -1, 0),
+                Node::Let { def, body, .. } | Node::RevLet { def, body, .. } => {
+                    let (is_def_valid, is_def_rev_fixer, _fix_func) = helper(def, set);
+                    let (is_body_valid, is_body_rev_fixer, _fix_func) = helper(body, set);
+                    if !is_def_valid
+                        || is_def_rev_fixer != -1
+                        || !is_body_valid
+                        || is_body_rev_fixer != -1
+                    {
+                        (false, -1, 0)
+                    } else {
+                        (true, -1, 0)
+                    }
+                }
                 Node::App(f, x) => {
                     let (is_valid, is_rev_fixer, fix_func) = helper(f, set);
                     if !is_valid {
@@ -677,7 +1081,6 @@ impl Pattern {
                         (true, -1, 0)
                     }
                 }
-                _ => unreachable!(),
             }
         }
         let expr = self.to_expr(shared);
@@ -726,7 +1129,29 @@ pub enum ExpandsTo {
     Var(i32, Tag),
     Prim(Symbol),
     IVar(i32),
-    NVar,
+    NVar(NamedVar),
+    Let, // the body is under the Func zipper and the definition under the Arg zipper
+    RevLet(NamedVar, usize), // the input variable and the number of variables it binds, zippers as for Let
+}
+
+/// A named variable as seen from the root of a pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Ord, PartialOrd)]
+pub enum NamedVar {
+    Free(Symbol), // not bound within the pattern, so it has to be an argument unless it's the input of a RevLet
+    Bound(usize), // bound by a Let or RevLet within the pattern, numbering the named variables bound on the way down from the root
+}
+
+impl NamedVar {
+    /// moves up through a Let or RevLet that binds `vars`
+    fn bind(&self, vars: &[Symbol]) -> NamedVar {
+        match self {
+            NamedVar::Free(name) => match vars.iter().position(|var| var == name) {
+                Some(i) => NamedVar::Bound(i),
+                None => NamedVar::Free(name.clone()),
+            },
+            NamedVar::Bound(i) => NamedVar::Bound(i + vars.len()),
+        }
+    }
 }
 
 impl ExpandsTo {
@@ -740,7 +1165,9 @@ impl ExpandsTo {
             ExpandsTo::Var(_, _) => false,
             ExpandsTo::Prim(_) => false,
             ExpandsTo::IVar(_) => false,
-            ExpandsTo::NVar => false,
+            ExpandsTo::NVar(_) => false,
+            ExpandsTo::Let => true,
+            ExpandsTo::RevLet(_, _) => true,
         }
     }
     #[inline]
@@ -748,6 +1175,29 @@ impl ExpandsTo {
     fn is_ivar(&self) -> bool {
         matches!(self, ExpandsTo::IVar(_))
     }
+    /// what this expands to as seen from above a Let or RevLet that binds `vars`
+    fn bind_named_vars(&self, vars: &[Symbol]) -> ExpandsTo {
+        match self {
+            ExpandsTo::NVar(var) => ExpandsTo::NVar(var.bind(vars)),
+            ExpandsTo::RevLet(inp_var, num_vars) => {
+                ExpandsTo::RevLet(inp_var.bind(vars), *num_vars)
+            }
+            e => e.clone(),
+        }
+    }
+    /// true if this is a named variable that isn't bound within the pattern
+    fn has_free_named_var(&self) -> bool {
+        matches!(self, ExpandsTo::NVar(NamedVar::Free(_)))
+    }
+}
+
+impl std::fmt::Display for NamedVar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NamedVar::Free(name) => write!(f, "{name}"),
+            NamedVar::Bound(i) => write!(f, "$bound{i}"),
+        }
+    }
 }
 
 impl std::fmt::Display for ExpandsTo {
@@ -770,7 +1220,9 @@ impl std::fmt::Display for ExpandsTo {
             }
             ExpandsTo::Prim(p) => write!(f, "{p}"),
             ExpandsTo::IVar(v) => write!(f, "#{v}"),
-            ExpandsTo::NVar => write!(f, "$NVar"),
+            ExpandsTo::NVar(var) => write!(f, "{var}"),
+            ExpandsTo::Let => write!(f, "let ?? = ?? in ??"),
+            ExpandsTo::RevLet(inp_var, _) => write!(f, "let ?? = rev({inp_var} = ??) in ??"),
         }
     }
 }
@@ -790,6 +1242,20 @@ pub struct Arg {
     pub expands_to: ExpandsTo,
 }
 
+/// how much expanding a hole to `expands_to` adds to the size of a pattern body
+fn expansion_cost(expands_to: &ExpandsTo, cost_fn: &ExprCost) -> i32 {
+    match expands_to {
+        ExpandsTo::Lam(_) => cost_fn.cost_lam,
+        ExpandsTo::App => cost_fn.cost_app,
+        ExpandsTo::Var(_, _) => cost_fn.cost_var,
+        ExpandsTo::Prim(p) => prim_cost(cost_fn, p),
+        ExpandsTo::IVar(_) => 0,
+        ExpandsTo::NVar(_) => cost_fn.cost_nvar,
+        ExpandsTo::Let => cost_fn.cost_let,
+        ExpandsTo::RevLet(_, _) => cost_fn.cost_revlet,
+    }
+}
+
 fn expands_to_of_node(node: &Node) -> ExpandsTo {
     match node {
         Node::Var(i, tag) => ExpandsTo::Var(*i, *tag),
@@ -797,10 +1263,12 @@ fn expands_to_of_node(node: &Node) -> ExpandsTo {
         Node::Lam(_, tag) => ExpandsTo::Lam(*tag),
         Node::App(_, _) => ExpandsTo::App,
         Node::IVar(i) => ExpandsTo::IVar(*i),
-        Node::NVar(_) => ExpandsTo::NVar,
-        Node::NLinkVar(_, _) => ExpandsTo::NVar, //TODO: should we expand to a linked var?
-        Node::Let { .. } => unreachable!(),
-        Node::RevLet { .. } => unreachable!(),
+        Node::NVar(name) => ExpandsTo::NVar(NamedVar::Free(name.clone())),
+        Node::NLinkVar(name, _) => ExpandsTo::NVar(NamedVar::Free(name.clone())), //TODO: should we expand to a linked var?
+        Node::Let { .. } => ExpandsTo::Let,
+        Node::RevLet {
+            inp_var, def_vars, ..
+        } => ExpandsTo::RevLet(NamedVar::Free(inp_var.clone()), def_vars.len()),
     }
 }
 
@@ -808,15 +1276,36 @@ fn expands_to_of_node(node: &Node) -> ExpandsTo {
 fn tracked_expands_to(pattern: &Pattern, hole_zid: ZId, shared: &SharedData) -> ExpandsTo {
     // apply the hole zipper to the original expr being tracked to get the subtree
     // this will expand into, then get the ExpandsTo of that
-    let idx = shared
-        .tracking
-        .as_ref()
-        .unwrap()
-        .expr
-        .immut()
-        .zip(&shared.zip_of_zid[hole_zid])
-        .idx;
-    match expands_to_of_node(&shared.tracking.as_ref().unwrap().expr.set[idx]) {
+    let expr = &shared.tracking.as_ref().unwrap().expr;
+    let mut idx = expr.idx;
+    // the named variables bound by each Let and RevLet on the way down
+    let mut binders: Vec<Vec<Symbol>> = vec![];
+    for znode in shared.zip_of_zid[hole_zid].iter() {
+        match &expr.set[idx] {
+            Node::Let { var, body, .. } if *znode == ZNode::Func => {
+                binders.push(vec![var.clone()]);
+                idx = *body;
+            }
+            Node::Let { def, .. } => idx = *def,
+            Node::RevLet {
+                def_vars,
+                def,
+                body,
+                ..
+            } => {
+                binders.push(def_vars.clone());
+                idx = if *znode == ZNode::Func { *body } else { *def };
+            }
+            _ => idx = expr.immut().get(idx).zip(std::slice::from_ref(znode)).idx,
+        }
+    }
+    let expands_to = binders
+        .iter()
+        .rev()
+        .fold(expands_to_of_node(&expr.set[idx]), |expands_to, vars| {
+            expands_to.bind_named_vars(vars)
+        });
+    match expands_to {
         ExpandsTo::IVar(i) => {
             // in the case where we're searching for an IVar we need to be robust to relabellings
             // since this doesn't have to be canonical. What we can do is we can look over
@@ -872,6 +1361,11 @@ pub struct CriticalMultithreadData {
     worklist: BinaryHeap<HeapItem>,
     utility_pruning_cutoff: i32,
     active_threads: FxHashSet<std::thread::ThreadId>, // list of threads currently holding worklist items
+    steps: usize,                                     // number of worklist items handed out so far
+    out_of_budget: bool, // set once we run out of time or steps, after which no more worklist items are handed out
+    evicted_patterns: usize, // patterns evicted from the worklist with upper bounds above the cutoff, see cfg.max_worklist
+    evicted_bound_mass: i64, // sum of the upper bounds of those patterns
+    max_evicted_bound: Option<i32>,
 }
 
 /// All the data shared among threads, mostly read-only
@@ -879,9 +1373,9 @@ pub struct CriticalMultithreadData {
 #[derive(Debug)]
 pub struct SharedData {
     pub crit: Mutex<CriticalMultithreadData>,
-    pub programs: Vec<ExprOwned>,
     pub arg_of_zid_node: Vec<FxHashMap<Idx, Arg>>,
     pub cost_fn: ExprCost,
+    pub inv_cost: i32, // cost of the primitive for the new invention
     pub analyzed_free_vars: AnalyzedExpr<FreeVarAnalysis>,
     pub analyzed_ivars: AnalyzedExpr<IVarAnalysis>,
     pub analyzed_cost: AnalyzedExpr<ExprCost>,
@@ -910,13 +1404,19 @@ pub struct SharedData {
     pub multistep_cfg: MultistepCompressionConfig,
     pub tracking: Option<Tracking>,
     pub fused_lambda_tags: Option<FxHashSet<Tag>>,
+    pub prim_types: Option<FxHashMap<Symbol, DcType>>, // only set when searching for well typed abstractions
+    pub reversible_prims: Option<FxHashMap<Symbol, bool>>, // only set when we know which primitives are reversible
+    pub typings: Vec<Option<NodeTyping>>, // principal typing of each node, if prim_types is set
+    pub deadline: Option<std::time::Instant>, // when to stop searching, if cfg.time_limit is set
+    pub observer: Arc<dyn CompressionObserver>,
+    pub cancellation: CancellationHandle,
 }
 
 fn invalid_metavar_location(shared: &SharedData, node: Idx) -> bool {
     fused_lambda_location(&shared.set, &shared.fused_lambda_tags, node)
 }
 
-fn invalid_match_location(
+pub fn invalid_match_location(
     set: &ExprSet,
     fused_lambda_tags: &Option<FxHashSet<Tag>>,
     node: Idx,
@@ -948,41 +1448,186 @@ pub struct Tracking {
 
 impl CriticalMultithreadData {
     /// Create a new mutable multithread data struct with
-    /// a worklist that just has a single hole on it
+    /// a worklist that just has a single hole on it. The donelist gets sorted and truncated
+    /// by the first update() once SharedData exists
     fn new(
         donelist: Vec<FinishedPattern>,
         worklist: BinaryHeap<HeapItem>,
         cfg: &CompressionStepConfig,
     ) -> Self {
-        let mut res = CriticalMultithreadData {
+        CriticalMultithreadData {
             donelist,
             worklist,
             // we allow negative utilities in follow_prune case
             utility_pruning_cutoff: if !cfg.follow_prune { 0 } else { i32::MIN },
             active_threads: FxHashSet::default(),
-        };
-        res.update(cfg);
-        res
+            steps: 0,
+            out_of_budget: false,
+            evicted_patterns: 0,
+            evicted_bound_mass: 0,
+            max_evicted_bound: None,
+        }
     }
-    /// sort the donelist by utility, truncate to cfg.inv_candidates, update
-    /// update utility_pruning_cutoff to be the lowest utility
+    /// sort the donelist by utility, truncate to cfg.inv_candidates, update utility_pruning_cutoff to be the lowest utility.
+    /// With cfg.diversity_threshold the donelist is a pool of cfg.diversity_pool times as many candidates, which
+    /// get diversified once the search is done (see finish())
     //#[inline(never)]
-    fn update(&mut self, cfg: &CompressionStepConfig) {
+    fn update(&mut self, shared: &SharedData) {
+        let cfg = &shared.cfg;
         // sort in decreasing order by utility primarily, and break ties using the argchoice zids (just in order to be deterministic!)
         // let old_best = self.donelist.first().map(|x|x.utility).unwrap_or(0);
         self.donelist.sort_unstable_by(|a, b| {
             (b.utility, &b.pattern.arg_choices).cmp(&(a.utility, &a.pattern.arg_choices))
         });
-        self.donelist.truncate(cfg.inv_candidates);
-        // the cutoff is the lowest utility
+        // a candidate we'd keep now can be suppressed by a better one found later, which would bring back
+        // whatever it suppressed or anything below it, so when diversifying we keep a pool to pick from instead
+        let max_donelist = if cfg.diversity_threshold.is_some() {
+            cfg.inv_candidates.saturating_mul(cfg.diversity_pool)
+        } else {
+            cfg.inv_candidates
+        };
+        self.donelist.truncate(max_donelist);
+        // the cutoff is the lowest utility in the donelist, since anything worse would get truncated away. The
+        // pool isn't cut off until it's full though, any candidate could still make it in before then
         // we allow negative utilities in follow_prune case
         let default_bound = if !cfg.follow_prune { 0 } else { i32::MIN };
-        self.utility_pruning_cutoff = if cfg.no_opt_upper_bound {
+        self.utility_pruning_cutoff = if cfg.no_opt_upper_bound
+            || (cfg.diversity_threshold.is_some() && self.donelist.len() < max_donelist)
+        {
             default_bound
         } else {
             std::cmp::max(0, self.donelist.last().map(|x| x.utility).unwrap_or(0))
         };
     }
+    /// the last update once the search is done, which is when the candidates can be diversified
+    fn finish(&mut self, shared: &SharedData) {
+        self.update(shared);
+        if let Some(threshold) = shared.cfg.diversity_threshold {
+            self.diversify(threshold, shared);
+        }
+    }
+    /// goes through the sorted donelist keeping each candidate unless it is similar to one we already kept,
+    /// until cfg.inv_candidates are kept. Each body is only built once
+    fn diversify(&mut self, threshold: f64, shared: &SharedData) {
+        let mut kept: Vec<(FinishedPattern, ExprOwned)> = vec![];
+        for done in std::mem::take(&mut self.donelist) {
+            if kept.len() == shared.cfg.inv_candidates {
+                break;
+            }
+            let body = done.to_expr(shared);
+            if !kept.iter().any(|(other, other_body)| {
+                similar_candidates(&done, &body, other, other_body, threshold)
+            }) {
+                kept.push((done, body));
+            }
+        }
+        self.donelist = kept.into_iter().map(|(done, _)| done).collect();
+    }
+    /// evicts the patterns with the lowest upper bounds from the worklist so that at most `max_worklist`
+    /// remain, keeping track of the upper bounds thrown away
+    fn evict(&mut self, max_worklist: usize) {
+        let mut items = std::mem::take(&mut self.worklist).into_vec();
+        let num_evicted = items.len() - max_worklist;
+        if num_evicted < items.len() {
+            // move the lowest upper bounds to the front
+            items.select_nth_unstable(num_evicted);
+        }
+        for item in items.drain(..num_evicted) {
+            let bound = item.pattern.utility_upper_bound;
+            // patterns at or below the cutoff would have been pruned anyways
+            if bound > self.utility_pruning_cutoff {
+                self.evicted_patterns += 1;
+                self.evicted_bound_mass += bound as i64;
+                self.max_evicted_bound = std::cmp::max(self.max_evicted_bound, Some(bound));
+            }
+        }
+        self.worklist = BinaryHeap::from(items);
+    }
+}
+
+/// whether two invention candidates mostly share match locations or have mostly the same body (see
+/// CompressionStepConfig::diversity_threshold)
+fn similar_candidates(
+    a: &FinishedPattern,
+    a_body: &ExprOwned,
+    b: &FinishedPattern,
+    b_body: &ExprOwned,
+    threshold: f64,
+) -> bool {
+    let (fewer, more) = if a.pattern.match_locations.len() <= b.pattern.match_locations.len() {
+        (&a.pattern.match_locations, &b.pattern.match_locations)
+    } else {
+        (&b.pattern.match_locations, &a.pattern.match_locations)
+    };
+    if !fewer.is_empty() {
+        let more: FxHashSet<Idx> = more.iter().map(|(loc, _)| *loc).collect();
+        let shared_locs = fewer.iter().filter(|(loc, _)| more.contains(loc)).count();
+        if shared_locs as f64 >= threshold * fewer.len() as f64 {
+            return true;
+        }
+    }
+    let larger_body = std::cmp::max(skeleton_size(a_body.immut()), skeleton_size(b_body.immut()));
+    larger_body > 0
+        && shared_skeleton_size(a_body.immut(), b_body.immut()) as f64
+            >= threshold * larger_body as f64
+}
+
+/// number of nodes in an invention body, not counting its arguments
+fn skeleton_size(e: Expr) -> usize {
+    match e.node() {
+        Node::IVar(_) => 0,
+        Node::App(f, x) => 1 + skeleton_size(e.get(*f)) + skeleton_size(e.get(*x)),
+        Node::Lam(b, _) => 1 + skeleton_size(e.get(*b)),
+        Node::Let { def, body, .. } | Node::RevLet { def, body, .. } => {
+            1 + skeleton_size(e.get(*def)) + skeleton_size(e.get(*body))
+        }
+        Node::Prim(_) | Node::Var(_, _) | Node::NVar(_) | Node::NLinkVar(_, _) => 1,
+    }
+}
+
+/// size of the anti-unifier of two invention bodies: the number of nodes they agree on, where everywhere
+/// they disagree (or either has an argument) becomes a hole that isn't counted
+fn shared_skeleton_size(a: Expr, b: Expr) -> usize {
+    match (a.node(), b.node()) {
+        (Node::IVar(_), _) | (_, Node::IVar(_)) => 0,
+        (Node::App(f1, x1), Node::App(f2, x2)) => {
+            1 + shared_skeleton_size(a.get(*f1), b.get(*f2))
+                + shared_skeleton_size(a.get(*x1), b.get(*x2))
+        }
+        (Node::Lam(b1, tag1), Node::Lam(b2, tag2)) if tag1 == tag2 => {
+            1 + shared_skeleton_size(a.get(*b1), b.get(*b2))
+        }
+        (
+            Node::Let {
+                def: d1, body: b1, ..
+            },
+            Node::Let {
+                def: d2, body: b2, ..
+            },
+        )
+        | (
+            Node::RevLet {
+                def: d1, body: b1, ..
+            },
+            Node::RevLet {
+                def: d2, body: b2, ..
+            },
+        ) => {
+            1 + shared_skeleton_size(a.get(*d1), b.get(*d2))
+                + shared_skeleton_size(a.get(*b1), b.get(*b2))
+        }
+        (Node::NLinkVar(n1, _), Node::NLinkVar(n2, _)) if n1 == n2 => 1,
+        (x, y) if x == y => 1,
+        _ => 0,
+    }
+}
+
+/// How far from exhaustive the search in a compression step was
+#[derive(Debug, Clone, Default)]
+pub struct SearchCompleteness {
+    pub remaining_upper_bound: Option<i32>, // set when patterns that might have beaten the result were never explored
+    pub evicted_patterns: usize,            // see CompressionStepConfig::max_worklist
+    pub evicted_bound_mass: i64,            // sum of the upper bounds of the evicted patterns
 }
 
 /// At the end of the day we convert our Inventions into InventionExprs to make
@@ -1020,21 +1665,72 @@ impl Display for Invention {
 /// Various tracking stats
 #[derive(Clone, Default, Debug)]
 pub struct Stats {
-    worklist_steps: usize,
-    finished: usize,
-    calc_final_utility: usize,
-    calc_unargcap: usize,
-    donelist_push: usize,
-    azero_calc_util: usize,
-    azero_calc_unargcap: usize,
-    upper_bound_fired: usize,
+    pub worklist_steps: usize,
+    pub finished: usize,
+    pub calc_final_utility: usize,
+    pub calc_unargcap: usize,
+    pub donelist_push: usize,
+    pub azero_calc_util: usize,
+    pub azero_calc_unargcap: usize,
+    pub upper_bound_fired: usize,
     // conflict_upper_bound_fired: usize,
-    free_vars_fired: usize,
-    named_vars_fired: usize,
-    single_use_fired: usize,
-    single_task_fired: usize,
-    useless_abstract_fired: usize,
-    force_multiuse_fired: usize,
+    pub free_vars_fired: usize,
+    pub named_vars_fired: usize,
+    pub single_use_fired: usize,
+    pub single_task_fired: usize,
+    pub useless_abstract_fired: usize,
+    pub force_multiuse_fired: usize,
+    pub ill_typed_fired: usize,
+}
+
+/// A snapshot of how a compression step's search is going, passed to `CompressionObserver::on_progress()`
+#[derive(Clone, Debug)]
+pub struct SearchProgress {
+    pub stats: Stats,              // empty if running with no_stats
+    pub best_utility: Option<i32>, // utility of the best abstraction found so far in this step, if any
+    pub worklist_len: usize,
+}
+
+/// Gets told about the progress of compression, for library users that can't watch stdout. Both methods
+/// do nothing by default. `on_progress()` is called by the search threads while they hold the worklist lock
+/// so it should return quickly.
+pub trait CompressionObserver: Send + Sync {
+    /// called every `progress_interval` worklist items during the search of each compression step
+    fn on_progress(&self, _progress: &SearchProgress) {}
+    /// called with the abstraction chosen at the end of each compression step
+    fn on_step_result(&self, _result: &CompressionStepResult) {}
+}
+
+impl fmt::Debug for dyn CompressionObserver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "CompressionObserver")
+    }
+}
+
+/// An observer that ignores everything
+#[derive(Clone, Debug, Default)]
+pub struct NoObserver;
+
+impl CompressionObserver for NoObserver {}
+
+/// A handle for cancelling compression from another thread. Clones share the same flag. Once cancelled,
+/// the current step stops searching and returns the best abstraction found so far (marked as not proven
+/// optimal), and no further steps are run.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationHandle {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
 }
 
 /// a strategy for choosing which hole to expand next in a partial pattern
@@ -1152,27 +1848,84 @@ impl HoleChoice {
                     .unwrap()
                     .0
             }
-            _ => unimplemented!(),
-        }
-    }
-}
-
-/// tells you which zipper if any you would get if you extended the depth
-/// of whatever the current zipper is in any of these directions.
-#[derive(Clone, Debug)]
-pub struct ZIdExtension {
-    body: Option<ZId>,
-    arg: Option<ZId>,
-    func: Option<ZId>,
-}
-
-/// empties worklist_buf and donelist_buf into the shared worklist while holding the mutex, updates
-/// the donelist and cutoffs, and grabs and returns a new worklist item along with new cutoff bounds.
-//#[inline(never)]
-fn get_worklist_item(
-    worklist_buf: &mut Vec<HeapItem>,
-    donelist_buf: &mut Vec<FinishedPattern>,
-    shared: &Arc<SharedData>,
+            HoleChoice::HighEntropy | HoleChoice::LowEntropy => {
+                let entropies = pattern
+                    .holes
+                    .iter()
+                    .enumerate()
+                    .map(|(hole_idx, hole_zid)| {
+                        (hole_idx, hole_entropy(pattern, *hole_zid, shared))
+                    });
+                if let HoleChoice::HighEntropy = *self {
+                    entropies
+                        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                        .unwrap()
+                        .0
+                } else {
+                    entropies
+                        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                        .unwrap()
+                        .0
+                }
+            }
+            HoleChoice::ManyGroups | HoleChoice::FewGroups => {
+                let num_groups = pattern
+                    .holes
+                    .iter()
+                    .enumerate()
+                    .map(|(hole_idx, hole_zid)| {
+                        (
+                            hole_idx,
+                            pattern
+                                .match_locations
+                                .iter()
+                                .map(|loc| &shared.arg_of_zid_node[*hole_zid][&loc.0].expands_to)
+                                .unique()
+                                .count(),
+                        )
+                    });
+                if let HoleChoice::ManyGroups = *self {
+                    num_groups.max_by_key(|x| x.1).unwrap().0
+                } else {
+                    num_groups.min_by_key(|x| x.1).unwrap().0
+                }
+            }
+        }
+    }
+}
+
+/// entropy of the distribution of what a hole expands into across the match locations of a pattern
+fn hole_entropy(pattern: &Pattern, hole_zid: ZId, shared: &SharedData) -> f64 {
+    let total = pattern.match_locations.len() as f64;
+    -pattern
+        .match_locations
+        .iter()
+        .map(|loc| &shared.arg_of_zid_node[hole_zid][&loc.0].expands_to)
+        .counts()
+        .values()
+        .map(|count| {
+            let p = *count as f64 / total;
+            p * p.ln()
+        })
+        .sum::<f64>()
+}
+
+/// tells you which zipper if any you would get if you extended the depth
+/// of whatever the current zipper is in any of these directions.
+#[derive(Clone, Debug)]
+pub struct ZIdExtension {
+    body: Option<ZId>,
+    arg: Option<ZId>,
+    func: Option<ZId>,
+}
+
+/// empties worklist_buf and donelist_buf into the shared worklist while holding the mutex, updates
+/// the donelist and cutoffs, and grabs and returns a new worklist item along with new cutoff bounds.
+//#[inline(never)]
+fn get_worklist_item(
+    worklist_buf: &mut Vec<HeapItem>,
+    donelist_buf: &mut Vec<FinishedPattern>,
+    shared: &Arc<SharedData>,
 ) -> Option<(Vec<Pattern>, i32)> {
     // * MULTITHREADING: CRITICAL SECTION START *
     // take the lock, which will be released immediately when this scope exits
@@ -1191,7 +1944,7 @@ fn get_worklist_item(
         shared.stats.lock().deref_mut().finished += crit.donelist.len() - old_donelist_len;
     };
     // sort + truncate + update utility_pruning_cutoff
-    crit.update(&shared.cfg); // this also updates utility_pruning_cutoff
+    crit.update(shared); // this also updates utility_pruning_cutoff
 
     if shared.cfg.verbose_best
         && crit.donelist.first().map(|x| x.utility).unwrap_or(0) > old_best_utility
@@ -1233,6 +1986,11 @@ fn get_worklist_item(
         shared.stats.lock().deref_mut().upper_bound_fired +=
             worklist_buf_len - (crit.worklist.len() - old_worklist_len);
     };
+    if let Some(max_worklist) = shared.cfg.max_worklist {
+        if crit.worklist.len() > max_worklist {
+            crit.evict(max_worklist);
+        }
+    }
 
     let mut returned_items = vec![];
 
@@ -1241,6 +1999,15 @@ fn get_worklist_item(
                                                          // if !shared.cfg.quiet { println!("worklist len: {}", crit.worklist.len()) }
 
     loop {
+        // stop handing out worklist items once we run out of budget. Anything we already took goes back on
+        // the worklist so that it counts towards the remaining upper bound
+        if crit.out_of_budget || out_of_budget(shared, crit.steps) {
+            crit.out_of_budget = true;
+            crit.worklist
+                .extend(returned_items.into_iter().map(HeapItem::new));
+            return None;
+        }
+
         // with dynamic batch size, take worklist_size/num_threads items from the worklist
         let batch_size = if shared.cfg.dynamic_batch {
             std::cmp::max(1, crit.worklist.len() / shared.cfg.threads)
@@ -1262,6 +2029,9 @@ fn get_worklist_item(
             crit = shared_guard.deref_mut();
             // update our cutoff in case it changed
             utility_pruning_cutoff = crit.utility_pruning_cutoff;
+            if crit.out_of_budget {
+                return None; // someone else ran out of budget while we were waiting
+            }
         }
 
         let heap_item = crit.worklist.pop().unwrap();
@@ -1271,6 +2041,14 @@ fn get_worklist_item(
         {
             // we got one!
             returned_items.push(heap_item.pattern);
+            crit.steps += 1;
+            if crit.steps.checked_rem(shared.cfg.progress_interval) == Some(0) {
+                shared.observer.on_progress(&SearchProgress {
+                    stats: shared.stats.lock().clone(),
+                    best_utility: crit.donelist.first().map(|done| done.utility),
+                    worklist_len: crit.worklist.len(),
+                });
+            }
             if returned_items.len() == batch_size {
                 // we got enough, so return it
                 crit.active_threads.insert(thread::current().id());
@@ -1283,6 +2061,14 @@ fn get_worklist_item(
     // * MULTITHREADING: CRITICAL SECTION END *
 }
 
+/// whether the search has been cancelled, run out of time, or has processed `steps` worklist items out of a
+/// budget of fewer
+fn out_of_budget(shared: &SharedData, steps: usize) -> bool {
+    shared.cancellation.is_cancelled()
+        || matches!(shared.deadline, Some(deadline) if std::time::Instant::now() >= deadline)
+        || matches!(shared.cfg.step_limit, Some(limit) if steps >= limit)
+}
+
 /// The core top down branch and bound search
 fn stitch_search(shared: Arc<SharedData>) {
     // local buffers to eventually pour into the global worklist and donelist when we take the mutex
@@ -1375,8 +2161,10 @@ fn stitch_search(shared: Arc<SharedData>) {
                     continue 'expansion;
                 }
 
-                // Can't have named variables in the body of a pattern
-                if expands_to == ExpandsTo::NVar {
+                // A free named variable in the body of a pattern has to be an argument instead, which the ivar expansions
+                // already offer. The input of a RevLet is a name rather than a subexpression so it can't be an argument,
+                // it's kept in the body instead (see free_named_vars)
+                if expands_to.has_free_named_var() {
                     continue 'expansion;
                 }
 
@@ -1450,19 +2238,8 @@ fn stitch_search(shared: Arc<SharedData>) {
                 }
 
                 // update the body utility
-                let body_utility = original_pattern.body_utility
-                    + match &expands_to {
-                        ExpandsTo::Lam(_) => shared.cost_fn.cost_lam,
-                        ExpandsTo::App => shared.cost_fn.cost_app,
-                        ExpandsTo::Var(_, _) => shared.cost_fn.cost_var,
-                        ExpandsTo::Prim(p) => *shared
-                            .cost_fn
-                            .cost_prim
-                            .get(p)
-                            .unwrap_or(&shared.cost_fn.cost_prim_default),
-                        ExpandsTo::IVar(_) => 0,
-                        ExpandsTo::NVar => shared.cost_fn.cost_nvar,
-                    };
+                let body_utility =
+                    original_pattern.body_utility + expansion_cost(&expands_to, &shared.cost_fn);
 
                 // update the upper bound
                 let util_upper_bound: i32 = utility_upper_bound(
@@ -1470,7 +2247,7 @@ fn stitch_search(shared: Arc<SharedData>) {
                     body_utility,
                     &shared.cost_of_node_all,
                     &shared.num_paths_to_node,
-                    &shared.cost_fn,
+                    shared.inv_cost,
                     &shared.cfg,
                 );
                 assert!(util_upper_bound <= original_pattern.utility_upper_bound);
@@ -1506,7 +2283,7 @@ fn stitch_search(shared: Arc<SharedData>) {
                         // add new holes
                         holes.push(shared.extensions_of_zid[hole_zid].body.unwrap());
                     }
-                    ExpandsTo::App => {
+                    ExpandsTo::App | ExpandsTo::Let | ExpandsTo::RevLet(_, _) => {
                         // add new holes
                         holes.push(shared.extensions_of_zid[hole_zid].func.unwrap());
                         holes.push(shared.extensions_of_zid[hole_zid].arg.unwrap());
@@ -1514,6 +2291,13 @@ fn stitch_search(shared: Arc<SharedData>) {
                     _ => {}
                 }
 
+                // a RevLet input that isn't bound within the pattern stays a free name of the body (all the locations
+                // share it since they're grouped by what they expand to)
+                let mut free_named_vars = original_pattern.free_named_vars.clone();
+                if let ExpandsTo::RevLet(NamedVar::Free(inp_var), _) = &expands_to {
+                    free_named_vars.push(inp_var.clone());
+                }
+
                 // update arg_choices and possibly first_zid_of_ivar if a new ivar was added
                 let mut arg_choices = original_pattern.arg_choices.clone();
                 let mut first_zid_of_ivar = original_pattern.first_zid_of_ivar.clone();
@@ -1600,6 +2384,7 @@ fn stitch_search(shared: Arc<SharedData>) {
                     match_locations: locs,
                     utility_upper_bound: util_upper_bound,
                     body_utility,
+                    free_named_vars,
                     tracked,
                 };
 
@@ -1611,6 +2396,28 @@ fn stitch_search(shared: Arc<SharedData>) {
                 //     continue 'expansion; // too low utility
                 // }
 
+                // Pruning (TYPES): when running with types, a partial pattern that is already ill typed (its holes can
+                // have any type) or that doesn't fit its arguments somewhere stays that way as the holes get filled
+                // in. Inverse argument capture can turn parts of a finished body into arguments though, so then we
+                // leave it to the check on finished patterns
+                if !new_pattern.holes.is_empty()
+                    && !shared.cfg.inv_arg_cap
+                    && !new_pattern.check_types(&shared)
+                {
+                    if !shared.cfg.no_stats {
+                        shared.stats.lock().deref_mut().ill_typed_fired += 1;
+                    };
+                    if tracked && !shared.cfg.quiet {
+                        println!(
+                            "{} type pruned when expanding {} to {}",
+                            "[TRACK]".red().bold(),
+                            original_pattern.to_expr(&shared),
+                            original_pattern.show_track_expansion(hole_zid, &shared)
+                        )
+                    }
+                    continue 'expansion;
+                }
+
                 if new_pattern.holes.is_empty() {
                     // it's a finished pattern
 
@@ -1642,6 +2449,7 @@ fn stitch_search(shared: Arc<SharedData>) {
                         &shared.extensions_of_zid,
                         &shared.set,
                         &shared.analyzed_ivars,
+                        shared.inv_cost,
                         &shared.cost_fn,
                     );
 
@@ -1650,6 +2458,27 @@ fn stitch_search(shared: Arc<SharedData>) {
                         continue 'expansion; // todo could add a tracked{} printing thing here
                     }
 
+                    // Pruning (TYPES): when running with types every use of the abstraction must be well typed
+                    if !finished_pattern.pattern.check_types(&shared) {
+                        if !shared.cfg.no_stats {
+                            shared.stats.lock().deref_mut().ill_typed_fired += 1;
+                        };
+                        if tracked && !shared.cfg.quiet {
+                            println!(
+                                "{} type pruned when expanding {} to {}",
+                                "[TRACK]".red().bold(),
+                                original_pattern.to_expr(&shared),
+                                original_pattern.show_track_expansion(hole_zid, &shared)
+                            )
+                        }
+                        continue 'expansion;
+                    }
+
+                    // Pruning (REVERSIBLE): when only searching for reversible abstractions
+                    if !finished_pattern.pattern.check_reversible(&shared) {
+                        continue 'expansion;
+                    }
+
                     if !shared.cfg.no_stats {
                         shared.stats.lock().donelist_push += 1;
                     };
@@ -1664,13 +2493,25 @@ fn stitch_search(shared: Arc<SharedData>) {
                         );
                         let (rw_slow, _, _) = rewrite_with_inventions(
                             &shared
-                                .programs
+                                .roots
                                 .iter()
-                                .map(|p| p.to_string())
+                                .map(|root| shared.set.get(*root).to_string())
                                 .collect::<Vec<_>>(),
+                            Some(
+                                shared
+                                    .task_of_root_idx
+                                    .iter()
+                                    .map(|&task| shared.task_name_of_task[task].clone())
+                                    .collect(),
+                            ),
+                            Some(shared.weight_by_root_idx.clone()),
                             &[finished_pattern.clone().to_invention("fake_inv", &shared)],
-                            &shared.multistep_cfg,
-                        );
+                            &MultistepCompressionConfig {
+                                named_lambdas: false, // these are already converted
+                                ..shared.multistep_cfg.clone()
+                            },
+                        )
+                        .unwrap();
                         for (fast, slow) in rw_fast.iter().zip(rw_slow.iter()) {
                             assert_eq!(fast.to_string(), slow.to_string());
                         }
@@ -1819,6 +2660,7 @@ impl FinishedPattern {
             match_locations: pattern.match_locations.clone(),
             utility_upper_bound: pattern.utility_upper_bound,
             body_utility: pattern.body_utility,
+            free_named_vars: pattern.free_named_vars.clone(),
             tracked: pattern.tracked,
         };
 
@@ -1857,11 +2699,12 @@ impl FinishedPattern {
                     .root_idxs_of_task
                     .iter()
                     .map(|root_idxs| {
-                        root_idxs
-                            .iter()
-                            .map(|idx| rewritten[*idx].cost(&shared.cost_fn))
-                            .min()
-                            .unwrap()
+                        task_cost(
+                            root_idxs
+                                .iter()
+                                .map(|idx| rewritten[*idx].cost(&shared.cost_fn)),
+                            shared.cfg.expected_cost,
+                        )
                     })
                     .sum::<i32>();
             // res.compressive_utility = shared.init_cost - rewritten.iter().map(|e|e.cost()).sum::<i32>();
@@ -1897,34 +2740,292 @@ impl FinishedPattern {
 //     refined_subtree_cost: i32, // the compressive utility gained by refining it
 // }
 
+/// The structurally hashed corpus along with everything compression_step precomputes about it: the analyses, the zippers
+/// and the number of paths to each node. Keeping it around between the steps of multistep compression means each step only
+/// has to index the parts of the corpus that the last rewrite changed, since unchanged subtrees hash to the same nodes as
+/// before. Nodes that drop out of the corpus stay in the set with no paths to them. An index should only be used with one
+/// cost function and `let_bodies` setting, and with one sequence of rewrites (see `PartialLibrary::index`).
+#[derive(Debug)]
+pub struct CorpusIndex {
+    pub set: ExprSet,
+    pub roots: Vec<Idx>,
+    pub corpus_span: Span, // the set as of the last update(), before get_zippers() added to it: every node that has been part of the corpus is in here, along with nodes earlier updates' get_zippers() added
+    pub cost_fn: ExprCost,
+    pub analyzed_cost: AnalyzedExpr<ExprCost>,
+    pub analyzed_free_vars: AnalyzedExpr<FreeVarAnalysis>,
+    pub analyzed_ivars: AnalyzedExpr<IVarAnalysis>,
+    pub zid_of_zip: FxHashMap<Vec<ZNode>, ZId>,
+    pub zip_of_zid: Vec<Vec<ZNode>>,
+    pub arg_of_zid_node: Vec<FxHashMap<Idx, Arg>>,
+    pub zids_of_node: FxHashMap<Idx, Vec<ZId>>,
+    pub extensions_of_zid: Vec<ZIdExtension>,
+    pub indexed: Vec<bool>, // whether get_zippers() has processed a node
+    pub num_paths_to_node: Vec<i32>,
+    pub num_paths_to_node_by_root_idx: Vec<Vec<i32>>,
+    pub tasks_of_node: Vec<FxHashSet<usize>>,
+    pub task_of_root_idx: Vec<usize>,
+    pub fresh_len: usize, // size of the set the last time it was built from scratch
+    pub typings: Vec<Option<NodeTyping>>, // principal typing of each node inferred so far, see update_typings()
+    pub typing_prim_types: FxHashMap<Symbol, DcType>, // the primitive types `typings` were inferred with
+}
+
+// the analyses are cheap to recompute from the set, unlike the zippers
+impl Clone for CorpusIndex {
+    fn clone(&self) -> Self {
+        let mut analyzed_cost = AnalyzedExpr::new(self.cost_fn.clone());
+        let mut analyzed_free_vars = AnalyzedExpr::new(FreeVarAnalysis);
+        let mut analyzed_ivars = AnalyzedExpr::new(IVarAnalysis);
+        analyzed_cost.analyze(&self.set);
+        analyzed_free_vars.analyze(&self.set);
+        analyzed_ivars.analyze(&self.set);
+        CorpusIndex {
+            set: self.set.clone(),
+            roots: self.roots.clone(),
+            corpus_span: self.corpus_span.clone(),
+            cost_fn: self.cost_fn.clone(),
+            analyzed_cost,
+            analyzed_free_vars,
+            analyzed_ivars,
+            zid_of_zip: self.zid_of_zip.clone(),
+            zip_of_zid: self.zip_of_zid.clone(),
+            arg_of_zid_node: self.arg_of_zid_node.clone(),
+            zids_of_node: self.zids_of_node.clone(),
+            extensions_of_zid: self.extensions_of_zid.clone(),
+            indexed: self.indexed.clone(),
+            num_paths_to_node: self.num_paths_to_node.clone(),
+            num_paths_to_node_by_root_idx: self.num_paths_to_node_by_root_idx.clone(),
+            tasks_of_node: self.tasks_of_node.clone(),
+            task_of_root_idx: self.task_of_root_idx.clone(),
+            fresh_len: self.fresh_len,
+            typings: self.typings.clone(),
+            typing_prim_types: self.typing_prim_types.clone(),
+        }
+    }
+}
+
+impl CorpusIndex {
+    pub fn new(cost_fn: &ExprCost) -> Self {
+        CorpusIndex {
+            set: ExprSet::empty(Order::ChildFirst, false, true),
+            roots: vec![],
+            corpus_span: 0..0,
+            cost_fn: cost_fn.clone(),
+            analyzed_cost: AnalyzedExpr::new(cost_fn.clone()),
+            analyzed_free_vars: AnalyzedExpr::new(FreeVarAnalysis),
+            analyzed_ivars: AnalyzedExpr::new(IVarAnalysis),
+            zid_of_zip: Default::default(),
+            zip_of_zid: Default::default(),
+            arg_of_zid_node: Default::default(),
+            zids_of_node: Default::default(),
+            extensions_of_zid: Default::default(),
+            indexed: vec![],
+            num_paths_to_node: vec![],
+            num_paths_to_node_by_root_idx: vec![],
+            tasks_of_node: vec![],
+            task_of_root_idx: vec![],
+            fresh_len: 0,
+            typings: vec![],
+            typing_prim_types: Default::default(),
+        }
+    }
+
+    /// Infers the typings of the nodes added to the set since the last call. Typings are inferred bottom up, so
+    /// the earlier ones stay valid as long as none of their primitives changed type, which holds when the only new
+    /// primitive types are for primitives that weren't in the set yet, like the invention of the last step.
+    pub fn update_typings(&mut self, prim_types: &FxHashMap<Symbol, DcType>) {
+        let new_prims: FxHashSet<&Symbol> = prim_types
+            .keys()
+            .filter(|p| !self.typing_prim_types.contains_key(*p))
+            .collect();
+        let still_valid = self
+            .typing_prim_types
+            .iter()
+            .all(|(p, ty)| prim_types.get(p) == Some(ty))
+            && (new_prims.is_empty()
+                || (0..self.typings.len()).all(
+                    |node| !matches!(&self.set[node], Node::Prim(p) if new_prims.contains(p)),
+                ));
+        if !still_valid {
+            self.typings.clear();
+        }
+        infer_typings(&self.set, prim_types, &mut self.typings);
+        self.typing_prim_types = prim_types.clone();
+    }
+
+    /// An index whose set starts out as the set of `program_set` (which has to be structurally hashed and child
+    /// first, like the sets `ProgramSet::parse()` and `InputFormat::Jsonl` make), with its programs as the corpus.
+    /// Nothing is indexed until it's passed to `compression_step_indexed()` without programs, which saves copying
+    /// the programs out of `program_set` only to hash them right back into the same nodes.
+    pub fn from_program_set(program_set: &ProgramSet, cost_fn: &ExprCost) -> Self {
+        CorpusIndex {
+            set: program_set.set.clone(),
+            roots: program_set.roots.clone(),
+            fresh_len: program_set.set.len(),
+            ..CorpusIndex::new(cost_fn)
+        }
+    }
+
+    /// Makes `programs` the corpus, only recomputing paths for the programs that changed since the last update
+    /// and only finding zippers for nodes that haven't been part of the corpus before. Returns the number of
+    /// nodes that had to be indexed.
+    pub fn update(
+        &mut self,
+        programs: &[ExprOwned],
+        task_of_root_idx: &[usize],
+        let_bodies: bool,
+    ) -> usize {
+        let rebuild =
+            self.roots.len() != programs.len() || self.task_of_root_idx != task_of_root_idx;
+        // start over once most of the set is no longer part of the corpus
+        if rebuild || self.set.len() > 4 * self.fresh_len {
+            *self = CorpusIndex::new(&self.cost_fn);
+        }
+        let old_roots = std::mem::take(&mut self.roots);
+        self.roots = programs
+            .iter()
+            .map(|e| e.immut().copy_rec(&mut self.set))
+            .collect();
+        self.index_roots(old_roots, task_of_root_idx, let_bodies)
+    }
+
+    /// Indexes the corpus `roots` after they changed from `old_roots` (empty if nothing was indexed yet), see
+    /// `update()`
+    fn index_roots(
+        &mut self,
+        old_roots: Vec<Idx>,
+        task_of_root_idx: &[usize],
+        let_bodies: bool,
+    ) -> usize {
+        self.task_of_root_idx = task_of_root_idx.to_vec();
+        let len = self.set.len();
+        if old_roots.is_empty() {
+            self.fresh_len = len;
+        }
+        self.corpus_span = 0..len;
+        self.analyzed_cost.analyze(&self.set);
+        self.analyzed_free_vars.analyze(&self.set);
+
+        self.num_paths_to_node.resize(len, 0);
+        self.num_paths_to_node_by_root_idx
+            .resize(self.roots.len(), vec![]);
+        for num_paths in self.num_paths_to_node_by_root_idx.iter_mut() {
+            num_paths.resize(len, 0);
+        }
+        self.tasks_of_node.resize(len, Default::default());
+        self.indexed.resize(len, false);
+
+        let mut root_idxs_of_task: FxHashMap<usize, Vec<usize>> = Default::default();
+        for (root_idx, task) in task_of_root_idx.iter().enumerate() {
+            root_idxs_of_task.entry(*task).or_default().push(root_idx);
+        }
+        for (root_idx, task) in task_of_root_idx.iter().enumerate() {
+            let old_root = old_roots.get(root_idx).copied();
+            if old_root == Some(self.roots[root_idx]) {
+                continue; // this program wasn't rewritten
+            }
+            let mut touched: Vec<Idx> = vec![];
+            let num_paths_of_root = &mut self.num_paths_to_node_by_root_idx[root_idx];
+            if let Some(old_root) = old_root {
+                add_paths(
+                    &self.set,
+                    num_paths_of_root,
+                    &mut self.num_paths_to_node,
+                    old_root,
+                    -1,
+                    &mut touched,
+                );
+            }
+            add_paths(
+                &self.set,
+                num_paths_of_root,
+                &mut self.num_paths_to_node,
+                self.roots[root_idx],
+                1,
+                &mut touched,
+            );
+            touched.sort_unstable();
+            touched.dedup();
+            // a node belongs to a task as long as any program for that task uses it
+            for node in touched {
+                if root_idxs_of_task[task]
+                    .iter()
+                    .any(|i| self.num_paths_to_node_by_root_idx[*i][node] > 0)
+                {
+                    self.tasks_of_node[node].insert(*task);
+                } else {
+                    self.tasks_of_node[node].remove(task);
+                }
+            }
+        }
+
+        // bottom up order, so children are always indexed before their parents
+        let new_nodes: Vec<Idx> = self
+            .corpus_span
+            .clone()
+            .filter(|node| self.num_paths_to_node[*node] > 0 && !self.indexed[*node])
+            .collect();
+        get_zippers(self, &new_nodes, let_bodies);
+
+        self.analyzed_free_vars.analyze(&self.set);
+        self.analyzed_cost.analyze(&self.set);
+        self.analyzed_ivars.analyze(&self.set);
+        new_nodes.len()
+    }
+}
+
+/// adds `delta` to the number of paths to each node under `idx` (following all paths down from it), both for
+/// one program and for the whole corpus
+fn add_paths(
+    set: &ExprSet,
+    num_paths_of_root: &mut [i32],
+    num_paths_to_node: &mut [i32],
+    idx: Idx,
+    delta: i32,
+    touched: &mut Vec<Idx>,
+) {
+    num_paths_of_root[idx] += delta;
+    num_paths_to_node[idx] += delta;
+    touched.push(idx);
+    for child in set.get(idx).children() {
+        add_paths(
+            set,
+            num_paths_of_root,
+            num_paths_to_node,
+            child,
+            delta,
+            touched,
+        );
+    }
+}
+
 /// figure out all the N^2 zippers from choosing any given node and then choosing a descendant and returning the zipper from
 /// the node to the descendant. We also collect a bunch of other useful stuff like the argument you would get if you abstracted
-/// the descendant and introduced an invention rooted at the ancestor node.
-#[allow(clippy::type_complexity)]
+/// the descendant and introduced an invention rooted at the ancestor node. Only `nodes` get processed, so that an index
+/// can be extended with new nodes whose children have already been processed.
 //#[inline(never)]
-fn get_zippers(
-    corpus_span: &Span,
-    analyzed_cost: &AnalyzedExpr<ExprCost>,
-    set: &mut ExprSet,
-    analyzed_free_vars: &mut AnalyzedExpr<FreeVarAnalysis>,
-) -> (
-    FxHashMap<Vec<ZNode>, ZId>,
-    Vec<Vec<ZNode>>,
-    Vec<FxHashMap<Idx, Arg>>,
-    FxHashMap<Idx, Vec<ZId>>,
-    Vec<ZIdExtension>,
-) {
-    let mut zid_of_zip: FxHashMap<Vec<ZNode>, ZId> = Default::default();
-    let mut zip_of_zid: Vec<Vec<ZNode>> = Default::default();
-    let mut arg_of_zid_node: Vec<FxHashMap<Idx, Arg>> = Default::default();
-    let mut zids_of_node: FxHashMap<Idx, Vec<ZId>> = Default::default();
-
-    zid_of_zip.insert(vec![], EMPTY_ZID);
-    zip_of_zid.push(vec![]);
-    arg_of_zid_node.push(FxHashMap::default());
+fn get_zippers(index: &mut CorpusIndex, nodes: &[Idx], let_bodies: bool) {
+    let CorpusIndex {
+        set,
+        analyzed_cost,
+        analyzed_free_vars,
+        zid_of_zip,
+        zip_of_zid,
+        arg_of_zid_node,
+        zids_of_node,
+        extensions_of_zid,
+        indexed,
+        ..
+    } = index;
+
+    if zip_of_zid.is_empty() {
+        zid_of_zip.insert(vec![], EMPTY_ZID);
+        zip_of_zid.push(vec![]);
+        arg_of_zid_node.push(FxHashMap::default());
+    }
 
     // loop over all nodes in all programs in bottom up order
-    for idx in corpus_span.clone() {
+    for &idx in nodes {
+        indexed[idx] = true;
         // if !shared.cfg.quiet { println!("processing Idx={}: {}", treenode, extract(*treenode, egraph) ) }
 
         // clone to appease the borrow checker
@@ -1932,7 +3033,7 @@ fn get_zippers(
         // let node_str = format!("{:?}", node);
 
         match node {
-            Node::Let { .. } | Node::RevLet { .. } => {
+            Node::Let { .. } | Node::RevLet { .. } if !let_bodies => {
                 continue;
             }
             _ => {}
@@ -2061,13 +3162,74 @@ fn get_zippers(
                     arg_of_zid_node[*zid].insert(idx, arg);
                 }
             }
-            Node::Let { .. } => unreachable!(),
-            Node::RevLet { .. } => unreachable!(),
+            Node::Let { .. } | Node::RevLet { .. } => {
+                // the body is under the Func zipper and the definition under the Arg zipper, along with
+                // the named variables that are bound in each of them
+                let children = match &node {
+                    Node::Let { var, def, body } => {
+                        vec![(*body, true, vec![var.clone()]), (*def, false, vec![])]
+                    }
+                    Node::RevLet {
+                        def_vars,
+                        def,
+                        body,
+                        ..
+                    } => vec![
+                        (*body, true, def_vars.clone()),
+                        (*def, false, def_vars.clone()),
+                    ],
+                    _ => unreachable!(),
+                };
+                for (child, is_body, vars) in children {
+                    for c_zid in zids_of_node[&child].iter() {
+                        // a pattern can't look through a variable that it binds itself, it has to keep the variable
+                        if looks_through_named_var(set, child, &zip_of_zid[*c_zid], &vars) {
+                            continue;
+                        }
+                        // clone and extend zip to get new zid for this node
+                        let mut zip = zip_of_zid[*c_zid].clone();
+                        zip.insert(0, if is_body { ZNode::Func } else { ZNode::Arg });
+                        let zid = zid_of_zip.entry(zip.clone()).or_insert_with(|| {
+                            let zid = zip_of_zid.len();
+                            zip_of_zid.push(zip);
+                            arg_of_zid_node.push(FxHashMap::default());
+                            zid
+                        });
+                        // add new zid to this node
+                        zids.push(*zid);
+                        // give it the same arg, but with the variables bound here bound in what it expands to
+                        let mut arg = arg_of_zid_node[*c_zid][&child].clone();
+                        if !vars.is_empty() {
+                            // if the arg looks through a variable bound here it expands to that variable instead
+                            let mut var_idx = arg.unshifted_id;
+                            while let Node::NLinkVar(name, link) = &set[var_idx] {
+                                if vars.contains(name) {
+                                    arg.expands_to = ExpandsTo::NVar(NamedVar::Free(name.clone()));
+                                    arg.is_var_replacement = false;
+                                    break;
+                                }
+                                var_idx = *link;
+                            }
+                            arg.expands_to = arg.expands_to.bind_named_vars(&vars);
+                            if vars
+                                .iter()
+                                .any(|var| analyzed_cost[arg.unshifted_id].1.contains_key(var))
+                            {
+                                // the arg uses a variable bound here so it can't be passed in from outside the binding. Like
+                                // with lambdas, we insert an ivar to indicate this so the location can't be used
+                                arg.shifted_id = set.add(Node::IVar(0));
+                                arg.fill_id = arg.shifted_id;
+                            }
+                        }
+                        arg_of_zid_node[*zid].insert(idx, arg);
+                    }
+                }
+            }
         }
         zids_of_node.insert(idx, zids);
     }
 
-    for idx in corpus_span.clone() {
+    for &idx in nodes {
         match set.get(idx).node() {
             Node::NLinkVar(_, _) | Node::NVar(_) => {
                 arg_of_zid_node[EMPTY_ZID].remove(&idx);
@@ -2076,7 +3238,7 @@ fn get_zippers(
         }
     }
 
-    let extensions_of_zid = zip_of_zid
+    *extensions_of_zid = zip_of_zid
         .iter()
         .map(|zip| {
             let mut zip_body = zip.clone();
@@ -2092,14 +3254,29 @@ fn get_zippers(
             }
         })
         .collect();
+}
 
-    (
-        zid_of_zip,
-        zip_of_zid,
-        arg_of_zid_node,
-        zids_of_node,
-        extensions_of_zid,
-    )
+/// whether following `zip` down from `idx` goes through a linked variable that is one of `vars`
+fn looks_through_named_var(set: &ExprSet, mut idx: Idx, zip: &[ZNode], vars: &[Symbol]) -> bool {
+    for znode in zip {
+        while let Node::NLinkVar(name, link) = &set[idx] {
+            if vars.contains(name) {
+                return true;
+            }
+            idx = *link;
+        }
+        idx = match (&set[idx], znode) {
+            (Node::App(f, _), ZNode::Func) => *f,
+            (Node::App(_, x), ZNode::Arg) => *x,
+            (Node::Lam(b, _), ZNode::Body) => *b,
+            (Node::Let { body, .. }, ZNode::Func) | (Node::RevLet { body, .. }, ZNode::Func) => {
+                *body
+            }
+            (Node::Let { def, .. }, ZNode::Arg) | (Node::RevLet { def, .. }, ZNode::Arg) => *def,
+            _ => unreachable!(),
+        };
+    }
+    false
 }
 
 /// the complete result of a single step of compression, this is a somewhat expensive data structure
@@ -2122,6 +3299,9 @@ pub struct CompressionStepResult {
     pub initial_cost: i32,
     pub name_mapping: Vec<(String, String)>,
     pub dc_comparison_millis: Option<usize>,
+    pub inv_type: Option<DcType>,
+    pub is_reversible: Option<bool>,
+    pub completeness: SearchCompleteness,
 }
 
 impl CompressionStepResult {
@@ -2132,6 +3312,7 @@ impl CompressionStepResult {
         very_first_cost: i32,
         name_mapping: &[(String, String)],
         dc_comparison_millis: Option<usize>,
+        completeness: SearchCompleteness,
     ) -> Self {
         let inv = done.to_invention(inv_name, shared);
         let rewritten = rewrite_fast(
@@ -2147,15 +3328,14 @@ impl CompressionStepResult {
             .root_idxs_of_task
             .iter()
             .map(|root_idxs| {
-                root_idxs
-                    .iter()
-                    .map(|idx| {
+                task_cost(
+                    root_idxs.iter().map(|idx| {
                         (rewritten[*idx].cost(&shared.cost_fn) as f32
                             * shared.weight_by_root_idx[*idx])
                             .round() as i32
-                    })
-                    .min()
-                    .unwrap()
+                    }),
+                    shared.cfg.expected_cost,
+                )
             })
             .sum::<i32>();
         if expected_cost != final_cost && !shared.cfg.quiet {
@@ -2184,6 +3364,15 @@ impl CompressionStepResult {
             })
             .collect();
 
+        let inv_type: Option<DcType> = shared.prim_types.as_ref().and_then(|prim_types| {
+            infer_invention_type(inv.body.immut(), inv.arity, prim_types).ok()
+        });
+
+        let is_reversible: Option<bool> = shared
+            .reversible_prims
+            .as_ref()
+            .map(|reversible_prims| invention_is_reversible(inv.body.immut(), reversible_prims));
+
         // dreamcoder compatability
         let dc_inv_str: String = dc_inv_str(&inv, name_mapping);
         // Rewrite to dreamcoder syntax with all past invention
@@ -2196,22 +3385,7 @@ impl CompressionStepResult {
         let rewritten_dreamcoder: Option<Vec<String>> = if !shared.cfg.rewritten_dreamcoder {
             None
         } else {
-            Some(
-                rewritten
-                    .iter()
-                    .map(|p| {
-                        let mut res: String = p.to_string();
-                        for (name, anonymous) in &name_mapping {
-                            res = replace_prim_with(&res, name, anonymous);
-                        }
-
-                        // Now go ahead and replace the current invention.
-                        res = replace_prim_with(&res, inv_name, &dc_inv_str);
-                        res = res.replace("(lam ", "(lambda ");
-                        res
-                    })
-                    .collect(),
-            )
+            Some(rewritten_to_dreamcoder(&rewritten, &name_mapping))
         };
 
         CompressionStepResult {
@@ -2231,7 +3405,52 @@ impl CompressionStepResult {
             initial_cost: shared.init_cost,
             name_mapping,
             dc_comparison_millis,
+            inv_type,
+            is_reversible,
+            completeness,
+        }
+    }
+    /// renames this candidate to `name` and rewrites the corpus left by `prev` with it by directly matching its
+    /// body, so it can follow `prev` in a batch (see `MultistepCompressionConfig::batch_size`)
+    #[allow(clippy::too_many_arguments)]
+    fn batched_after(
+        mut self,
+        prev: &CompressionStepResult,
+        name: &str,
+        cfg: &CompressionStepConfig,
+        tasks: &[String],
+        weights: &[f32],
+        very_first_cost: i32,
+    ) -> Self {
+        self.inv.name = name.to_string();
+        let (rewritten, _) =
+            rewrite_with_inventions_direct(&prev.rewritten, std::slice::from_ref(&self.inv), cfg);
+        self.expected_cost = prev.final_cost - self.done.compressive_utility;
+        self.final_cost = corpus_cost(
+            &rewritten,
+            &Some(weights.to_vec()),
+            &Some(tasks.to_vec()),
+            &cfg.cost.expr_cost(),
+            cfg.expected_cost,
+        );
+        if self.expected_cost != self.final_cost && !cfg.quiet {
+            println!(
+                "*** expected cost {} != final cost {}",
+                self.expected_cost, self.final_cost
+            )
+        }
+        // like a sequential step this is relative to the corpus it rewrote
+        self.multiplier = prev.final_cost as f64 / self.final_cost as f64;
+        self.multiplier_wrt_orig = very_first_cost as f64 / self.final_cost as f64;
+        self.name_mapping = prev.name_mapping.clone();
+        self.name_mapping
+            .push((self.inv.name.clone(), self.dc_inv_str.clone()));
+        if self.rewritten_dreamcoder.is_some() {
+            self.rewritten_dreamcoder =
+                Some(rewritten_to_dreamcoder(&rewritten, &self.name_mapping));
         }
+        self.rewritten = rewritten;
+        self
     }
     pub fn json(&self, cfg: &CompressionStepConfig) -> serde_json::Value {
         let all_uses: Vec<serde_json::Value> = {
@@ -2291,25 +3510,120 @@ impl CompressionStepResult {
             "rewritten": rewritten,
             "rewritten_dreamcoder": rewritten_dreamcoder,
             "uses": all_uses,
-            "dc_comparison_millis": self.dc_comparison_millis
+            "dc_comparison_millis": self.dc_comparison_millis,
+            "type": self.inv_type.as_ref().map(|ty| ty.to_string()),
+            "is_reversible": self.is_reversible,
+            "proven_optimal": self.completeness.remaining_upper_bound.is_none(),
+            "remaining_upper_bound": self.completeness.remaining_upper_bound,
+            "evicted_patterns": self.completeness.evicted_patterns,
+            "evicted_bound_mass": self.completeness.evicted_bound_mass,
         })
     }
 }
 
-impl fmt::Display for CompressionStepResult {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.expected_cost != self.final_cost {
-            write!(
-                f,
-                "[cost mismatch of {}] ",
-                self.expected_cost - self.final_cost
-            )?;
+/// rewritten programs in dreamcoder syntax, given the dreamcoder strings of all the inventions used in them
+fn rewritten_to_dreamcoder(
+    rewritten: &[ExprOwned],
+    name_mapping: &[(String, String)],
+) -> Vec<String> {
+    // replace_prim_with() makes sure we don't replace "inv1" inside of "inv10"
+    rewritten
+        .iter()
+        .map(|p| {
+            let mut res: String = p.to_string();
+            for (name, anonymous) in name_mapping {
+                res = replace_prim_with(&res, name, anonymous);
+            }
+            res.replace("(lam ", "(lambda ")
+        })
+        .collect()
+}
+
+/// every node in the subtrees rooted at `roots`
+fn descendants(set: &ExprSet, roots: &[Idx]) -> FxHashSet<Idx> {
+    let mut seen: FxHashSet<Idx> = FxHashSet::default();
+    let mut stack: Vec<Idx> = roots.to_vec();
+    while let Some(idx) = stack.pop() {
+        if !seen.insert(idx) {
+            continue;
+        }
+        match &set[idx] {
+            Node::App(f, x) => stack.extend([*f, *x].iter()),
+            Node::Lam(b, _) => stack.push(*b),
+            Node::Let { def, body, .. } | Node::RevLet { def, body, .. } => {
+                stack.extend([*def, *body].iter())
+            }
+            Node::Prim(_)
+            | Node::Var(_, _)
+            | Node::IVar(_)
+            | Node::NVar(_)
+            | Node::NLinkVar(_, _) => {}
         }
-        write!(
-            f,
-            "utility: {} | final_cost: {} | {:.2}x | uses: {} | body: {}",
-            self.done.utility, self.final_cost, self.multiplier, self.uses, self.inv
-        )
+    }
+    seen
+}
+
+/// Takes the best invention found in a step along with up to `batch_size - 1` of the next best candidates whose uses
+/// don't overlap the uses of any invention taken before them (see `MultistepCompressionConfig::batch_size`). Since
+/// they don't interact, each can be rewritten into the corpus left by the one before it without searching again.
+/// The inventions are named starting at `first_inv`.
+#[allow(clippy::too_many_arguments)]
+pub fn batch_step_results(
+    candidates: Vec<CompressionStepResult>,
+    batch_size: usize,
+    first_inv: usize,
+    cfg: &MultistepCompressionConfig,
+    tasks: &[String],
+    weights: &[f32],
+    very_first_cost: i32,
+) -> Vec<CompressionStepResult> {
+    let mut candidates = candidates.into_iter();
+    let mut batch: Vec<CompressionStepResult> = candidates.next().into_iter().collect();
+    // the parts of the corpus used by the inventions taken so far
+    let mut covered: FxHashSet<Idx> = batch
+        .iter()
+        .flat_map(|res| descendants(&res.set, &res.use_exprs))
+        .collect();
+    for candidate in candidates {
+        if batch.len() >= batch_size {
+            break;
+        }
+        let used = descendants(&candidate.set, &candidate.use_exprs);
+        if used.iter().any(|idx| covered.contains(idx)) {
+            continue;
+        }
+        covered.extend(used);
+        let name = format!("{}{}", cfg.abstraction_prefix, first_inv + batch.len());
+        let res = candidate.batched_after(
+            batch.last().unwrap(),
+            &name,
+            &cfg.step,
+            tasks,
+            weights,
+            very_first_cost,
+        );
+        batch.push(res);
+    }
+    batch
+}
+
+impl fmt::Display for CompressionStepResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.expected_cost != self.final_cost {
+            write!(
+                f,
+                "[cost mismatch of {}] ",
+                self.expected_cost - self.final_cost
+            )?;
+        }
+        if let Some(bound) = self.completeness.remaining_upper_bound {
+            write!(f, "[not proven optimal, upper bound {bound}] ")?;
+        }
+        write!(
+            f,
+            "utility: {} | final_cost: {} | {:.2}x | uses: {} | body: {}",
+            self.done.utility, self.final_cost, self.multiplier, self.uses, self.inv
+        )
     }
 }
 
@@ -2320,14 +3634,14 @@ fn utility_upper_bound(
     body_utility_lower_bound: i32,
     cost_of_node_all: &[i32],
     num_paths_to_node: &[i32],
-    cost_fn: &ExprCost,
+    inv_cost: i32,
     cfg: &CompressionStepConfig,
 ) -> i32 {
     compressive_utility_upper_bound(
         match_locations,
         cost_of_node_all,
         num_paths_to_node,
-        cost_fn,
+        inv_cost,
     ) + noncompressive_utility_upper_bound(body_utility_lower_bound, cfg)
 }
 
@@ -2352,13 +3666,11 @@ fn compressive_utility_upper_bound(
     match_locations: &[(Idx, i32)],
     cost_of_node_all: &[i32],
     num_paths_to_node: &[i32],
-    cost_fn: &ExprCost,
+    inv_cost: i32,
 ) -> i32 {
     match_locations
         .iter()
-        .map(|node| {
-            cost_of_node_all[node.0] - num_paths_to_node[node.0] * cost_fn.cost_prim_default
-        })
+        .map(|node| cost_of_node_all[node.0] - num_paths_to_node[node.0] * inv_cost)
         .sum::<i32>()
 
     // shared.init_cost - shared.root_idxs_of_task.iter().map(|root_idxs|
@@ -2399,17 +3711,16 @@ fn compressive_utility(pattern: &Pattern, shared: &SharedData) -> UtilityCalcula
             .root_idxs_of_task
             .iter()
             .map(|root_idxs| {
-                root_idxs
-                    .iter()
-                    .map(|idx| {
+                task_cost(
+                    root_idxs.iter().map(|idx| {
                         (shared.init_cost_by_root_idx_weighted[*idx]
                             - (std::cmp::max(cumulative_utility_of_node[shared.roots[*idx]], 0)
                                 as f32
                                 * shared.weight_by_root_idx[*idx]))
                             .round() as i32
-                    })
-                    .min()
-                    .unwrap()
+                    }),
+                    shared.cfg.expected_cost,
+                )
             })
             .sum::<i32>();
 
@@ -2442,8 +3753,8 @@ fn get_utility_of_loc_once(pattern: &Pattern, shared: &SharedData) -> Vec<i32> {
     // it costs a tiny bit to apply the invention, for example (app (app inv0 x) y) incurs a cost
     // of COST_TERMINAL for the `inv0` primitive and 2 * COST_NONTERMINAL for the two `app`s.
     // Also an extra COST_NONTERMINAL for each argument that is refined (for the lambda).
-    let app_penalty = -(shared.cost_fn.cost_prim_default
-        + shared.cost_fn.cost_app * pattern.first_zid_of_ivar.len() as i32);
+    let app_penalty =
+        -(shared.inv_cost + shared.cost_fn.cost_app * pattern.first_zid_of_ivar.len() as i32);
 
     // get a list of (ivar,usages-1) filtering out things that are only used once, this will come in handy for adding multi-use utility later
     let ivar_multiuses: Vec<(usize, i32)> = pattern
@@ -2511,6 +3822,9 @@ fn bottom_up_utility_correction(
         vec![Default::default(); shared.corpus_span.len()];
 
     for node in shared.corpus_span.clone() {
+        if shared.num_paths_to_node[node] == 0 {
+            continue; // no longer part of the corpus (see CorpusIndex)
+        }
         let utility_without_rewrite: i32 = match &shared.set[node] {
             Node::Lam(b, _) => {
                 var_uses[node] = var_uses[*b].clone();
@@ -2695,6 +4009,14 @@ fn bottom_up_utility_correction(
                         var_utilities_with_rewrite.entry(k.clone()).or_insert(*v);
                     });
             }
+            // the free RevLet inputs of the body are still used after rewriting
+            for var in pattern.free_named_vars.iter() {
+                var_uses_with_rewrite
+                    .entry(var.clone())
+                    .and_modify(|v| *v += 1)
+                    .or_insert(1);
+                var_utilities_with_rewrite.entry(var.clone()).or_insert(0);
+            }
 
             let utility_with_rewrite = utility_of_args + utility_of_loc_once[idx];
 
@@ -2734,10 +4056,11 @@ pub fn inverse_delta(
     cost_once: i32,
     usages: i32,
     arg_uses: usize,
+    inv_cost: i32,
     cost_fn: &ExprCost,
 ) -> (i32, i32, i32) {
     let compressive_delta = -(cost_once + cost_fn.cost_app) * usages;
-    let noncompressive_delta = arg_uses as i32 * (cost_once - cost_fn.cost_prim_default);
+    let noncompressive_delta = arg_uses as i32 * (cost_once - inv_cost);
     (
         compressive_delta,
         noncompressive_delta,
@@ -2755,6 +4078,7 @@ pub fn inverse_argument_capture(
     extensions_of_zid: &[ZIdExtension],
     set: &ExprSet,
     analyzed_ivars: &AnalyzedExpr<IVarAnalysis>,
+    inv_cost: i32,
     cost_fn: &ExprCost,
 ) {
     if !cfg.inv_arg_cap || cfg.no_other_util {
@@ -2775,7 +4099,7 @@ pub fn inverse_argument_capture(
             set,
             analyzed_ivars,
         );
-        let possible_to_uninline = possible_to_uninline(counts, finished.usages, cost_fn);
+        let possible_to_uninline = possible_to_uninline(counts, finished.usages, inv_cost, cost_fn);
 
         let best = possible_to_uninline
             .into_iter()
@@ -2803,6 +4127,7 @@ pub fn inverse_argument_capture(
 fn possible_to_uninline(
     counts: FxHashMap<Idx, (i32, Vec<usize>)>,
     finished_usages: i32,
+    inv_cost: i32,
     cost_fn: &ExprCost,
 ) -> Vec<(i32, i32, i32, i32, Vec<ZId>)> {
     let possible_to_uninline = counts
@@ -2811,10 +4136,10 @@ fn possible_to_uninline(
         // there are usages of the abstraction in the corpus
         .filter(|(_, zids)| zids.len() > finished_usages as usize)
         // argument must be larger than the cost of adding the terminal for the new abstraction variable
-        .filter(|(cost, _zids)| *cost > cost_fn.cost_prim_default)
+        .filter(|(cost, _zids)| *cost > inv_cost)
         .filter_map(|(cost, zids)| {
             let (compressive_delta, noncompressive_delta, delta) =
-                inverse_delta(*cost, finished_usages, zids.len(), cost_fn);
+                inverse_delta(*cost, finished_usages, zids.len(), inv_cost, cost_fn);
             if delta > 0 {
                 Some((
                     delta,
@@ -2901,7 +4226,15 @@ fn use_counts(
                 );
                 curr_zip.pop();
             }
-            Node::App(f, x) => {
+            Node::NVar(_) | Node::NLinkVar(_, _) => {}
+            // lets have their body under the Func zipper and their definition under the Arg zipper
+            Node::App(f, x)
+            | Node::Let {
+                body: f, def: x, ..
+            }
+            | Node::RevLet {
+                body: f, def: x, ..
+            } => {
                 curr_zip.push(ZNode::Func);
                 let new_zid = extensions_of_zid[curr_zid].func.unwrap();
                 helper(
@@ -2955,17 +4288,20 @@ fn use_counts(
     counts
 }
 
-/// Multistep compression
+/// Multistep compression. Returns an error if the config is invalid (see `MultistepCompressionConfig::validate()`)
+/// or `follow` doesn't have an invention for each iteration.
+#[allow(clippy::too_many_arguments)]
 pub fn multistep_compression_internal(
-    train_programs: &[ExprOwned],
+    train_programs: &ProgramSet,
     tasks: Option<Vec<String>>,
     weights: Option<Vec<f32>>,
     name_mapping: &mut Vec<(String, String)>,
     follow: Option<Vec<Invention>>,
     cfg: &MultistepCompressionConfig,
-) -> Vec<CompressionStepResult> {
-    let mut rewritten: Vec<ExprOwned> = train_programs.to_vec();
-    let mut step_results: Vec<CompressionStepResult> = Default::default();
+    observer: &Arc<dyn CompressionObserver>,
+    cancellation: &CancellationHandle,
+) -> Result<Vec<CompressionStepResult>, StitchError> {
+    cfg.validate()?;
     let cost_fn = &cfg.step.cost.expr_cost();
 
     let tstart = std::time::Instant::now();
@@ -2973,80 +4309,236 @@ pub fn multistep_compression_internal(
     let mut cfg = cfg.clone();
 
     if let Some(follow) = &follow {
-        assert_eq!(follow.len(), cfg.iterations);
+        if follow.len() != cfg.iterations {
+            return Err(StitchError::InvalidConfig(format!(
+                "got {} inventions to follow for {} iterations",
+                follow.len(),
+                cfg.iterations
+            )));
+        }
         cfg.step.follow_prune = true;
         cfg.step.rewrite_check = false; // this will cause a loop
         if !cfg.verbose_rewrite {
             cfg.step.quiet = true;
         }
         cfg.step.no_opt();
+        cfg.lookahead_beam = 1;
+        cfg.batch_size = 1;
     }
-
-    let very_first_cost = min_cost(train_programs, &weights, &tasks, cost_fn);
+    let lookahead_beam = std::cmp::max(1, cfg.lookahead_beam);
+    let batch_size = std::cmp::max(1, cfg.batch_size);
+    cfg.step.inv_candidates = std::cmp::max(cfg.step.inv_candidates, batch_size);
+
+    let very_first_cost = corpus_cost_of_costs(
+        &train_programs.costs(cost_fn),
+        &weights,
+        &tasks,
+        cfg.step.expected_cost,
+    );
 
     let tasks: Vec<String> =
         tasks.unwrap_or_else(|| (0..train_programs.len()).map(|i| i.to_string()).collect());
 
     let weights: Vec<f32> = weights.unwrap_or_else(|| vec![1.0; train_programs.len()]);
 
+    let mut beam: Vec<PartialLibrary> = vec![PartialLibrary {
+        rewritten: None,
+        name_mapping: name_mapping.clone(),
+        cfg,
+        step_results: Default::default(),
+        cost: very_first_cost,
+        index: Some(Arc::new(CorpusIndex::from_program_set(
+            train_programs,
+            cost_fn,
+        ))),
+    }];
+    // partial libraries that no compressive abstraction could be added to
+    let mut finished: Vec<PartialLibrary> = Default::default();
+    let incremental_index = beam[0].cfg.incremental_index;
+
     let mut i = 0;
-    while (i < cfg.iterations) || cfg.iterations == 0 {
-        if !cfg.step.quiet {
+    while (i < beam[0].cfg.iterations) || beam[0].cfg.iterations == 0 {
+        if !beam[0].cfg.step.quiet {
             println!("{}", format!("\n=======Iteration {i}=======").blue().bold())
         }
-        let inv_name = if let Some(follow) = &follow {
-            cfg.step.follow = Some(follow[i].body.to_string());
-            follow[i].name.clone()
-        } else {
-            format!(
-                "{}{}",
-                cfg.abstraction_prefix,
-                cfg.previous_abstractions + step_results.len()
-            )
-        };
-
-        // call actual compression
-        let res: Vec<CompressionStepResult> = compression_step(
-            &rewritten,
-            &inv_name,
-            &cfg,
-            &tasks,
-            &weights,
-            very_first_cost,
-            name_mapping,
-        );
+        let mut next_beam: Vec<PartialLibrary> = Default::default();
+        for mut library in beam {
+            // the first of the siblings to get here has to copy the index they share, the last one gets to keep it
+            let mut index: Option<CorpusIndex> = library
+                .index
+                .take()
+                .map(|index| Arc::try_unwrap(index).unwrap_or_else(|index| (*index).clone()));
+            let inv_name = if let Some(follow) = &follow {
+                library.cfg.step.follow = Some(follow[i].body.to_string());
+                follow[i].name.clone()
+            } else {
+                format!(
+                    "{}{}",
+                    library.cfg.abstraction_prefix,
+                    library.cfg.previous_abstractions + library.step_results.len()
+                )
+            };
 
-        if !res.is_empty() {
-            // rewrite with the invention
-            let res: CompressionStepResult = res[0].clone();
-            rewritten.clone_from(&res.rewritten);
-            *name_mapping = res.name_mapping.clone();
-            if !cfg.step.quiet {
-                println!("Chose Invention {}: {}", res.inv.name, res)
+            // call actual compression
+            let res: Vec<CompressionStepResult> =
+                if library.cfg.step.reversible_abstractions == ReversibleAbstractions::First {
+                    // look for a reversible abstraction first and only fall back to other abstractions if there isn't one
+                    let mut reversible_cfg = library.cfg.clone();
+                    reversible_cfg.step.reversible_abstractions = ReversibleAbstractions::Only;
+                    let res = compression_step_indexed(
+                        &mut index,
+                        library.rewritten.as_deref(),
+                        &inv_name,
+                        &reversible_cfg,
+                        &tasks,
+                        &weights,
+                        very_first_cost,
+                        &library.name_mapping,
+                        observer,
+                        cancellation,
+                    )?;
+                    if !res.is_empty() {
+                        res
+                    } else {
+                        compression_step_indexed(
+                            &mut index,
+                            library.rewritten.as_deref(),
+                            &inv_name,
+                            &library.cfg,
+                            &tasks,
+                            &weights,
+                            very_first_cost,
+                            &library.name_mapping,
+                            observer,
+                            cancellation,
+                        )?
+                    }
+                } else {
+                    compression_step_indexed(
+                        &mut index,
+                        library.rewritten.as_deref(),
+                        &inv_name,
+                        &library.cfg,
+                        &tasks,
+                        &weights,
+                        very_first_cost,
+                        &library.name_mapping,
+                        observer,
+                        cancellation,
+                    )?
+                };
+            // the index now holds this library's corpus, which its children only change part of. A library
+            // without inventions keeps it regardless, since the index is the only place its corpus is
+            if incremental_index || (library.rewritten.is_none() && res.is_empty()) {
+                library.index = index.map(Arc::new);
             }
-            step_results.push(res);
-        } else if follow.is_some() {
-            // if `follow` was given then we will keep going for the full set of iterations
-            if !cfg.step.quiet {
-                println!("Invention not found: {}", cfg.step.follow.as_ref().unwrap())
+
+            if !res.is_empty() {
+                let branches: Vec<Vec<CompressionStepResult>> = if lookahead_beam > 1 {
+                    // the beam branches on all of the inventions
+                    res.into_iter().map(|res| vec![res]).collect()
+                } else {
+                    // greedy search only ever takes the best invention (and in batch mode the ones that don't overlap it)
+                    let batch_size = if library.cfg.iterations == 0 {
+                        batch_size
+                    } else {
+                        std::cmp::min(
+                            batch_size,
+                            library.cfg.iterations - library.step_results.len(),
+                        )
+                    };
+                    vec![batch_step_results(
+                        res,
+                        batch_size,
+                        library.cfg.previous_abstractions + library.step_results.len(),
+                        &library.cfg,
+                        &tasks,
+                        &weights,
+                        very_first_cost,
+                    )]
+                };
+                for batch in branches {
+                    let mut child = library.clone();
+                    for res in batch {
+                        if lookahead_beam == 1 {
+                            if !library.cfg.step.quiet {
+                                println!("Chose Invention {}: {}", res.inv.name, res)
+                            }
+                            observer.on_step_result(&res);
+                        }
+                        child = child.extend(res);
+                    }
+                    next_beam.push(child);
+                }
+            } else if follow.is_some() {
+                // if `follow` was given then we will keep going for the full set of iterations
+                if !library.cfg.step.quiet {
+                    println!(
+                        "Invention not found: {}",
+                        library.cfg.step.follow.as_ref().unwrap()
+                    )
+                }
+                next_beam.push(library);
+            } else {
+                if !library.cfg.step.quiet {
+                    println!("No inventions found at iteration {i}")
+                }
+                library.index = None; // won't be stepped again
+                finished.push(library);
             }
-        } else {
-            if !cfg.step.quiet {
-                println!("No inventions found at iteration {i}")
+        }
+        // keep the partial libraries that compress the corpus the most (stable, so ties go to the better invention)
+        next_beam.sort_by_key(|library| library.cost);
+        next_beam.truncate(lookahead_beam);
+        beam = next_beam;
+
+        if beam.is_empty() {
+            break;
+        }
+        if beam[0].cfg.iterations != 0 && beam[0].step_results.len() >= beam[0].cfg.iterations {
+            // batches can find all the inventions we asked for in fewer iterations
+            break;
+        }
+        if cancellation.is_cancelled() {
+            if !beam[0].cfg.step.quiet {
+                println!("Cancelled at iteration {i}")
             }
             break;
         }
         i += 1;
     }
 
+    // the first minimum, so a library that was still being extended wins ties
+    let best = beam
+        .into_iter()
+        .chain(finished)
+        .min_by_key(|library| library.cost)
+        .unwrap();
+    if lookahead_beam > 1 {
+        for res in best.step_results.iter() {
+            if !best.cfg.step.quiet {
+                println!("Chose Invention {}: {}", res.inv.name, res)
+            }
+            observer.on_step_result(res);
+        }
+    }
+    let PartialLibrary {
+        rewritten,
+        name_mapping: best_name_mapping,
+        cfg,
+        step_results,
+        ..
+    } = best;
+    *name_mapping = best_name_mapping;
+
     if cfg.step.show_rewritten {
         println!(
             "rewritten:\n{}",
-            rewritten
-                .iter()
-                .map(|p| p.to_string())
-                .collect::<Vec<_>>()
-                .join("\n")
+            match &rewritten {
+                Some(rewritten) => rewritten.iter().map(|p| p.to_string()).collect(),
+                None => train_programs.strings(),
+            }
+            .join("\n")
         );
     }
 
@@ -3056,12 +4548,16 @@ pub fn multistep_compression_internal(
     if !cfg.step.quiet {
         println!("Found {} inventions", step_results.len())
     }
-    let rewritten_cost = min_cost(
-        &rewritten,
-        &Some(weights.clone()),
-        &Some(tasks.clone()),
-        cost_fn,
-    );
+    let rewritten_cost = match &rewritten {
+        Some(rewritten) => corpus_cost(
+            rewritten,
+            &Some(weights.clone()),
+            &Some(tasks.clone()),
+            cost_fn,
+            cfg.step.expected_cost,
+        ),
+        None => very_first_cost,
+    };
     if !cfg.step.quiet {
         println!(
             "Cost Improvement: ({:.2}x better) {} -> {}",
@@ -3071,11 +4567,12 @@ pub fn multistep_compression_internal(
         )
     }
     for res in step_results.iter() {
-        let rewritten_cost = min_cost(
+        let rewritten_cost = corpus_cost(
             &res.rewritten,
             &Some(weights.clone()),
             &Some(tasks.clone()),
             cost_fn,
+            cfg.step.expected_cost,
         );
         if !cfg.step.quiet {
             println!(
@@ -3099,10 +4596,60 @@ pub fn multistep_compression_internal(
         println!("{} you often want to run --follow-track with --no-opt otherwise your target may get pruned", "[WARNING]".yellow())
     }
 
-    step_results
+    Ok(step_results)
+}
+
+/// A partial library in the multistep beam search (see `MultistepCompressionConfig::lookahead_beam`) along
+/// with the corpus rewritten under it
+#[derive(Debug, Clone)]
+pub struct PartialLibrary {
+    pub rewritten: Option<Vec<ExprOwned>>, // None before the first invention, when the corpus is still the one in `index`
+    pub name_mapping: Vec<(String, String)>,
+    pub cfg: MultistepCompressionConfig, // includes the types and reversibility of the inventions so far
+    pub step_results: Vec<CompressionStepResult>,
+    pub cost: i32, // cost of the rewritten corpus
+    // with `incremental_index`, the index of the corpus this library's last invention was found in. Libraries
+    // extending the same one share it, since each of them only rewrote part of that corpus
+    pub index: Option<Arc<CorpusIndex>>,
+}
+
+impl PartialLibrary {
+    /// this library with one more invention, rewriting the corpus with it
+    pub fn extend(&self, res: CompressionStepResult) -> PartialLibrary {
+        let mut cfg = self.cfg.clone();
+        if let Some(inv_type) = &res.inv_type {
+            // later steps need to know the type of the new primitive
+            cfg.step
+                .prim_types
+                .types
+                .push((res.inv.name.clone(), inv_type.to_string()));
+        }
+        if let Some(is_reversible) = res.is_reversible {
+            // and whether it is reversible
+            cfg.step
+                .reversible_prims
+                .prims
+                .push((res.inv.name.clone(), is_reversible));
+        }
+        let rewritten = Some(res.rewritten.clone());
+        let name_mapping = res.name_mapping.clone();
+        let cost = res.final_cost;
+        let mut step_results = self.step_results.clone();
+        step_results.push(res);
+        PartialLibrary {
+            rewritten,
+            name_mapping,
+            cfg,
+            step_results,
+            cost,
+            index: self.index.clone(),
+        }
+    }
 }
 
-/// Takes a set of programs and does one full step of compresison.
+/// Takes a set of programs and does one full step of compresison. Returns an error if the config is invalid
+/// (see `MultistepCompressionConfig::validate()`).
+#[allow(clippy::too_many_arguments)]
 pub fn compression_step(
     programs: &[ExprOwned],
     new_inv_name: &str, // name of the new invention, like "inv4"
@@ -3111,41 +4658,65 @@ pub fn compression_step(
     weights: &[f32],
     very_first_cost: i32,
     name_mapping: &[(String, String)],
-) -> Vec<CompressionStepResult> {
+    observer: &Arc<dyn CompressionObserver>,
+    cancellation: &CancellationHandle,
+) -> Result<Vec<CompressionStepResult>, StitchError> {
+    compression_step_indexed(
+        &mut None,
+        Some(programs),
+        new_inv_name,
+        multistep_cfg,
+        tasks,
+        weights,
+        very_first_cost,
+        name_mapping,
+        observer,
+        cancellation,
+    )
+}
+
+/// The parts of the `CorpusIndex` that `SharedData` doesn't hold on to, for handing the index back after a step
+struct IndexRemainder {
+    cost_fn: ExprCost,
+    indexed: Vec<bool>,
+    fresh_len: usize,
+    typing_prim_types: FxHashMap<Symbol, DcType>,
+}
+
+/// Everything `compression_step_indexed()` does before the search: indexes the corpus, sets up tracking, finds
+/// the arity zero inventions and builds the `SharedData` with a worklist holding just the single hole pattern.
+/// Returns None (leaving the index in `index`) if the tracked abstraction can't match anywhere in the corpus.
+#[allow(clippy::too_many_arguments)]
+fn prepare_search(
+    index: &mut Option<CorpusIndex>,
+    programs: Option<&[ExprOwned]>,
+    new_inv_name: &str,
+    multistep_cfg: &MultistepCompressionConfig,
+    tasks: &[String],
+    weights: &[f32],
+    observer: &Arc<dyn CompressionObserver>,
+    cancellation: &CancellationHandle,
+    tstart_total: std::time::Instant,
+) -> Result<Option<(Arc<SharedData>, IndexRemainder)>, StitchError> {
     let cfg = &multistep_cfg.step.clone();
 
+    // before anything else so that the index is left alone if they don't parse
+    let prim_types: Option<FxHashMap<Symbol, DcType>> = if cfg.prim_types.types.is_empty() {
+        None
+    } else {
+        Some(
+            parse_prim_types(&cfg.prim_types.types)
+                .map_err(|e| StitchError::InvalidConfig(format!("bad primitive types: {e}")))?,
+        )
+    };
+
     let cost_fn = &cfg.cost.expr_cost();
+    // the cost of the primitive the new invention will be rewritten to
+    let inv_cost = prim_cost(cost_fn, &Symbol::from(new_inv_name));
 
-    let tstart_total = std::time::Instant::now();
     let tstart_prep = std::time::Instant::now();
     let mut tstart = std::time::Instant::now();
 
-    // structurally hashed exprset
-    let mut set = ExprSet::empty(Order::ChildFirst, false, true);
-    let roots: Vec<Idx> = programs
-        .iter()
-        .map(|e| e.immut().copy_rec(&mut set))
-        .collect();
-    let corpus_span: Span = 0..set.len();
-
-    // println!("Merged set");
-    // for (i, node) in set.nodes.iter().enumerate() {
-    //     println!("{} {:?}", i, node);
-    // }
-
-    let mut analyzed_cost = AnalyzedExpr::new(cost_fn.clone());
-    analyzed_cost.analyze(&set);
-
-    // populate num_paths_to_node so we know how many different parts of the programs tree
-    // a node participates in (ie multiple uses within a single program or among programs)
-    let (num_paths_to_node, num_paths_to_node_by_root_idx): (Vec<i32>, Vec<Vec<i32>>) =
-        num_paths_to_node(&roots, &corpus_span, &set);
-
-    if !cfg.quiet {
-        println!("num_paths_to_node(): {:?}ms", tstart.elapsed().as_millis())
-    }
-    tstart = std::time::Instant::now();
-
     let mut task_name_of_task: Vec<String> = vec![];
     let mut task_of_root_idx: Vec<usize> = vec![];
     let mut root_idxs_of_task: Vec<Vec<usize>> = vec![];
@@ -3161,8 +4732,105 @@ pub fn compression_step(
         task_of_root_idx.push(task);
         root_idxs_of_task[task].push(root_idx);
     }
-    let tasks_of_node: Vec<FxHashSet<usize>> =
-        associate_tasks(&roots, &set, &corpus_span, &task_of_root_idx);
+
+    // the structurally hashed corpus along with num_paths_to_node (how many different parts of the programs
+    // tree a node participates in), tasks_of_node, the analyses and the zippers
+    let mut corpus = index.take().unwrap_or_else(|| CorpusIndex::new(cost_fn));
+    let num_indexed = match programs {
+        Some(programs) => corpus.update(programs, &task_of_root_idx, cfg.let_bodies),
+        // the programs the index was seeded with (see CorpusIndex::from_program_set()), unless an earlier step
+        // already indexed them
+        None if corpus.task_of_root_idx.is_empty() && corpus.roots.len() == tasks.len() => {
+            corpus.index_roots(vec![], &task_of_root_idx, cfg.let_bodies)
+        }
+        None if corpus.task_of_root_idx == task_of_root_idx => 0,
+        None => {
+            let num_programs = corpus.roots.len();
+            *index = Some(corpus);
+            return Err(StitchError::InvalidConfig(format!(
+                "got {} tasks but no programs, for an index seeded with {num_programs} programs",
+                tasks.len()
+            )));
+        }
+    };
+
+    if !cfg.quiet {
+        println!(
+            "indexed {} new nodes: {:?}ms",
+            num_indexed,
+            tstart.elapsed().as_millis()
+        )
+    }
+    tstart = std::time::Instant::now();
+
+    if !cfg.quiet {
+        println!("{} zips", corpus.zip_of_zid.len())
+    }
+    if !cfg.quiet {
+        println!("arg_of_zid_node size: {}", corpus.arg_of_zid_node.len())
+    }
+
+    // set up tracking if any
+    let tracking: Option<Tracking> = {
+        if let Some(s) = &cfg.follow {
+            let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+            let idx = set.parse_extend(s).unwrap();
+            let expr = ExprOwned::new(set, idx);
+            if let Some(zids_of_ivar) = zids_of_ivar_of_expr(&expr, &corpus.zid_of_zip) {
+                Some(Tracking { expr, zids_of_ivar })
+            } else {
+                if !cfg.quiet {
+                    println!("Tracking: can't possibly find a match for this in corpus because one if the necessary zippers ZIDs doesnt exist in corpus")
+                }
+                *index = Some(corpus);
+                return Ok(None);
+            }
+        } else {
+            None
+        }
+    };
+
+    if !cfg.quiet {
+        println!("Tracking setup: {:?}ms", tstart.elapsed().as_millis())
+    }
+    tstart = std::time::Instant::now();
+
+    // only the nodes that are new since the last step need typing
+    let typings: Vec<Option<NodeTyping>> = match &prim_types {
+        Some(prim_types) => {
+            corpus.update_typings(prim_types);
+            std::mem::take(&mut corpus.typings)
+        }
+        None => vec![],
+    };
+
+    if !cfg.quiet {
+        println!("inferred typings: {:?}ms", tstart.elapsed().as_millis())
+    }
+    tstart = std::time::Instant::now();
+
+    let CorpusIndex {
+        set,
+        roots,
+        corpus_span,
+        cost_fn: index_cost_fn,
+        mut analyzed_cost,
+        mut analyzed_free_vars,
+        analyzed_ivars,
+        zid_of_zip,
+        zip_of_zid,
+        arg_of_zid_node,
+        zids_of_node,
+        extensions_of_zid,
+        indexed,
+        num_paths_to_node,
+        num_paths_to_node_by_root_idx,
+        tasks_of_node,
+        task_of_root_idx: _,
+        fresh_len,
+        typings: _,
+        typing_prim_types,
+    } = corpus;
 
     let init_cost_by_root_idx: Vec<i32> = roots.iter().map(|idx| analyzed_cost[*idx].0).collect();
     let init_cost_by_root_idx_weighted: Vec<f32> = init_cost_by_root_idx
@@ -3173,21 +4841,21 @@ pub fn compression_step(
     let init_cost: i32 = root_idxs_of_task
         .iter()
         .map(|root_idxs| {
-            root_idxs
-                .iter()
-                .map(|idx| init_cost_by_root_idx[*idx])
-                .min()
-                .unwrap()
+            task_cost(
+                root_idxs.iter().map(|idx| init_cost_by_root_idx[*idx]),
+                cfg.expected_cost,
+            )
         })
         .sum();
     let init_cost_weighted: i32 = root_idxs_of_task
         .iter()
         .map(|root_idxs| {
-            root_idxs
-                .iter()
-                .map(|idx| init_cost_by_root_idx_weighted[*idx].round() as i32)
-                .min()
-                .unwrap()
+            task_cost(
+                root_idxs
+                    .iter()
+                    .map(|idx| init_cost_by_root_idx_weighted[*idx].round() as i32),
+                cfg.expected_cost,
+            )
         })
         .sum();
     let first_train_cost = roots.iter().map(|idx| analyzed_cost[*idx].0).sum(); // This is used for --verbose-print
@@ -3212,88 +4880,40 @@ pub fn compression_step(
         .map(|node| analyzed_cost[node].0 * num_paths_to_node[node])
         .collect();
 
-    let mut analyzed_free_vars = AnalyzedExpr::new(FreeVarAnalysis);
-
     if !cfg.quiet {
         println!("cost_of_node structs: {:?}ms", tstart.elapsed().as_millis())
     }
     tstart = std::time::Instant::now();
 
-    let (zid_of_zip, zip_of_zid, arg_of_zid_node, zids_of_node, extensions_of_zid) = get_zippers(
-        &corpus_span,
-        &analyzed_cost,
-        &mut set,
-        &mut analyzed_free_vars,
-    );
+    let reversible_prims: Option<FxHashMap<Symbol, bool>> = if cfg.reversible_prims.prims.is_empty()
+    {
+        None
+    } else {
+        Some(
+            cfg.reversible_prims
+                .prims
+                .iter()
+                .map(|(name, is_reversible)| (Symbol::from(name.as_str()), *is_reversible))
+                .collect(),
+        )
+    };
 
-    // println!("zip_of_zid");
-    // for (i, zip) in zip_of_zid.iter().enumerate() {
-    //     println!("{} {:?}", i, zip);
-    // }
-    // println!("arg_of_zid_node");
-    // for (i, arg) in arg_of_zid_node.iter().enumerate() {
-    //     println!("{} {:?}", i, arg);
-    // }
-    // println!("zids_of_node");
-    // for (i, zids) in zids_of_node.iter().enumerate() {
-    //     println!("{} {:?}", i, zids);
-    // }
-    // println!("extensions_of_zid");
-    // for (i, ext) in extensions_of_zid.iter().enumerate() {
-    //     println!("{} {:?}", i, ext);
-    // }
     if !cfg.quiet {
-        println!("get_zippers(): {:?}ms", tstart.elapsed().as_millis())
+        println!("ran analyses: {:?}ms", tstart.elapsed().as_millis())
     }
     tstart = std::time::Instant::now();
 
-    if !cfg.quiet {
-        println!("{} zips", zip_of_zid.len())
-    }
-    if !cfg.quiet {
-        println!("arg_of_zid_node size: {}", arg_of_zid_node.len())
-    }
-
-    // set up tracking if any
-    let tracking: Option<Tracking> = {
-        if let Some(s) = &cfg.follow {
-            let mut set = ExprSet::empty(Order::ChildFirst, false, false);
-            let idx = set.parse_extend(s).unwrap();
-            let expr = ExprOwned::new(set, idx);
-            if let Some(zids_of_ivar) = zids_of_ivar_of_expr(&expr, &zid_of_zip) {
-                Some(Tracking { expr, zids_of_ivar })
-            } else {
-                if !cfg.quiet {
-                    println!("Tracking: can't possibly find a match for this in corpus because one if the necessary zippers ZIDs doesnt exist in corpus")
-                }
-                return vec![];
-            }
-        } else {
-            None
-        }
-    };
-
-    if !cfg.quiet {
-        println!("Tracking setup: {:?}ms", tstart.elapsed().as_millis())
-    }
-    tstart = std::time::Instant::now();
-
-    let mut analyzed_ivars = AnalyzedExpr::new(IVarAnalysis);
-    analyzed_free_vars.analyze(&set);
-    analyzed_cost.analyze(&set);
-    analyzed_ivars.analyze(&set);
-
-    if !cfg.quiet {
-        println!("ran analyses: {:?}ms", tstart.elapsed().as_millis())
-    }
-    tstart = std::time::Instant::now();
-
-    let mut stats: Stats = Default::default();
+    let mut stats: Stats = Default::default();
 
     // define all the important data structures for compression
     let mut donelist: Vec<FinishedPattern> = Default::default(); // completed inventions will go here
 
-    let single_node_locations: Vec<Idx> = arg_of_zid_node[0].keys().cloned().collect();
+    // nodes that aren't part of the corpus anymore are still in the index, but have no paths to them
+    let single_node_locations: Vec<Idx> = arg_of_zid_node[0]
+        .keys()
+        .filter(|node| num_paths_to_node[**node] > 0)
+        .cloned()
+        .collect();
 
     let single_hole = Pattern::single_hole(
         single_node_locations,
@@ -3302,7 +4922,7 @@ pub fn compression_step(
         &mut analyzed_free_vars,
         &mut analyzed_cost,
         &set,
-        cost_fn,
+        inv_cost,
         cfg,
     );
 
@@ -3338,6 +4958,15 @@ pub fn compression_step(
                 continue;
             }
 
+            // Pruning (REVERSIBLE): when only searching for reversible abstractions
+            if cfg.reversible_abstractions == ReversibleAbstractions::Only {
+                if let Some(reversible_prims) = &reversible_prims {
+                    if !invention_is_reversible(set.get(node), reversible_prims) {
+                        continue;
+                    }
+                }
+            }
+
             // Note that "single use" pruning is intentionally not done here,
             // since any invention specific to a node will by definition only
             // be useful at that node
@@ -3350,18 +4979,17 @@ pub fn compression_step(
                 - root_idxs_of_task
                     .iter()
                     .map(|root_idxs| {
-                        root_idxs
-                            .iter()
-                            .map(|idx| {
+                        task_cost(
+                            root_idxs.iter().map(|idx| {
                                 (init_cost_by_root_idx_weighted[*idx]
                                     - weights[*idx]
                                         * (num_paths_to_node_by_root_idx[*idx][node]
-                                            * (analyzed_cost[node].0 - cost_fn.cost_prim_default))
+                                            * (analyzed_cost[node].0 - inv_cost))
                                             as f32)
                                     .round() as i32
-                            })
-                            .min()
-                            .unwrap()
+                            }),
+                            cfg.expected_cost,
+                        )
                     })
                     .sum::<i32>();
 
@@ -3389,6 +5017,7 @@ pub fn compression_step(
                 match_locations,
                 utility_upper_bound: utility,
                 body_utility,
+                free_named_vars: vec![],
                 tracked: false,
             };
             let mut finished_pattern = FinishedPattern {
@@ -3413,6 +5042,7 @@ pub fn compression_step(
                 &extensions_of_zid,
                 &set,
                 &analyzed_ivars,
+                inv_cost,
                 cost_fn,
             );
             if !cfg.no_stats {
@@ -3457,9 +5087,9 @@ pub fn compression_step(
 
     let shared = Arc::new(SharedData {
         crit: Mutex::new(crit),
-        programs: programs.to_vec(),
         arg_of_zid_node,
         cost_fn: cost_fn.clone(),
+        inv_cost,
         analyzed_free_vars,
         analyzed_ivars,
         analyzed_cost,
@@ -3488,12 +5118,22 @@ pub fn compression_step(
         multistep_cfg: multistep_cfg.clone(),
         tracking,
         fused_lambda_tags: fused_copy,
+        prim_types,
+        typings,
+        reversible_prims,
+        deadline: cfg
+            .time_limit
+            .map(|secs| tstart_total + std::time::Duration::from_secs_f64(secs)),
+        observer: Arc::clone(observer),
+        cancellation: cancellation.clone(),
     });
 
+    // sort + truncate the arity zero inventions, which needs SharedData to compare their bodies
+    shared.crit.lock().deref_mut().update(&shared);
+
     if !shared.cfg.quiet {
         println!("built SharedData: {:?}ms", tstart.elapsed().as_millis())
     }
-    tstart = std::time::Instant::now();
 
     if cfg.verbose_best {
         let mut crit = shared.crit.lock();
@@ -3525,6 +5165,54 @@ pub fn compression_step(
         println!("TOTAL PREP: {:?}ms", tstart_prep.elapsed().as_millis())
     }
 
+    Ok(Some((
+        shared,
+        IndexRemainder {
+            cost_fn: index_cost_fn,
+            indexed,
+            fresh_len,
+            typing_prim_types,
+        },
+    )))
+}
+
+/// Like `compression_step()`, but reuses the `CorpusIndex` from an earlier step if `index` has one so that only
+/// the parts of the corpus that changed since then get indexed. Afterwards `index` holds the index of `programs`.
+/// Without `programs` the corpus is the one already in `index`, like the programs of `CorpusIndex::from_program_set()`.
+#[allow(clippy::too_many_arguments)]
+pub fn compression_step_indexed(
+    index: &mut Option<CorpusIndex>,
+    programs: Option<&[ExprOwned]>,
+    new_inv_name: &str, // name of the new invention, like "inv4"
+    multistep_cfg: &MultistepCompressionConfig,
+    tasks: &[String],
+    weights: &[f32],
+    very_first_cost: i32,
+    name_mapping: &[(String, String)],
+    observer: &Arc<dyn CompressionObserver>,
+    cancellation: &CancellationHandle,
+) -> Result<Vec<CompressionStepResult>, StitchError> {
+    multistep_cfg.validate()?;
+    let cfg = &multistep_cfg.step.clone();
+    let cost_fn = &cfg.cost.expr_cost();
+
+    let tstart_total = std::time::Instant::now();
+    let (shared, index_remainder) = match prepare_search(
+        index,
+        programs,
+        new_inv_name,
+        multistep_cfg,
+        tasks,
+        weights,
+        observer,
+        cancellation,
+        tstart_total,
+    )? {
+        Some(prepared) => prepared,
+        None => return Ok(vec![]),
+    };
+    let mut tstart = std::time::Instant::now();
+
     if !shared.cfg.quiet {
         println!("running pattern search...")
     }
@@ -3569,12 +5257,38 @@ pub fn compression_step(
     let mut shared: SharedData = Arc::try_unwrap(shared).unwrap();
 
     // one last .update()
-    shared.crit.lock().deref_mut().update(cfg);
+    shared.crit.lock().deref_mut().finish(&shared);
 
     if !shared.cfg.quiet {
         println!("{:?}", shared.stats.lock().deref_mut())
     }
-    assert!(shared.crit.lock().deref_mut().worklist.is_empty());
+    // if we ran out of budget or evicted patterns, whatever we didn't explore bounds how much better we could have done
+    let completeness = {
+        let crit = shared.crit.lock();
+        assert!(crit.out_of_budget || crit.worklist.is_empty());
+        SearchCompleteness {
+            remaining_upper_bound: crit
+                .worklist
+                .iter()
+                .map(|heap_item| heap_item.pattern.utility_upper_bound)
+                .chain(crit.max_evicted_bound)
+                .filter(|bound| *bound > crit.utility_pruning_cutoff)
+                .max(),
+            evicted_patterns: crit.evicted_patterns,
+            evicted_bound_mass: crit.evicted_bound_mass,
+        }
+    };
+    if !shared.cfg.quiet {
+        if completeness.evicted_patterns > 0 {
+            println!(
+                "evicted {} patterns from the worklist with a total upper bound of {}",
+                completeness.evicted_patterns, completeness.evicted_bound_mass
+            )
+        }
+        if let Some(bound) = completeness.remaining_upper_bound {
+            println!("not proven optimal (remaining upper bound: {bound})")
+        }
+    }
 
     let donelist: Vec<FinishedPattern> = shared.crit.lock().deref_mut().donelist.clone();
 
@@ -3629,6 +5343,7 @@ pub fn compression_step(
             very_first_cost,
             name_mapping,
             dc_comparison_millis,
+            completeness.clone(),
         );
         if !shared.cfg.quiet {
             println!("{i}: {res}")
@@ -3650,7 +5365,308 @@ pub fn compression_step(
         println!("post processing: {:?}ms", tstart.elapsed().as_millis())
     }
 
-    results
+    // hand the index back for the next step
+    *index = Some(CorpusIndex {
+        set: shared.set,
+        roots: shared.roots,
+        corpus_span: shared.corpus_span,
+        cost_fn: index_remainder.cost_fn,
+        analyzed_cost: shared.analyzed_cost,
+        analyzed_free_vars: shared.analyzed_free_vars,
+        analyzed_ivars: shared.analyzed_ivars,
+        zid_of_zip: shared.zid_of_zip,
+        zip_of_zid: shared.zip_of_zid,
+        arg_of_zid_node: shared.arg_of_zid_node,
+        zids_of_node: shared.zids_of_node,
+        extensions_of_zid: shared.extensions_of_zid,
+        indexed: index_remainder.indexed,
+        num_paths_to_node: shared.num_paths_to_node,
+        num_paths_to_node_by_root_idx: shared.num_paths_to_node_by_root_idx,
+        tasks_of_node: shared.tasks_of_node,
+        task_of_root_idx: shared.task_of_root_idx,
+        fresh_len: index_remainder.fresh_len,
+        typings: shared.typings,
+        typing_prim_types: index_remainder.typing_prim_types,
+    });
+
+    Ok(results)
+}
+
+/// How well an abstraction compresses a corpus, see `score_abstraction()`
+#[derive(Debug, Clone)]
+pub struct AbstractionScore {
+    pub body: String, // with the ivars numbered the way compression would number them
+    pub arity: usize,
+    pub utility: i32,
+    pub compressive_utility: i32,
+    pub usages: i32, // total number of paths to the match locations
+    pub match_locations: Vec<AbstractionMatch>,
+    pub uses_by_task: Vec<(String, i32)>, // uses at the match locations that get rewritten, for each task
+}
+
+/// A place where an abstraction matches the corpus
+#[derive(Debug, Clone)]
+pub struct AbstractionMatch {
+    pub expr: String,      // the subtree that the abstraction matches
+    pub args: Vec<String>, // what each ivar gets filled in with here
+    pub uses: i32,         // number of paths to this subtree in the corpus
+    pub rewritten: bool, // whether bottom_up_utility_correction() chose to rewrite here over conflicting locations
+}
+
+impl AbstractionScore {
+    pub fn json(&self) -> serde_json::Value {
+        json!({
+            "body": self.body,
+            "arity": self.arity,
+            "utility": self.utility,
+            "compressive_utility": self.compressive_utility,
+            "num_uses": self.usages,
+            "uses_by_task": self.uses_by_task.iter().map(|(task, uses)| json!({task: uses})).collect::<Vec<serde_json::Value>>(),
+            "match_locations": self.match_locations.iter().map(|loc| json!({
+                "expr": loc.expr,
+                "args": loc.args,
+                "uses": loc.uses,
+                "rewritten": loc.rewritten,
+            })).collect::<Vec<serde_json::Value>>(),
+        })
+    }
+}
+
+/// Scores `body` (an abstraction body like "(+ #0 (* #1 #0))") against the programs without searching, to see how it
+/// compares to the abstractions that compression finds. The utility is calculated by the same code the search uses
+/// (`compressive_utility()` and `bottom_up_utility_correction()`), with the same config, but nothing is pruned
+/// along the way and `inverse_argument_capture()` isn't run so the body is scored exactly as given.
+pub fn score_abstraction(
+    programs: &[String],
+    tasks: Option<Vec<String>>,
+    weights: Option<Vec<f32>>,
+    body: &str,
+    cfg: &MultistepCompressionConfig,
+) -> Result<AbstractionScore, StitchError> {
+    let mut cfg = cfg.clone();
+    if cfg.silent {
+        cfg.step.quiet = true
+    }
+    cfg.validate()?;
+
+    let (programs, body) = if cfg.named_lambdas {
+        (
+            programs_from_named(programs)?,
+            named_to_de_bruijn(body).map_err(|e| {
+                StitchError::InvalidConfig(format!("couldn't convert abstraction body {body}: {e}"))
+            })?,
+        )
+    } else {
+        (programs.to_vec(), body.to_string())
+    };
+    let body = body.as_str();
+
+    let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+    let idx = set.parse_extend(body).map_err(|e| {
+        StitchError::InvalidConfig(format!("couldn't parse abstraction body {body}: {e}"))
+    })?;
+    let body_expr = ExprOwned::new(set, idx);
+    let arity = body_expr
+        .set
+        .iter()
+        .filter_map(|node| match &body_expr.set[node] {
+            Node::IVar(i) => Some(*i as usize + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    // follow the body so that tracked_expands_to() tells us how to expand each hole
+    cfg.step.follow = Some(body.to_string());
+    cfg.step.max_arity = std::cmp::max(cfg.step.max_arity, arity);
+    cfg.step.no_opt_arity_zero = true; // no need to look for arity zero inventions
+
+    let programs: Vec<ExprOwned> = parse_programs(&programs)?;
+    let tasks: Vec<String> =
+        tasks.unwrap_or_else(|| (0..programs.len()).map(|i| i.to_string()).collect());
+    let weights: Vec<f32> = weights.unwrap_or_else(|| vec![1.0; programs.len()]);
+    let name = format!("{}{}", cfg.abstraction_prefix, cfg.previous_abstractions);
+    let observer: Arc<dyn CompressionObserver> = Arc::new(NoObserver);
+
+    let no_matches = |cost_fn: &ExprCost| {
+        let body_utility = body_utility_of_expr(body_expr.immut(), cost_fn);
+        AbstractionScore {
+            body: body.to_string(),
+            arity,
+            utility: noncompressive_utility(body_utility, &cfg.step),
+            compressive_utility: 0,
+            usages: 0,
+            match_locations: vec![],
+            uses_by_task: vec![],
+        }
+    };
+
+    let shared = match prepare_search(
+        &mut None,
+        Some(&programs[..]),
+        &name,
+        &cfg,
+        &tasks,
+        &weights,
+        &observer,
+        &CancellationHandle::new(),
+        std::time::Instant::now(),
+    )? {
+        Some((shared, _)) => shared,
+        None => return Ok(no_matches(&cfg.step.cost.expr_cost())),
+    };
+    let finished = match tracked_pattern(&shared) {
+        Some(finished) => finished,
+        None => return Ok(no_matches(&shared.cost_fn)),
+    };
+
+    let match_locations: Vec<AbstractionMatch> = finished
+        .pattern
+        .match_locations
+        .iter()
+        .map(|(loc, _)| AbstractionMatch {
+            expr: shared.set.get(*loc).to_string(),
+            args: finished
+                .pattern
+                .first_zid_of_ivar
+                .iter()
+                .map(|zid| {
+                    shared
+                        .set
+                        .get(shared.arg_of_zid_node[*zid][loc].fill_id)
+                        .to_string()
+                })
+                .collect(),
+            uses: shared.num_paths_to_node[*loc],
+            rewritten: finished
+                .util_calc
+                .corrected_utils
+                .get(loc)
+                .cloned()
+                .unwrap_or(true),
+        })
+        .collect();
+    let uses_by_task: Vec<(String, i32)> = shared
+        .root_idxs_of_task
+        .iter()
+        .zip(shared.task_name_of_task.iter())
+        .map(|(root_idxs, task)| {
+            let uses = root_idxs
+                .iter()
+                .map(|root_idx| {
+                    finished
+                        .pattern
+                        .match_locations
+                        .iter()
+                        .zip(match_locations.iter())
+                        .filter(|(_, loc)| loc.rewritten)
+                        .map(|((node, _), _)| {
+                            shared.num_paths_to_node_by_root_idx[*root_idx][*node]
+                        })
+                        .sum::<i32>()
+                })
+                .sum::<i32>();
+            (task.clone(), uses)
+        })
+        .collect();
+
+    Ok(AbstractionScore {
+        body: finished.to_expr(&shared).to_string(),
+        arity: finished.arity,
+        utility: finished.utility,
+        compressive_utility: finished.compressive_utility,
+        usages: finished.usages,
+        match_locations,
+        uses_by_task,
+    })
+}
+
+/// Builds the finished pattern for the abstraction being tracked (see `Tracking`) by expanding each hole of the
+/// single hole pattern on the worklist the way the tracked body does, without any of the pruning that the search
+/// does. Returns None if the body doesn't match anywhere in the corpus.
+fn tracked_pattern(shared: &Arc<SharedData>) -> Option<FinishedPattern> {
+    let mut pattern: Pattern = shared.crit.lock().worklist.peek()?.pattern.clone();
+    while !pattern.holes.is_empty() {
+        let hole_zid = pattern.holes.remove(0);
+        let expands_to = tracked_expands_to(&pattern, hole_zid, shared);
+        let arg_of_loc = &shared.arg_of_zid_node[hole_zid];
+        let locs: Vec<(Idx, i32)> = if let ExpandsTo::IVar(_) = expands_to {
+            get_ivars_expansions(&pattern, arg_of_loc, hole_zid, shared)
+                .into_iter()
+                .find(|(ivar, _)| *ivar == expands_to)
+                .map(|(_, locs)| locs)
+                .unwrap_or_default()
+        } else {
+            pattern
+                .match_locations
+                .iter()
+                .filter(|(loc, _)| arg_of_loc[loc].expands_to == expands_to)
+                .map(|(loc, r)| {
+                    if arg_of_loc[loc].is_var_replacement {
+                        (*loc, r + 1)
+                    } else {
+                        (*loc, *r)
+                    }
+                })
+                .collect()
+        };
+        if locs.is_empty() {
+            return None;
+        }
+
+        match &expands_to {
+            ExpandsTo::Lam(_) => {
+                pattern
+                    .holes
+                    .push(shared.extensions_of_zid[hole_zid].body.unwrap());
+            }
+            ExpandsTo::App | ExpandsTo::Let | ExpandsTo::RevLet(_, _) => {
+                pattern
+                    .holes
+                    .push(shared.extensions_of_zid[hole_zid].func.unwrap());
+                pattern
+                    .holes
+                    .push(shared.extensions_of_zid[hole_zid].arg.unwrap());
+                if let ExpandsTo::RevLet(NamedVar::Free(inp_var), _) = &expands_to {
+                    pattern.free_named_vars.push(inp_var.clone());
+                }
+            }
+            ExpandsTo::IVar(i) => {
+                pattern
+                    .arg_choices
+                    .push(LabelledZId::new(hole_zid, *i as usize));
+                if *i as usize == pattern.first_zid_of_ivar.len() {
+                    pattern.first_zid_of_ivar.push(hole_zid);
+                }
+            }
+            _ => {}
+        }
+        pattern.body_utility += expansion_cost(&expands_to, &shared.cost_fn);
+        pattern.match_locations = locs;
+        pattern.utility_upper_bound = utility_upper_bound(
+            &pattern.match_locations,
+            pattern.body_utility,
+            &shared.cost_of_node_all,
+            &shared.num_paths_to_node,
+            shared.inv_cost,
+            &shared.cfg,
+        );
+    }
+    Some(FinishedPattern::new(pattern, shared))
+}
+
+/// the body utility (see `Pattern::body_utility`) of a complete abstraction body
+fn body_utility_of_expr(e: Expr, cost_fn: &ExprCost) -> i32 {
+    match e.node() {
+        // don't follow the link to what the variable is bound to
+        Node::NLinkVar(_, _) => cost_fn.cost_nvar,
+        node => {
+            expansion_cost(&expands_to_of_node(node), cost_fn)
+                + e.children()
+                    .map(|child| body_utility_of_expr(e.get(child), cost_fn))
+                    .sum::<i32>()
+        }
+    }
 }
 
 /// toplevel entrypoint to compression used by most apis
@@ -3660,13 +5676,110 @@ pub fn multistep_compression(
     weights: Option<Vec<f32>>,
     name_mapping: Option<Vec<(String, String)>>,
     follow: Option<Vec<Invention>>,
+    test_programs: Option<Vec<String>>,
+    cfg: &MultistepCompressionConfig,
+) -> Result<(Vec<CompressionStepResult>, serde_json::Value), StitchError> {
+    multistep_compression_with_observer(
+        programs,
+        tasks,
+        weights,
+        name_mapping,
+        follow,
+        test_programs,
+        cfg,
+        Arc::new(NoObserver),
+        &CancellationHandle::new(),
+    )
+}
+
+/// Like `multistep_compression()` but reports progress and each abstraction found to `observer`, and stops early
+/// (returning the abstractions found so far) once `cancellation` is cancelled.
+#[allow(clippy::too_many_arguments)]
+pub fn multistep_compression_with_observer(
+    programs: &[String],
+    tasks: Option<Vec<String>>,
+    weights: Option<Vec<f32>>,
+    name_mapping: Option<Vec<(String, String)>>,
+    follow: Option<Vec<Invention>>,
+    test_programs: Option<Vec<String>>,
+    cfg: &MultistepCompressionConfig,
+    observer: Arc<dyn CompressionObserver>,
+    cancellation: &CancellationHandle,
+) -> Result<(Vec<CompressionStepResult>, serde_json::Value), StitchError> {
+    let programs = if cfg.named_lambdas {
+        programs_from_named(programs)?
+    } else {
+        programs.to_vec()
+    };
+
+    // parse the program strings into expressions
+    let train_programs = ProgramSet::parse(&programs)?;
+
+    // for (p, pr) in zip(programs.iter(), train_programs.iter()) {
+    //     println!("{}", p);
+    //     for (i, node) in pr.set.nodes.iter().enumerate() {
+    //         println!("{} {:?}", i, node);
+    //     }
+    // }
+
+    multistep_compression_parsed(
+        train_programs,
+        tasks,
+        weights,
+        name_mapping,
+        follow,
+        test_programs,
+        cfg,
+        observer,
+        cancellation,
+    )
+}
+
+/// Like `multistep_compression_with_observer()` but takes programs that are already parsed, such as the
+/// `ProgramSet` read by `InputFormat::Jsonl`, so they never need to be printed to strings and parsed again. With
+/// `named_lambdas` the programs must already have been converted to de Bruijn form, only the held out
+/// `test_programs` and the outputs are converted.
+#[allow(clippy::too_many_arguments)]
+pub fn multistep_compression_parsed(
+    mut train_programs: ProgramSet,
+    tasks: Option<Vec<String>>,
+    weights: Option<Vec<f32>>,
+    name_mapping: Option<Vec<(String, String)>>,
+    follow: Option<Vec<Invention>>,
+    test_programs: Option<Vec<String>>,
     cfg: &MultistepCompressionConfig,
-) -> (Vec<CompressionStepResult>, serde_json::Value) {
-    let mut programs = programs.to_vec();
+    observer: Arc<dyn CompressionObserver>,
+    cancellation: &CancellationHandle,
+) -> Result<(Vec<CompressionStepResult>, serde_json::Value), StitchError> {
     let mut cfg = cfg.clone();
 
+    cfg.validate()?;
     if let Some(tasks) = &tasks {
-        assert_eq!(tasks.len(), programs.len());
+        if tasks.len() != train_programs.len() {
+            return Err(StitchError::InvalidConfig(format!(
+                "got {} tasks for {} programs",
+                tasks.len(),
+                train_programs.len()
+            )));
+        }
+    }
+    if let Some(weights) = &weights {
+        if weights.len() != train_programs.len() {
+            return Err(StitchError::InvalidConfig(format!(
+                "got {} weights for {} programs",
+                weights.len(),
+                train_programs.len()
+            )));
+        }
+    }
+    if let Some(follow) = &follow {
+        if follow.len() != cfg.iterations {
+            return Err(StitchError::InvalidConfig(format!(
+                "got {} abstractions to follow for {} iterations",
+                follow.len(),
+                cfg.iterations
+            )));
+        }
     }
 
     if cfg.silent {
@@ -3678,29 +5791,12 @@ pub fn multistep_compression(
     }
 
     if cfg.shuffle {
-        programs.shuffle(&mut rand::thread_rng());
+        train_programs.roots.shuffle(&mut rand::thread_rng());
     }
     if let Some(n) = cfg.truncate {
-        programs.truncate(n);
+        train_programs.roots.truncate(n);
     }
 
-    // parse the program strings into expressions
-    let train_programs: Vec<ExprOwned> = programs
-        .iter()
-        .map(|p| {
-            let mut set = ExprSet::empty(Order::ChildFirst, false, false);
-            let idx = set.parse_extend(p).unwrap();
-            ExprOwned::new(set, idx)
-        })
-        .collect();
-
-    // for (p, pr) in zip(programs.iter(), train_programs.iter()) {
-    //     println!("{}", p);
-    //     for (i, node) in pr.set.nodes.iter().enumerate() {
-    //         println!("{} {:?}", i, node);
-    //     }
-    // }
-
     let cost_fn = cfg.step.cost.expr_cost();
 
     if !cfg.silent {
@@ -3712,53 +5808,315 @@ pub fn multistep_compression(
 
     let mut name_mapping = name_mapping.unwrap_or_default();
 
-    let step_results = multistep_compression_internal(
+    let mut step_results = multistep_compression_internal(
         &train_programs,
         tasks.clone(),
         weights.clone(),
         &mut name_mapping,
         follow,
         &cfg,
-    );
+        &observer,
+        cancellation,
+    )?;
+
+    // the test programs are rewritten one step at a time, which only works with the library as it was found since
+    // refactoring can make earlier inventions use later ones
+    let found_invs: Vec<Invention> = step_results.iter().map(|res| res.inv.clone()).collect();
+
+    let refactored = if cfg.refactor_library {
+        refactor_step_results(&mut step_results, &cfg)?
+    } else {
+        vec![]
+    };
 
     // write everything to json
-    let json_res = json_of_step_results(
+    let mut json_res = json_of_step_results(
         &step_results,
         &train_programs,
-        weights,
-        tasks,
+        weights.clone(),
+        tasks.clone(),
         &cost_fn,
         name_mapping,
         &cfg,
     );
 
-    (step_results, json_res)
+    if let Some(mut test_programs) = test_programs {
+        if cfg.named_lambdas {
+            test_programs = programs_from_named(&test_programs)?;
+        }
+        let test_programs: Vec<ExprOwned> = parse_programs(&test_programs)?;
+        add_test_results_to_json(&mut json_res, &found_invs, &test_programs, &cost_fn, &cfg);
+    }
+
+    if cfg.refactor_library {
+        json_res["refactored_abstractions"] = json!(refactored);
+    }
+
+    if cfg.prune_library {
+        add_pruned_library_to_json(
+            &mut json_res,
+            &step_results,
+            &train_programs,
+            &weights,
+            &tasks,
+            &cost_fn,
+            &cfg,
+        )?;
+    }
+
+    if cfg.named_lambdas {
+        add_named_to_json(&mut json_res);
+    }
+
+    Ok((step_results, json_res))
+}
+
+/// Runs `refactor_library()` on the library found by compression and updates the bodies and dreamcoder strings
+/// of the step results to match. Returns the names of the inventions whose bodies changed, or an error if the
+/// library can't be refactored (see `refactor_library()`).
+pub fn refactor_step_results(
+    step_results: &mut [CompressionStepResult],
+    cfg: &MultistepCompressionConfig,
+) -> Result<Vec<String>, StitchError> {
+    let invs: Vec<Invention> = step_results.iter().map(|res| res.inv.clone()).collect();
+    let refactored_invs = refactor_library(&invs, &cfg.step)?;
+    let refactored: Vec<String> = invs
+        .iter()
+        .zip(refactored_invs.iter())
+        .filter(|(old, new)| old.body.to_string() != new.body.to_string())
+        .map(|(_, new)| new.name.clone())
+        .collect();
+    if refactored.is_empty() {
+        return Ok(refactored);
+    }
+
+    // the dreamcoder strings of any inventions from before this run of compression
+    let base_mapping: Vec<(String, String)> = {
+        let name_mapping = &step_results.last().unwrap().name_mapping;
+        name_mapping[..name_mapping.len() - step_results.len()].to_vec()
+    };
+    // an invention's dreamcoder string has the dreamcoder strings of the inventions it uses inlined, so those
+    // need to be built first
+    let mut dc_inv_strs: Vec<Option<String>> = vec![None; refactored_invs.len()];
+    for i in library_order(&refactored_invs)? {
+        let mut name_mapping = base_mapping.clone();
+        name_mapping.extend(
+            refactored_invs
+                .iter()
+                .zip(dc_inv_strs.iter())
+                .filter_map(|(inv, dc)| dc.as_ref().map(|dc| (inv.name.clone(), dc.clone()))),
+        );
+        dc_inv_strs[i] = Some(dc_inv_str(&refactored_invs[i], &name_mapping));
+    }
+
+    let mut name_mapping = base_mapping;
+    for ((res, inv), dc) in step_results
+        .iter_mut()
+        .zip(refactored_invs)
+        .zip(dc_inv_strs)
+    {
+        res.inv = inv;
+        res.dc_inv_str = dc.unwrap();
+        name_mapping.push((res.inv.name.clone(), res.dc_inv_str.clone()));
+        res.name_mapping = name_mapping.clone();
+        if res.rewritten_dreamcoder.is_some() {
+            res.rewritten_dreamcoder =
+                Some(rewritten_to_dreamcoder(&res.rewritten, &res.name_mapping));
+        }
+    }
+
+    if !cfg.silent {
+        println!("{}", "Refactored library:".yellow().bold());
+        for res in step_results.iter() {
+            if refactored.contains(&res.inv.name) {
+                println!("{}", res.inv);
+            }
+        }
+    }
+    Ok(refactored)
+}
+
+/// Runs `prune_library()` on the library found by compression and adds the removed inventions, the remaining
+/// library, and the corpus with the removed inventions inlined to the json output under "pruned_library".
+/// Returns an error if the library can't be inlined (see `prune_library()`).
+pub fn add_pruned_library_to_json(
+    json_res: &mut serde_json::Value,
+    step_results: &[CompressionStepResult],
+    train_programs: &ProgramSet,
+    weights: &Option<Vec<f32>>,
+    tasks: &Option<Vec<String>>,
+    cost_fn: &ExprCost,
+    cfg: &MultistepCompressionConfig,
+) -> Result<(), StitchError> {
+    let invs: Vec<Invention> = step_results.iter().map(|res| res.inv.clone()).collect();
+    // with expected costs each task costs the sum over its programs, which is what no tasks at all gives too
+    let tasks = if cfg.step.expected_cost { &None } else { tasks };
+    let (pruned, rewritten) = match step_results.last() {
+        Some(res) => {
+            let pruned = prune_library(&invs, &res.rewritten, weights, tasks, cost_fn)?;
+            let rewritten: Vec<String> = pruned.rewritten.iter().map(|p| p.to_string()).collect();
+            (pruned, rewritten)
+        }
+        // an empty library has nothing to prune
+        None => {
+            let cost = min_cost_of_costs(&train_programs.costs(cost_fn), weights, tasks);
+            let pruned = PrunedLibrary {
+                invs: vec![],
+                removed: vec![],
+                rewritten: vec![],
+                original_cost: cost,
+                final_cost: cost,
+            };
+            (pruned, train_programs.strings())
+        }
+    };
+    if !cfg.silent {
+        println!("{}", "Pruned library:".yellow().bold());
+        if pruned.removed.is_empty() {
+            println!("no inventions removed");
+        } else {
+            println!("removed {}", pruned.removed.join(", "));
+        }
+        println!(
+            "library cost (corpus + bodies): {} -> {}",
+            pruned.original_cost, pruned.final_cost
+        );
+    }
+    json_res["pruned_library"] = json!({
+        "removed": pruned.removed,
+        "original_cost": pruned.original_cost,
+        "final_cost": pruned.final_cost,
+        "abstractions": pruned.invs.iter().map(|inv| json!({
+            "name": inv.name,
+            "arity": inv.arity,
+            "body": inv.body.to_string(),
+        })).collect::<Vec<serde_json::Value>>(),
+        "rewritten": rewritten,
+    });
+    Ok(())
+}
+
+/// Adds named lambda versions of the rewritten programs and abstraction bodies to the json output (including the
+/// refactored and pruned library, if any), for programs that were given with `named_lambdas`
+pub fn add_named_to_json(json_res: &mut serde_json::Value) {
+    let to_named = |programs: &serde_json::Value| -> Vec<String> {
+        programs
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| de_bruijn_to_named(p.as_str().unwrap()).unwrap())
+            .collect()
+    };
+    let add_body_named = |abstraction: &mut serde_json::Value| {
+        let body_named = invention_to_named(
+            abstraction["body"].as_str().unwrap(),
+            abstraction["arity"].as_u64().unwrap() as usize,
+        )
+        .unwrap();
+        abstraction["body_named"] = json!(body_named);
+    };
+    json_res["rewritten_named"] = json!(to_named(&json_res["rewritten"]));
+    if json_res["test_rewritten"].is_array() {
+        json_res["test_rewritten_named"] = json!(to_named(&json_res["test_rewritten"]));
+    }
+    for abstraction in json_res["abstractions"].as_array_mut().unwrap() {
+        add_body_named(abstraction);
+    }
+    // the refactored bodies are the ones in "abstractions" by now
+    if let Some(refactored) = json_res["refactored_abstractions"].as_array() {
+        let refactored_named: Vec<serde_json::Value> = refactored
+            .iter()
+            .map(|name| {
+                json_res["abstractions"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|abstraction| abstraction["name"] == *name)
+                    .unwrap()["body_named"]
+                    .clone()
+            })
+            .collect();
+        json_res["refactored_abstractions_named"] = json!(refactored_named);
+    }
+    if json_res["pruned_library"].is_object() {
+        let pruned_library = &mut json_res["pruned_library"];
+        pruned_library["rewritten_named"] = json!(to_named(&pruned_library["rewritten"]));
+        for abstraction in pruned_library["abstractions"].as_array_mut().unwrap() {
+            add_body_named(abstraction);
+        }
+    }
+}
+
+/// Rewrites the held out `test_programs` with the library so far after each step of compression, and adds
+/// their costs and compression ratios to the json output next to the train numbers (prefixed with "test_").
+pub fn add_test_results_to_json(
+    json_res: &mut serde_json::Value,
+    invs: &[Invention],
+    test_programs: &[ExprOwned],
+    cost_fn: &ExprCost,
+    cfg: &MultistepCompressionConfig,
+) {
+    let original_cost = min_cost(test_programs, &None, &None, cost_fn);
+    let mut rewritten: Vec<ExprOwned> = test_programs.to_vec();
+    let mut prev_cost = original_cost;
+    for (i, inv) in invs.iter().enumerate() {
+        let (new_rewritten, uses) =
+            rewrite_with_inventions_direct(&rewritten, std::slice::from_ref(inv), &cfg.step);
+        rewritten = new_rewritten;
+        let final_cost = min_cost(&rewritten, &None, &None, cost_fn);
+        let abstraction = &mut json_res["abstractions"][i];
+        abstraction["test_final_cost"] = json!(final_cost);
+        abstraction["test_compression_ratio"] = json!(compression_factor(prev_cost, final_cost));
+        abstraction["test_cumulative_compression_ratio"] =
+            json!(compression_factor(original_cost, final_cost));
+        abstraction["test_num_uses"] = json!(uses[0]);
+        prev_cost = final_cost;
+    }
+    json_res["test_original_cost"] = json!(original_cost);
+    json_res["test_final_cost"] = json!(prev_cost);
+    json_res["test_compression_ratio"] = json!(compression_factor(original_cost, prev_cost));
+    json_res["test_original"] = json!(test_programs
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<String>>());
+    json_res["test_rewritten"] = json!(rewritten
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<String>>());
 }
 
 pub fn json_of_step_results(
     step_results: &[CompressionStepResult],
-    train_programs: &Vec<ExprOwned>,
+    train_programs: &ProgramSet,
     weights: Option<Vec<f32>>,
     tasks: Option<Vec<String>>,
     cost_fn: &ExprCost,
     name_mapping: Vec<(String, String)>,
     cfg: &MultistepCompressionConfig,
 ) -> serde_json::Value {
-    let rewritten: &Vec<ExprOwned> = step_results
-        .iter()
-        .last()
-        .map(|res| &res.rewritten)
-        .unwrap_or(train_programs);
-    let original_cost = min_cost(train_programs, &weights, &tasks, cost_fn);
-    let final_cost = min_cost(rewritten, &weights, &tasks, cost_fn);
-    let rewritten = step_results
-        .iter()
-        .last()
-        .map(|res| &res.rewritten)
-        .unwrap_or(train_programs)
-        .iter()
-        .map(|p| p.to_string())
-        .collect::<Vec<String>>();
+    let original = train_programs.strings();
+    let original_cost = corpus_cost_of_costs(
+        &train_programs.costs(cost_fn),
+        &weights,
+        &tasks,
+        cfg.step.expected_cost,
+    );
+    let (final_cost, rewritten) = match step_results.last() {
+        Some(res) => (
+            corpus_cost(
+                &res.rewritten,
+                &weights,
+                &tasks,
+                cost_fn,
+                cfg.step.expected_cost,
+            ),
+            res.rewritten
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<String>>(),
+        ),
+        None => (original_cost, original.clone()),
+    };
     let rewritten_dreamcoder = if !cfg.step.rewritten_dreamcoder {
         None
     } else {
@@ -3767,10 +6125,10 @@ pub fn json_of_step_results(
             .last()
             .map(|res| res.rewritten_dreamcoder.clone().unwrap())
             .unwrap_or_else(|| {
-                train_programs
+                original
                     .iter()
                     .map(|p| {
-                        let mut res = p.to_string();
+                        let mut res = p.clone();
                         for (name, anonymous) in &name_mapping {
                             res = replace_prim_with(&res, name, anonymous);
                         }
@@ -3788,9 +6146,128 @@ pub fn json_of_step_results(
         "final_cost": final_cost,
         "compression_ratio": compression_factor(original_cost,final_cost),
         "num_abstractions": step_results.len(),
-        "original": train_programs.iter().map(|p| p.to_string()).collect::<Vec<String>>(),
-        "rewritten": rewritten.iter().map(|p| p.to_string()).collect::<Vec<String>>(),
+        "original": original,
+        "rewritten": rewritten,
         "rewritten_dreamcoder": rewritten_dreamcoder,
         "abstractions": step_results.iter().map(|inv| inv.json(&cfg.step)).collect::<Vec<serde_json::Value>>(),
     })
 }
+
+/// Builds an updated DreamCoder DSL (the "DSL" field of the DreamCoder input format) with a new production for each
+/// invention, ready to feed straight back into DreamCoder. Each new production gets a type inferred from the types
+/// of the existing productions, whether it is reversible (if the existing productions say which are reversible),
+/// and `initial_log_probability`. Returns an error if an invention's type can't be inferred, since DreamCoder can't
+/// use a production without one and the inventions after it may be built on top of it.
+pub fn dreamcoder_dsl_of_step_results(
+    dsl: &serde_json::Value,
+    step_results: &[CompressionStepResult],
+    initial_log_probability: f64,
+) -> Result<serde_json::Value, StitchError> {
+    let mut productions: Vec<serde_json::Value> =
+        dsl["productions"].as_array().cloned().unwrap_or_default();
+    let name_mapping: Vec<(String, String)> = step_results
+        .last()
+        .map(|res| res.name_mapping.clone())
+        .unwrap_or_default();
+    // productions are keyed by their dreamcoder expression but the bodies of our inventions use stitch names
+    let name_of_expression = |expression: &str| -> String {
+        name_mapping
+            .iter()
+            .find(|(_, dc_str)| dc_str == expression)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| expression.to_string())
+    };
+
+    let mut prim_types: FxHashMap<Symbol, DcType> = FxHashMap::default();
+    let mut reversible: FxHashMap<Symbol, bool> = FxHashMap::default();
+    for prod in productions.iter() {
+        let expression = prod["expression"].as_str().ok_or_else(|| {
+            StitchError::InvalidDsl(format!("production without an expression: {prod}"))
+        })?;
+        let name = Symbol::from(name_of_expression(expression));
+        if let Some(ty) = prod["type"].as_str().and_then(|ty| DcType::parse(ty).ok()) {
+            prim_types.insert(name.clone(), ty);
+        }
+        if let Some(is_reversible) = prod["is_reversible"].as_bool() {
+            reversible.insert(name, is_reversible);
+        }
+    }
+
+    for res in step_results.iter() {
+        let name = Symbol::from(res.inv.name.as_str());
+        let inv_type: DcType = match &res.inv_type {
+            Some(ty) => ty.clone(),
+            None if prim_types.is_empty() => {
+                return Err(StitchError::InvalidDsl(format!(
+                    "couldn't infer a type for {}: the DSL productions have no types",
+                    res.inv.name
+                )))
+            }
+            None => infer_invention_type(res.inv.body.immut(), res.inv.arity, &prim_types)
+                .map_err(|e| {
+                    StitchError::InvalidDsl(format!(
+                        "couldn't infer a type for {}: {e}",
+                        res.inv.name
+                    ))
+                })?,
+        };
+        let is_reversible: Option<bool> = if reversible.is_empty() {
+            None
+        } else {
+            Some(invention_is_reversible(res.inv.body.immut(), &reversible))
+        };
+        let mut prod = json!({
+            "expression": res.dc_inv_str,
+            "type": inv_type.to_string(),
+            "logProbability": initial_log_probability,
+        });
+        if let Some(is_reversible) = is_reversible {
+            prod["is_reversible"] = json!(is_reversible);
+            reversible.insert(name.clone(), is_reversible);
+        }
+        prim_types.insert(name, inv_type);
+        productions.push(prod);
+    }
+
+    let mut dsl = dsl.clone();
+    dsl["productions"] = json!(productions);
+    Ok(dsl)
+}
+
+/// Whether an invention body is reversible given whether each primitive is reversible, meaning it is built
+/// only out of reversible primitives with its ivars in invertible positions, so that running the body backwards
+/// recovers every argument and the invention can be used in the definitions of a RevLet. An ivar is in an
+/// invertible position if it is an argument of a reversible primitive (possibly nested) and not inside a lambda,
+/// since lambdas are fixed functions handed to higher order primitives rather than values we can recover.
+/// Primitives we don't know about are assumed not to be reversible.
+pub fn invention_is_reversible(body: Expr, reversible: &FxHashMap<Symbol, bool>) -> bool {
+    fn helper(e: Expr, under_lam: bool, reversible: &FxHashMap<Symbol, bool>) -> bool {
+        match e.node() {
+            Node::Prim(p) => *reversible.get(p).unwrap_or(&false),
+            Node::IVar(_) => !under_lam,
+            // variables bound by a lambda in the body
+            Node::Var(_, _) => true,
+            Node::Lam(b, _) => helper(e.get(*b), true, reversible),
+            Node::App(_, _) => {
+                let mut f = e.idx;
+                let mut args = vec![];
+                while let Node::App(g, x) = &e.set[f] {
+                    args.push(*x);
+                    f = *g;
+                }
+                let head_ok = match &e.set[f] {
+                    Node::Prim(p) => *reversible.get(p).unwrap_or(&false),
+                    // calling a function bound by a lambda, which is up to the primitive the lambda is passed to
+                    Node::Var(_, _) => under_lam,
+                    _ => false,
+                };
+                head_ok
+                    && args
+                        .iter()
+                        .all(|x| helper(e.get(*x), under_lam, reversible))
+            }
+            _ => false,
+        }
+    }
+    helper(body, false, reversible)
+}
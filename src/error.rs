@@ -0,0 +1,50 @@
+use std::fmt;
+
+/// Everything that can go wrong with the inputs to stitch: input files that can't be read or don't have the
+/// expected shape, programs that don't parse, and configs that don't make sense.
+#[derive(Debug)]
+pub enum StitchError {
+    /// reading an input file failed
+    Io { path: String, error: std::io::Error },
+    /// the input isn't valid json, or is missing a field or has one of the wrong type
+    Json(String),
+    /// a program didn't parse. `program` is its index in the list of programs it came from
+    Parse { program: usize, message: String },
+    /// a combination of arguments that can't be used
+    InvalidConfig(String),
+    /// a library of abstractions that can't be used, like one whose abstractions use each other in a cycle
+    InvalidLibrary(String),
+    /// a DreamCoder DSL that a library can't be added to, like one whose productions have no types to infer the
+    /// types of the new productions from
+    InvalidDsl(String),
+}
+
+impl fmt::Display for StitchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StitchError::Io { path, error } => write!(f, "couldn't read {path}: {error}"),
+            StitchError::Json(message) => write!(f, "bad json input: {message}"),
+            StitchError::Parse { program, message } => {
+                write!(f, "couldn't parse program {program}: {message}")
+            }
+            StitchError::InvalidConfig(message) => write!(f, "invalid config: {message}"),
+            StitchError::InvalidLibrary(message) => write!(f, "invalid library: {message}"),
+            StitchError::InvalidDsl(message) => write!(f, "invalid DreamCoder DSL: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for StitchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StitchError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for StitchError {
+    fn from(error: serde_json::Error) -> Self {
+        StitchError::Json(error.to_string())
+    }
+}
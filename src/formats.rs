@@ -1,8 +1,11 @@
-use clap::ArgEnum;
-use serde::Serialize;
-use serde_json::de::from_reader;
+use crate::*;
+use clap::{ArgEnum, Parser};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use serde_json::de::{from_reader, from_str};
 use serde_json::Value;
 use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::iter::repeat;
 use std::path::Path;
 
@@ -10,36 +13,248 @@ use std::path::Path;
 pub enum InputFormat {
     Dreamcoder,
     ProgramsList,
+    WeightedProgramsList,
+    Jsonl,
 }
 
 #[derive(Debug, Clone)]
 pub struct Input {
-    pub train_programs: Vec<String>,                 // Program strings.
-    pub tasks: Option<Vec<String>>,                  // Task names for each corresponding string.
+    pub train_programs: Vec<String>, // Program strings, empty if they were read straight into `program_set`.
+    pub program_set: Option<ProgramSet>, // The programs read straight into one shared set, for formats that do so instead of keeping strings.
+    pub tasks: Option<Vec<String>>,      // Task names for each corresponding string.
+    pub weights: Option<Vec<f32>>,       // Weight of each corresponding string.
+    pub posterior_weights: Option<Vec<f32>>, // DreamCoder posterior of each string normalized within its frontier, if the frontiers had log likelihoods or priors.
     pub name_mapping: Option<Vec<(String, String)>>, // Vec of [#Dreamcoder invention, fn_i] tuples for any existing inventions in the DSL.
+    pub log_probabilities: Option<LogProbabilities>, // Log probabilities from the DreamCoder DSL, if there was one.
+    pub prim_types: Option<Vec<(String, String)>>, // Types of the productions in the DreamCoder DSL, if it has them.
+    pub reversible_prims: Option<Vec<(String, bool)>>, // Whether each production in the DreamCoder DSL is reversible, if it says.
+    pub dsl: Option<Value>, // The DreamCoder DSL itself, if there was one.
+}
+
+/// How the binaries weight the programs they read in, flattened into their arguments
+#[derive(Parser, Debug, Serialize, Clone)]
+pub struct PosteriorWeightsConfig {
+    /// weight each program by its posterior (logLikelihood + logPrior) normalized within its frontier and score each
+    /// frontier by its expected cost under them (implies --expected-cost), so that likely programs drive abstraction
+    /// choice. Requires --fmt=dreamcoder
+    #[clap(long)]
+    pub posterior_weights: bool,
+}
+
+impl PosteriorWeightsConfig {
+    /// Switches `input` over to its posterior weights (see `Input::use_posterior_weights()`) and `cfg` over to
+    /// expected costs, if --posterior-weights was given
+    pub fn apply(
+        &self,
+        input: &mut Input,
+        cfg: &mut MultistepCompressionConfig,
+    ) -> Result<(), StitchError> {
+        if self.posterior_weights {
+            input.use_posterior_weights()?;
+            cfg.step.expected_cost = true;
+        }
+        Ok(())
+    }
+}
+
+/// The log probabilities of the productions, variables and lambdas in a DreamCoder DSL. Productions
+/// that are inventions are keyed by the name they were given in `Input::name_mapping`.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogProbabilities {
+    pub productions: Vec<(String, f64)>,
+    pub log_variable: f64,
+    pub log_lambda: Option<f64>,
+    pub log_free_var: Option<f64>,
+}
+
+impl Input {
+    /// Weight each program by its posterior normalized within its frontier (see `Input::posterior_weights`)
+    /// so that likely programs drive abstraction choice, like in DreamCoder's own compressor. Compression should
+    /// be run with `CompressionStepConfig::expected_cost` so that each frontier is scored by its expected cost
+    /// under these weights rather than by its least likely program.
+    pub fn use_posterior_weights(&mut self) -> Result<(), StitchError> {
+        let posterior_weights = self.posterior_weights.clone().ok_or_else(|| {
+            StitchError::InvalidConfig(
+                "posterior weights need a DreamCoder input with a \"logLikelihood\" or \"logPrior\" for its programs"
+                    .to_string(),
+            )
+        })?;
+        self.weights = Some(posterior_weights);
+        Ok(())
+    }
+
+    /// Prints the programs of `program_set` (if any) into `train_programs`, for uses that need program strings
+    pub fn expand_program_set(&mut self) {
+        if let Some(program_set) = self.program_set.take() {
+            self.train_programs = program_set.strings();
+        }
+    }
+
+    /// The train programs parsed into expressions, copying each one out of `program_set` into its own
+    /// `ExprOwned` if they were read into one. Compression doesn't need this, see `multistep_compression_parsed()`.
+    pub fn train_exprs(&self) -> Result<Vec<ExprOwned>, StitchError> {
+        match &self.program_set {
+            Some(program_set) => Ok(program_set
+                .roots
+                .iter()
+                .map(|root| {
+                    let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+                    let idx = program_set.set.get(*root).copy_rec(&mut set);
+                    ExprOwned::new(set, idx)
+                })
+                .collect()),
+            None => parse_programs(&self.train_programs),
+        }
+    }
+}
+
+/// Programs parsed into one shared (structurally hashed) set, along with the root of each program in it. This
+/// is how `InputFormat::Jsonl` hands programs to `multistep_compression_parsed()` without printing them to strings
+/// and parsing them again, and the set is what the first `CorpusIndex` of compression starts out with (see
+/// `CorpusIndex::from_program_set()`), so the programs never get copied out one by one.
+#[derive(Debug, Clone)]
+pub struct ProgramSet {
+    pub set: ExprSet,
+    pub roots: Vec<Idx>,
+}
+
+impl ProgramSet {
+    /// parses the programs into one set, reporting the index of the first one that doesn't parse
+    pub fn parse(programs: &[String]) -> Result<ProgramSet, StitchError> {
+        let mut set = ExprSet::empty(Order::ChildFirst, false, true);
+        let roots = programs
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                set.parse_extend(p).map_err(|message| StitchError::Parse {
+                    program: i,
+                    message,
+                })
+            })
+            .collect::<Result<Vec<Idx>, StitchError>>()?;
+        Ok(ProgramSet { set, roots })
+    }
+
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// prints each program to a string
+    pub fn strings(&self) -> Vec<String> {
+        self.roots
+            .iter()
+            .map(|root| self.set.get(*root).to_string())
+            .collect()
+    }
+
+    /// the cost of each program under `cost_fn`
+    pub fn costs(&self, cost_fn: &ExprCost) -> Vec<i32> {
+        let mut analyzed_cost = AnalyzedExpr::new(cost_fn.clone());
+        analyzed_cost.analyze(&self.set);
+        self.roots
+            .iter()
+            .map(|root| analyzed_cost[*root].0)
+            .collect()
+    }
+
+    /// the depth of each program, counting leaves as depth 1
+    pub fn depths(&self) -> Vec<usize> {
+        // the set is child first, so children always come before their parents
+        let mut depth: Vec<usize> = vec![0; self.set.len()];
+        for node in 0..self.set.len() {
+            depth[node] = 1 + self
+                .set
+                .get(node)
+                .children()
+                .map(|child| depth[child])
+                .max()
+                .unwrap_or(0);
+        }
+        self.roots.iter().map(|root| depth[*root]).collect()
+    }
+}
+
+/// An entry in the `WeightedProgramsList` format. Entries without a task get a task of their own (see
+/// `name_untasked()`), and entries without a weight are given a weight of 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WeightedProgram {
+    pub program: String,
+    #[serde(default)]
+    pub task: Option<String>,
+    #[serde(default)]
+    pub weight: Option<f32>,
+}
+
+/// Gives each program without a task a task of its own, named after its index. Primes are added to the name
+/// until it doesn't collide with a task some program was actually given, so it can't get lumped in with one.
+fn name_untasked(tasks: Vec<Option<String>>) -> Vec<String> {
+    let given: FxHashSet<String> = tasks.iter().flatten().cloned().collect();
+    tasks
+        .into_iter()
+        .enumerate()
+        .map(|(i, task)| {
+            task.unwrap_or_else(|| {
+                let mut name = i.to_string();
+                while given.contains(&name) {
+                    name.push('\'');
+                }
+                name
+            })
+        })
+        .collect()
+}
+
+/// A line of the `Jsonl` format, which is either a bare program string or a `WeightedProgram`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum JsonlEntry {
+    Program(String),
+    Weighted(WeightedProgram),
 }
 
 impl InputFormat {
-    pub fn load_programs_and_tasks(&self, path: &Path) -> Result<Input, String> {
+    pub fn load_programs_and_tasks(&self, path: &Path) -> Result<Input, StitchError> {
+        let file = File::open(path).map_err(|error| StitchError::Io {
+            path: path.display().to_string(),
+            error,
+        })?;
         match *self {
             InputFormat::Dreamcoder => {
                 // read dreamcoder format
-                let json: Value = from_reader(File::open(path).expect("file not found"))
-                    .expect("json deserializing error");
+                let json: Value = from_reader(file)?;
                 // there should be a "frontiers" field at the toplevel
-                let frontiers = json["frontiers"].as_array().unwrap_or_else(|| {
-                    panic!(
-                        "json parse error, are you sure you wanted format {:?}?",
-                        self
-                    )
-                });
+                let frontiers = json["frontiers"].as_array().ok_or_else(|| {
+                    StitchError::Json(format!(
+                        "no \"frontiers\" list at the top level, are you sure you wanted format {self:?}?"
+                    ))
+                })?;
+                let productions: &[Value] = match &json["DSL"]["productions"] {
+                    Value::Array(productions) => productions,
+                    _ => {
+                        return Err(StitchError::Json(
+                            "no \"productions\" list in the \"DSL\"".to_string(),
+                        ))
+                    }
+                };
+                let expressions: Vec<&str> = productions
+                    .iter()
+                    .map(|prod| {
+                        prod["expression"].as_str().ok_or_else(|| {
+                            StitchError::Json(format!(
+                                "production without an \"expression\" string: {prod}"
+                            ))
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
                 // grab any existing inventions from the DSL
-                let mut dc_invs: Vec<String> = json["DSL"]["productions"]
-                    .as_array()
-                    .unwrap()
+                let mut dc_invs: Vec<String> = expressions
                     .iter()
-                    .map(|prod| prod["expression"].as_str().unwrap().to_string())
                     .filter(|s| s.starts_with('#'))
+                    .map(|s| s.to_string())
                     .collect();
                 dc_invs.sort_by_key(|s| s.len()); // increasing length so inventions that build on earlier ones come later
                 let inv_dc_strs: Vec<(String, String)> = dc_invs
@@ -47,26 +262,111 @@ impl InputFormat {
                     .enumerate()
                     .map(|(i, dc_str)| (format!("dreamcoder_abstraction_{i}"), dc_str)) // TODO: determine if we need to replace these in the future.
                     .collect();
+                let names: Vec<&str> = expressions
+                    .iter()
+                    .map(|expression| {
+                        inv_dc_strs
+                            .iter()
+                            .find(|(_, dc_str)| dc_str == expression)
+                            .map(|(name, _)| name.as_str())
+                            .unwrap_or(expression)
+                    })
+                    .collect();
+                let log_probabilities = LogProbabilities {
+                    productions: productions
+                        .iter()
+                        .zip(names.iter())
+                        .filter_map(|(prod, name)| {
+                            prod["logProbability"]
+                                .as_f64()
+                                .map(|log_prob| (name.to_string(), log_prob))
+                        })
+                        .collect(),
+                    log_variable: json["DSL"]["logVariable"].as_f64().unwrap_or(0.),
+                    log_lambda: json["DSL"]["logLambda"].as_f64(),
+                    log_free_var: json["DSL"]["logFreeVar"].as_f64(),
+                };
+                let prim_types: Vec<(String, String)> = productions
+                    .iter()
+                    .zip(names.iter())
+                    .filter_map(|(prod, name)| {
+                        prod["type"]
+                            .as_str()
+                            .map(|ty| (name.to_string(), ty.to_string()))
+                    })
+                    .collect();
+                let reversible_prims: Vec<(String, bool)> = productions
+                    .iter()
+                    .zip(names.iter())
+                    .filter_map(|(prod, name)| {
+                        prod["is_reversible"]
+                            .as_bool()
+                            .map(|is_reversible| (name.to_string(), is_reversible))
+                    })
+                    .collect();
                 let mut programs: Vec<String> = Vec::default();
                 let mut tasks: Vec<String> = Vec::default();
+                let mut posterior_weights: Vec<f32> = Vec::default();
+                let mut has_posteriors = false;
                 for (i, frontier) in frontiers.iter().enumerate() {
                     let programs_in_frontier: Vec<String> = frontier["programs"]
                         .as_array()
-                        .unwrap()
+                        .ok_or_else(|| {
+                            StitchError::Json(format!("frontier {i} has no \"programs\" list"))
+                        })?
                         .iter()
-                        .map(|p| p["program"].as_str().unwrap().to_string())
                         .map(|p| {
-                            inv_dc_strs
-                                .iter()
-                                .rev()
-                                .fold(p, |p, s| p.replace(&s.1, &s.0))
+                            p["program"].as_str().map(|p| p.to_string()).ok_or_else(|| {
+                                StitchError::Json(format!(
+                                    "frontier {i} has an entry without a \"program\" string: {p}"
+                                ))
+                            })
+                        })
+                        .map(|p| {
+                            p.map(|p| {
+                                inv_dc_strs
+                                    .iter()
+                                    .rev()
+                                    .fold(p, |p, s| p.replace(&s.1, &s.0))
+                            })
                         }) // replace #(lambda ...) with fn_2 etc. Start with highest numbered fn to avoid mangling bodies of other fns.
-                        .collect();
-                    assert!(!programs_in_frontier.iter().any(|p| p.contains('#')));
+                        .collect::<Result<_, _>>()?;
+                    if let Some(j) = programs_in_frontier.iter().position(|p| p.contains('#')) {
+                        return Err(StitchError::Parse {
+                            program: programs.len() + j,
+                            message: "uses an invention that isn't in the DSL".to_string(),
+                        });
+                    }
                     let task: String = match frontier["task"].as_str() {
                         Some(name) => name.to_string(),
                         None => i.to_string(),
                     };
+                    // a missing logLikelihood or logPrior counts as 0, ie probability 1
+                    let log_posteriors: Vec<f64> = frontier["programs"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|p| {
+                            has_posteriors |=
+                                p["logLikelihood"].is_number() || p["logPrior"].is_number();
+                            p["logLikelihood"].as_f64().unwrap_or(0.)
+                                + p["logPrior"].as_f64().unwrap_or(0.)
+                        })
+                        .collect();
+                    // normalize within the frontier, subtracting the max first so exp() doesn't underflow
+                    let max_log_posterior = log_posteriors
+                        .iter()
+                        .cloned()
+                        .fold(f64::NEG_INFINITY, f64::max);
+                    let total: f64 = log_posteriors
+                        .iter()
+                        .map(|lp| (lp - max_log_posterior).exp())
+                        .sum();
+                    posterior_weights.extend(
+                        log_posteriors
+                            .iter()
+                            .map(|lp| ((lp - max_log_posterior).exp() / total) as f32),
+                    );
                     let task_repeated: Vec<String> =
                         repeat(task).take(programs_in_frontier.len()).collect();
                     programs.extend(programs_in_frontier);
@@ -74,17 +374,175 @@ impl InputFormat {
                 }
                 let input = Input {
                     train_programs: programs,
+                    program_set: None,
                     tasks: Some(tasks),
+                    weights: None,
+                    posterior_weights: if has_posteriors {
+                        Some(posterior_weights)
+                    } else {
+                        None
+                    },
                     name_mapping: Some(inv_dc_strs),
+                    log_probabilities: Some(log_probabilities),
+                    prim_types: if prim_types.is_empty() {
+                        None
+                    } else {
+                        Some(prim_types)
+                    },
+                    reversible_prims: if reversible_prims.is_empty() {
+                        None
+                    } else {
+                        Some(reversible_prims)
+                    },
+                    dsl: Some(json["DSL"].clone()),
                 };
                 Ok(input)
             }
             InputFormat::ProgramsList => {
-                let programs: Vec<String> = from_reader(File::open(path).map_err(|e| format!("file not found, error code {e:?}"))?).map_err(|e| format!("json parser error, are you sure you wanted format {self:?}? Error code was {e:?}"))?;
+                let programs: Vec<String> = from_reader(file).map_err(|e| {
+                    StitchError::Json(format!(
+                        "{e}, are you sure you wanted format {self:?}? It should be a list of program strings"
+                    ))
+                })?;
                 let input = Input {
                     train_programs: programs,
+                    program_set: None,
                     tasks: None,
+                    weights: None,
+                    posterior_weights: None,
+                    name_mapping: None,
+                    log_probabilities: None,
+                    prim_types: None,
+                    reversible_prims: None,
+                    dsl: None,
+                };
+                Ok(input)
+            }
+            InputFormat::WeightedProgramsList => {
+                let entries: Vec<WeightedProgram> = from_reader(file).map_err(|e| {
+                    StitchError::Json(format!(
+                        "{e}, are you sure you wanted format {self:?}? It should be a list of objects with a \"program\" string and optional \"task\" and \"weight\" fields"
+                    ))
+                })?;
+                if let Some(i) = entries
+                    .iter()
+                    .position(|entry| matches!(entry.weight, Some(w) if !w.is_finite() || w < 0.))
+                {
+                    return Err(StitchError::Json(format!(
+                        "entry {i} has weight {}, weights must be finite and nonnegative",
+                        entries[i].weight.unwrap()
+                    )));
+                }
+                // only report tasks and weights if some entry actually gave them
+                let tasks: Option<Vec<String>> = if entries.iter().any(|entry| entry.task.is_some())
+                {
+                    Some(name_untasked(
+                        entries.iter().map(|entry| entry.task.clone()).collect(),
+                    ))
+                } else {
+                    None
+                };
+                let weights: Option<Vec<f32>> =
+                    if entries.iter().any(|entry| entry.weight.is_some()) {
+                        Some(
+                            entries
+                                .iter()
+                                .map(|entry| entry.weight.unwrap_or(1.))
+                                .collect(),
+                        )
+                    } else {
+                        None
+                    };
+                let input = Input {
+                    train_programs: entries.into_iter().map(|entry| entry.program).collect(),
+                    program_set: None,
+                    tasks,
+                    weights,
+                    posterior_weights: None,
+                    name_mapping: None,
+                    log_probabilities: None,
+                    prim_types: None,
+                    reversible_prims: None,
+                    dsl: None,
+                };
+                Ok(input)
+            }
+            InputFormat::Jsonl => {
+                // Read a line at a time straight into one structurally hashed set, so identical programs hash to
+                // the same root. Copies without a task get merged as they're read: every program is its own task
+                // then, so copies add up into a weight under any scoring. Copies within a task are kept apart,
+                // since whether they'd add up (with `expected_cost`) or only the cheapest would count depends on
+                // how compression scores tasks.
+                let mut set = ExprSet::empty(Order::ChildFirst, false, true);
+                let mut roots: Vec<Idx> = vec![];
+                let mut tasks: Vec<Option<String>> = vec![];
+                let mut weights: Vec<f32> = vec![];
+                let mut root_idx_of: FxHashMap<Idx, usize> = Default::default(); // of the programs without a task
+                for (i, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line.map_err(|error| StitchError::Io {
+                        path: path.display().to_string(),
+                        error,
+                    })?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let entry = match from_str(&line).map_err(|e| {
+                        StitchError::Json(format!(
+                            "line {i}: {e}, are you sure you wanted format {self:?}? Each line should be a program string or an object with a \"program\" string and optional \"task\" and \"weight\" fields"
+                        ))
+                    })? {
+                        JsonlEntry::Program(program) => WeightedProgram {
+                            program,
+                            task: None,
+                            weight: None,
+                        },
+                        JsonlEntry::Weighted(entry) => entry,
+                    };
+                    let weight = entry.weight.unwrap_or(1.);
+                    if !weight.is_finite() || weight < 0. {
+                        return Err(StitchError::Json(format!(
+                            "line {i} has weight {weight}, weights must be finite and nonnegative"
+                        )));
+                    }
+                    let root =
+                        set.parse_extend(&entry.program)
+                            .map_err(|message| StitchError::Parse {
+                                program: i,
+                                message,
+                            })?;
+                    match root_idx_of.get(&root) {
+                        Some(&root_idx) if entry.task.is_none() => weights[root_idx] += weight,
+                        _ => {
+                            if entry.task.is_none() {
+                                root_idx_of.insert(root, roots.len());
+                            }
+                            roots.push(root);
+                            tasks.push(entry.task);
+                            weights.push(weight);
+                        }
+                    }
+                }
+                let tasks: Option<Vec<String>> = if tasks.iter().any(|task| task.is_some()) {
+                    Some(name_untasked(tasks))
+                } else {
+                    None
+                };
+                let weights: Option<Vec<f32>> = if weights.iter().any(|w| *w != 1.) {
+                    Some(weights)
+                } else {
+                    None
+                };
+                let input = Input {
+                    train_programs: vec![],
+                    program_set: Some(ProgramSet { set, roots }),
+                    tasks,
+                    weights,
+                    posterior_weights: None,
                     name_mapping: None,
+                    log_probabilities: None,
+                    prim_types: None,
+                    reversible_prims: None,
+                    dsl: None,
                 };
                 Ok(input)
             }
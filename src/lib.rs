@@ -1,9 +1,15 @@
 pub mod compression;
 pub mod egraphs;
+pub mod error;
 pub mod formats;
+pub mod named;
 pub mod rewriting;
+pub mod types;
 pub mod util;
 
-pub use {compression::*, egraphs::*, formats::*, lambdas::*, rewriting::*, util::*};
+pub use {
+    compression::*, egraphs::*, error::*, formats::*, lambdas::*, named::*, rewriting::*, types::*,
+    util::*,
+};
 
 pub use colorful::{Color, Colorful, RGB};
@@ -0,0 +1,266 @@
+use crate::*;
+use rustc_hash::FxHashSet;
+use std::fmt;
+
+/// An s-expression, just enough structure to move between named lambdas like `(lambda (x y) (f x y))` and the
+/// de Bruijn `(lam (lam (f $1 $0)))` syntax that `ExprSet::parse_extend()` reads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SExpr {
+    Atom(String),
+    List(Vec<SExpr>),
+}
+
+impl fmt::Display for SExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SExpr::Atom(atom) => write!(f, "{atom}"),
+            SExpr::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+fn parse_sexpr(s: &str) -> Result<SExpr, String> {
+    let spaced = s.replace('(', " ( ").replace(')', " ) ");
+    let mut stack: Vec<Vec<SExpr>> = vec![vec![]];
+    for token in spaced.split_whitespace() {
+        match token {
+            "(" => stack.push(vec![]),
+            ")" => {
+                let items = stack.pop().unwrap();
+                stack
+                    .last_mut()
+                    .ok_or_else(|| format!("unbalanced ')' in {s}"))?
+                    .push(SExpr::List(items));
+            }
+            atom => stack
+                .last_mut()
+                .unwrap()
+                .push(SExpr::Atom(atom.to_string())),
+        }
+    }
+    if stack.len() != 1 {
+        return Err(format!("unbalanced '(' in {s}"));
+    }
+    let mut top = stack.pop().unwrap();
+    if top.len() != 1 {
+        return Err(format!(
+            "expected a single expression, got {} in {s}",
+            top.len()
+        ));
+    }
+    Ok(top.pop().unwrap())
+}
+
+/// `lam` followed by whatever tag it has, if `atom` is a lambda keyword (`lambda`, `lam`, `lambda_<tag>` or `lam_<tag>`)
+fn lambda_keyword(atom: &str) -> Option<String> {
+    let rest = atom
+        .strip_prefix("lambda")
+        .or_else(|| atom.strip_prefix("lam"))?;
+    if rest.is_empty() || rest.starts_with('_') {
+        Some(format!("lam{rest}"))
+    } else {
+        None
+    }
+}
+
+/// Converts a program written with named lambdas like `(lambda (x y) (f x y))` or `(lambda x (f x))` into the de Bruijn
+/// syntax stitch uses, e.g. `(lam (lam (f $1 $0)))`. A multi-argument lambda becomes one `lam` per argument, names that
+/// aren't bound by an enclosing lambda are left alone as primitives, and everything else passes through unchanged.
+pub fn named_to_de_bruijn(program: &str) -> Result<String, String> {
+    fn convert(e: &SExpr, env: &mut Vec<String>) -> Result<SExpr, String> {
+        match e {
+            SExpr::Atom(atom) => Ok(match env.iter().rposition(|name| name == atom) {
+                Some(pos) => SExpr::Atom(format!("${}", env.len() - 1 - pos)),
+                None => SExpr::Atom(atom.clone()),
+            }),
+            SExpr::List(items) => {
+                let keyword = match items.first() {
+                    Some(SExpr::Atom(atom)) => lambda_keyword(atom),
+                    _ => None,
+                };
+                let keyword = match keyword {
+                    Some(keyword) => keyword,
+                    None => {
+                        return Ok(SExpr::List(
+                            items
+                                .iter()
+                                .map(|item| convert(item, env))
+                                .collect::<Result<_, _>>()?,
+                        ))
+                    }
+                };
+                if items.len() != 3 {
+                    return Err(format!("expected (lambda (x ...) body) but got {e}"));
+                }
+                let binders: Vec<String> = match &items[1] {
+                    SExpr::Atom(name) => vec![name.clone()],
+                    SExpr::List(names) if !names.is_empty() => names
+                        .iter()
+                        .map(|name| match name {
+                            SExpr::Atom(name) => Ok(name.clone()),
+                            SExpr::List(_) => Err(format!("lambda arguments must be names in {e}")),
+                        })
+                        .collect::<Result<_, _>>()?,
+                    SExpr::List(_) => return Err(format!("lambda without arguments in {e}")),
+                };
+                if let Some(name) = binders
+                    .iter()
+                    .find(|name| name.starts_with('$') || name.starts_with('#'))
+                {
+                    return Err(format!("can't use {name} as a lambda argument name in {e}"));
+                }
+                env.extend(binders.iter().cloned());
+                let body = convert(&items[2], env);
+                env.truncate(env.len() - binders.len());
+                Ok(binders.iter().fold(body?, |body, _| {
+                    SExpr::List(vec![SExpr::Atom(keyword.clone()), body])
+                }))
+            }
+        }
+    }
+    Ok(convert(&parse_sexpr(program)?, &mut vec![])?.to_string())
+}
+
+/// Names that don't clash with anything already in an expression
+struct FreshNames {
+    used: FxHashSet<String>,
+    next: usize,
+}
+
+impl FreshNames {
+    fn new(e: &SExpr) -> Self {
+        fn atoms(e: &SExpr, used: &mut FxHashSet<String>) {
+            match e {
+                SExpr::Atom(atom) => {
+                    used.insert(atom.clone());
+                }
+                SExpr::List(items) => items.iter().for_each(|item| atoms(item, used)),
+            }
+        }
+        let mut used = FxHashSet::default();
+        atoms(e, &mut used);
+        FreshNames { used, next: 0 }
+    }
+    fn fresh(&mut self) -> String {
+        loop {
+            let name = format!("x{}", self.next);
+            self.next += 1;
+            if !self.used.contains(&name) {
+                return name;
+            }
+        }
+    }
+}
+
+/// The inverse of `named_to_de_bruijn()`: gives each lambda a fresh name, merging directly nested lambdas into one
+/// multi-argument lambda, and names each ivar `#i` with the i-th name in `ivar_names`.
+fn de_bruijn_to_named_rec(
+    e: &SExpr,
+    env: &mut Vec<String>,
+    ivar_names: &[String],
+    fresh: &mut FreshNames,
+) -> SExpr {
+    match e {
+        SExpr::Atom(atom) => {
+            if let Some(Ok(i)) = atom.strip_prefix('$').map(|i| i.parse::<usize>()) {
+                if i < env.len() {
+                    return SExpr::Atom(env[env.len() - 1 - i].clone());
+                }
+            }
+            if let Some(Ok(i)) = atom.strip_prefix('#').map(|i| i.parse::<usize>()) {
+                if i < ivar_names.len() {
+                    return SExpr::Atom(ivar_names[i].clone());
+                }
+            }
+            SExpr::Atom(atom.clone())
+        }
+        SExpr::List(items) => {
+            let keyword = match items.as_slice() {
+                [SExpr::Atom(atom), _] => lambda_keyword(atom),
+                _ => None,
+            };
+            let keyword = match keyword {
+                Some(keyword) => keyword,
+                None => {
+                    return SExpr::List(
+                        items
+                            .iter()
+                            .map(|item| de_bruijn_to_named_rec(item, env, ivar_names, fresh))
+                            .collect(),
+                    )
+                }
+            };
+            // collect the chain of lambdas with the same tag
+            let mut binders: Vec<String> = vec![];
+            let mut body = e;
+            while let SExpr::List(items) = body {
+                match items.as_slice() {
+                    [SExpr::Atom(atom), inner]
+                        if lambda_keyword(atom).as_ref() == Some(&keyword) =>
+                    {
+                        binders.push(fresh.fresh());
+                        body = inner;
+                    }
+                    _ => break,
+                }
+            }
+            env.extend(binders.iter().cloned());
+            let body = de_bruijn_to_named_rec(body, env, ivar_names, fresh);
+            env.truncate(env.len() - binders.len());
+            SExpr::List(vec![
+                SExpr::Atom(keyword.replacen("lam", "lambda", 1)),
+                SExpr::List(binders.into_iter().map(SExpr::Atom).collect()),
+                body,
+            ])
+        }
+    }
+}
+
+/// Converts a program in stitch's de Bruijn syntax like `(lam (lam (f $1 $0)))` to named lambdas like
+/// `(lambda (x0 x1) (f x0 x1))`, with names that don't clash with any primitive in the program.
+pub fn de_bruijn_to_named(program: &str) -> Result<String, String> {
+    let e = parse_sexpr(program)?;
+    let mut fresh = FreshNames::new(&e);
+    Ok(de_bruijn_to_named_rec(&e, &mut vec![], &[], &mut fresh).to_string())
+}
+
+/// Converts an invention body with `arity` ivars to a named lambda that takes them as arguments, so
+/// `(f #0 (lam (g $0 #1)))` becomes `(lambda (x0 x1) (f x0 (lambda (x2) (g x2 x1))))`.
+pub fn invention_to_named(body: &str, arity: usize) -> Result<String, String> {
+    let e = parse_sexpr(body)?;
+    let mut fresh = FreshNames::new(&e);
+    let ivar_names: Vec<String> = (0..arity).map(|_| fresh.fresh()).collect();
+    let body = de_bruijn_to_named_rec(&e, &mut vec![], &ivar_names, &mut fresh);
+    if arity == 0 {
+        return Ok(body.to_string());
+    }
+    Ok(SExpr::List(vec![
+        SExpr::Atom("lambda".to_string()),
+        SExpr::List(ivar_names.into_iter().map(SExpr::Atom).collect()),
+        body,
+    ])
+    .to_string())
+}
+
+/// Runs `named_to_de_bruijn()` on each program, reporting the index of the first one that doesn't convert
+pub fn programs_from_named(programs: &[String]) -> Result<Vec<String>, StitchError> {
+    programs
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            named_to_de_bruijn(p).map_err(|message| StitchError::Parse {
+                program: i,
+                message,
+            })
+        })
+        .collect()
+}
@@ -13,43 +13,71 @@ struct ShiftRule {
     shift: i32,
 }
 
-pub fn rewrite_fast(
-    pattern: &FinishedPattern,
-    shared: &SharedData,
-    inv_name: &Node,
-    cost_fn: &ExprCost,
-) -> Vec<ExprOwned> {
-    //  if !shared.cfg.quiet { println!("rewriting with {}", pattern.info(&shared)) }
+/// An argument of an invention being applied at a rewrite site: the corpus node it takes, how much the free
+/// variables in it have to be shifted by, and the zipper from the root of the body down to the ivar
+struct SiteArg<'a> {
+    unshifted_id: Idx,
+    shift: i32,
+    zip: &'a [ZNode],
+}
+
+/// An invention being applied at a node of the corpus during rewriting
+struct RewriteSite<'a> {
+    inv_name: &'a Node,
+    args: Vec<SiteArg<'a>>,
+    free_names: &'a [Symbol], // the free RevLet inputs of the body, which stay used after rewriting
+}
+
+/// Where `rewrite_program()` applies inventions: at the match locations of a pattern for `rewrite_fast()`, or
+/// wherever `direct_rewrite_decisions()` chose to for `rewrite_direct()`
+trait RewriteSites<'a> {
+    /// the invention to apply at `node`, if any. `in_func_position` is whether `node` is the function of an app
+    fn site(&mut self, node: Idx, in_func_position: bool) -> Option<RewriteSite<'a>>;
+    /// whether to stop rewriting under the let at `node`
+    fn skip_nested(&self, _node: Idx) -> bool {
+        false
+    }
+    /// called before each pass over the program, since dropping unused let bindings can take more than one
+    fn start_pass(&mut self) {}
+}
+
+/// Rewrites the program at `root` in `set`, applying inventions wherever `sites` says to. Let bindings whose
+/// variables are no longer used once their uses are rewritten away get dropped, and since dropping one can
+/// make others unused we keep rewriting until no more of them become unused.
+fn rewrite_program<'a>(
+    set: &ExprSet,
+    root: Idx,
+    sites: &mut impl RewriteSites<'a>,
+    eta_long: bool,
+) -> ExprOwned {
     #[allow(clippy::too_many_arguments)]
-    fn helper(
+    fn helper<'a>(
         owned_set: &mut ExprSet,
         new_vars_mapping: &mut FxHashMap<Symbol, Idx>,
         used_vars: &mut Vec<Symbol>,
         unused_vars: &mut FxHashSet<Symbol>,
-        pattern: &FinishedPattern,
-        shared: &SharedData,
+        set: &ExprSet,
+        sites: &mut impl RewriteSites<'a>,
+        eta_long: bool,
         unshifted_id: Idx,
         total_depth: i32, // depth from the very root of the program down
         shift_rules: &mut Vec<ShiftRule>,
-        inv_name: &Node,
         should_skip: bool,
+        in_func_position: bool,
     ) -> Idx {
-        // println!("next @ {}: {}", total_depth, shared.set.get(unshifted_id));
+        // println!("next @ {}: {}", total_depth, set.get(unshifted_id));
 
         // we search using the the *unshifted* one since its an original program tree node
-        if !should_skip && pattern.pattern.match_locations.binary_search_by(|(n, _)| n.cmp(&unshifted_id)).is_ok() // if the pattern matches here
-           && (!pattern.util_calc.corrected_utils.contains_key(&unshifted_id) // and either we have no conflict (ie corrected_utils doesnt have an entry)
-             || pattern.util_calc.corrected_utils[&unshifted_id])
-        // or we have a conflict but we choose to accept it (which is contextless in this top down approach so its the right move)
-        //    && !pattern.pattern.first_zid_of_ivar.iter().any(|zid| // and there are no negative vars anywhere in the arguments
-        //         shared.egraph[shared.arg_of_zid_node[*zid][&unshifted_id].Idx].data.free_vars.iter().any(|var| *var < 0))
-        {
+        let site = if should_skip {
+            None
+        } else {
+            sites.site(unshifted_id, in_func_position)
+        };
+        if let Some(site) = site {
             //  if !shared.cfg.quiet { println!("inv applies at unshifted={} with shift={}", extract(unshifted_id,&shared.egraph), shift) }
-            let mut expr = (*owned_set).add(inv_name.clone());
+            let mut expr = owned_set.add(site.inv_name.clone());
             // wrap the prim in all the Apps to args
-            for zid in pattern.pattern.first_zid_of_ivar.iter() {
-                let arg: &Arg = &shared.arg_of_zid_node[*zid][&unshifted_id];
-
+            for arg in site.args.iter() {
                 if arg.shift != 0 {
                     shift_rules.push(ShiftRule {
                         depth_cutoff: total_depth,
@@ -57,38 +85,35 @@ pub fn rewrite_fast(
                     });
                     // println!("pushing shift rule: {:?}", shift_rules.last().unwrap());
                 }
-                // println!("rewriting arg: {}", shared.set.get(arg.unshifted_id));
+                // println!("rewriting arg: {}", set.get(arg.unshifted_id));
                 // recurse with the shift added (subtracted since it's negative) to the depth
                 let mut rewritten_arg = helper(
                     owned_set,
                     new_vars_mapping,
                     used_vars,
                     unused_vars,
-                    pattern,
-                    shared,
+                    set,
+                    sites,
+                    eta_long,
                     arg.unshifted_id,
                     total_depth - arg.shift,
                     shift_rules,
-                    inv_name,
                     should_skip,
+                    false,
                 );
                 if arg.shift != 0 {
                     // println!("popping shift rule");
                     shift_rules.pop(); // pop the rule back off after
                 }
 
-                if shared.cfg.eta_long {
+                if eta_long {
                     // assuming eta long form in the original corpus, the number of times we need to insert new apps() via eta expanding
                     // is the number of apps directly up and to the right of this argument - ie how many Funcs are at the end of the zipper to
                     // this argument?
                     // Also note that in the single_hole code --eta-long enforces that match locations never contains anything that starts to the left of a func so
                     // we dont need to worry about the case where the zipper would extend even past the root of the match location
                     // Also note that due to beta normal form, this will be zero and will be a no-op if the arg is a lambda
-                    let arity_of_arg = shared.zip_of_zid[*zid]
-                        .iter()
-                        .rev()
-                        .take_while(|znode| **znode == ZNode::Func)
-                        .count();
+                    let arity_of_arg = arity_of_arg(arg.zip);
                     if arity_of_arg > 0 {
                         let analyzed_free_vars = &mut AnalyzedExpr::new(FreeVarAnalysis);
 
@@ -122,12 +147,19 @@ pub fn rewrite_fast(
                 expr = owned_set.add(Node::App(expr, rewritten_arg));
             }
 
-            // println!("rewrote: {} -> {}", shared.set.get(unshifted_id), owned_set.get(expr));
+            // println!("rewrote: {} -> {}", set.get(unshifted_id), owned_set.get(expr));
+
+            // the free RevLet inputs of the body are still used after rewriting
+            for var in site.free_names.iter() {
+                if !used_vars.contains(var) {
+                    used_vars.push(var.clone());
+                }
+            }
 
             return expr;
         }
 
-        match &shared.set[unshifted_id] {
+        match &set[unshifted_id] {
             Node::Prim(p) => owned_set.add(Node::Prim(p.clone())),
             Node::Var(i, tag) => {
                 let mut j = *i;
@@ -150,26 +182,28 @@ pub fn rewrite_fast(
                     new_vars_mapping,
                     used_vars,
                     unused_vars,
-                    pattern,
-                    shared,
+                    set,
+                    sites,
+                    eta_long,
                     *unshifted_f,
                     total_depth,
                     shift_rules,
-                    inv_name,
                     should_skip,
+                    true,
                 );
                 let x = helper(
                     owned_set,
                     new_vars_mapping,
                     used_vars,
                     unused_vars,
-                    pattern,
-                    shared,
+                    set,
+                    sites,
+                    eta_long,
                     *unshifted_x,
                     total_depth,
                     shift_rules,
-                    inv_name,
                     should_skip,
+                    false,
                 );
                 owned_set.add(Node::App(f, x))
             }
@@ -179,19 +213,19 @@ pub fn rewrite_fast(
                     new_vars_mapping,
                     used_vars,
                     unused_vars,
-                    pattern,
-                    shared,
+                    set,
+                    sites,
+                    eta_long,
                     *unshifted_b,
                     total_depth + 1,
                     shift_rules,
-                    inv_name,
                     should_skip,
+                    false,
                 );
                 owned_set.add(Node::Lam(b, *tag))
             }
-            Node::IVar(_) => {
-                unreachable!()
-            }
+            // abstraction bodies being refactored (see refactor_library()) have ivars as leaves
+            Node::IVar(i) => owned_set.add(Node::IVar(*i)),
             Node::NVar(name) => {
                 if !used_vars.contains(name) {
                     used_vars.push(name.clone());
@@ -210,24 +244,21 @@ pub fn rewrite_fast(
                 def: unshifted_def,
                 body: unshifted_body,
             } => {
-                let should_skip = should_skip
-                    || pattern
-                        .util_calc
-                        .skip_rewrite_nested
-                        .contains(&unshifted_id);
+                let should_skip = should_skip || sites.skip_nested(unshifted_id);
                 if unused_vars.contains(var) {
                     helper(
                         owned_set,
                         new_vars_mapping,
                         used_vars,
                         unused_vars,
-                        pattern,
-                        shared,
+                        set,
+                        sites,
+                        eta_long,
                         *unshifted_body,
                         total_depth,
                         shift_rules,
-                        inv_name,
                         should_skip,
+                        false,
                     )
                 } else {
                     let def = helper(
@@ -235,13 +266,14 @@ pub fn rewrite_fast(
                         new_vars_mapping,
                         used_vars,
                         unused_vars,
-                        pattern,
-                        shared,
+                        set,
+                        sites,
+                        eta_long,
                         *unshifted_def,
                         total_depth,
                         shift_rules,
-                        inv_name,
                         should_skip,
+                        false,
                     );
                     new_vars_mapping.insert(var.clone(), def);
                     let body = helper(
@@ -249,13 +281,14 @@ pub fn rewrite_fast(
                         new_vars_mapping,
                         used_vars,
                         unused_vars,
-                        pattern,
-                        shared,
+                        set,
+                        sites,
+                        eta_long,
                         *unshifted_body,
                         total_depth,
                         shift_rules,
-                        inv_name,
                         should_skip,
+                        false,
                     );
                     if !used_vars.contains(var) {
                         unused_vars.insert(var.clone());
@@ -273,51 +306,40 @@ pub fn rewrite_fast(
                 def: unshifted_def,
                 body: unshifted_body,
             } => {
+                let body = helper(
+                    owned_set,
+                    new_vars_mapping,
+                    used_vars,
+                    unused_vars,
+                    set,
+                    sites,
+                    eta_long,
+                    *unshifted_body,
+                    total_depth,
+                    shift_rules,
+                    should_skip,
+                    false,
+                );
                 if unused_vars.contains(inp_var) {
-                    let body = helper(
-                        owned_set,
-                        new_vars_mapping,
-                        used_vars,
-                        unused_vars,
-                        pattern,
-                        shared,
-                        *unshifted_body,
-                        total_depth,
-                        shift_rules,
-                        inv_name,
-                        should_skip,
-                    );
                     for var in def_vars.iter() {
                         unused_vars.insert(var.clone());
                     }
                     body
                 } else {
-                    let body = helper(
-                        owned_set,
-                        new_vars_mapping,
-                        used_vars,
-                        unused_vars,
-                        pattern,
-                        shared,
-                        *unshifted_body,
-                        total_depth,
-                        shift_rules,
-                        inv_name,
-                        should_skip,
-                    );
                     let mut def_used_vars: Vec<Symbol> = vec![];
                     let def = helper(
                         owned_set,
                         new_vars_mapping,
                         &mut def_used_vars,
                         unused_vars,
-                        pattern,
-                        shared,
+                        set,
+                        sites,
+                        eta_long,
                         *unshifted_def,
                         total_depth,
                         shift_rules,
-                        inv_name,
                         should_skip,
+                        false,
                     );
                     for var in def_vars.iter() {
                         if !def_used_vars.contains(var) {
@@ -325,10 +347,8 @@ pub fn rewrite_fast(
                         }
                     }
                     for var in def_used_vars.iter() {
-                        if unused_vars.contains(var) {
-                            // We need this in case when two revlets are rewritten by overlapping patterns
-                            unused_vars.remove(var);
-                        }
+                        // We need this in case when two revlets are rewritten by overlapping patterns
+                        unused_vars.remove(var);
                     }
                     if !used_vars.contains(inp_var) {
                         used_vars.push(inp_var.clone());
@@ -345,58 +365,112 @@ pub fn rewrite_fast(
         }
     }
 
+    let mut unused_vars: FxHashSet<Symbol> = FxHashSet::default();
+    loop {
+        let unused_count = unused_vars.len();
+        sites.start_pass();
+        let mut owned_set = ExprSet::empty(Order::ChildFirst, false, false); // need struct hash off for cost_span later
+        let idx = helper(
+            &mut owned_set,
+            &mut FxHashMap::default(),
+            &mut vec![],
+            &mut unused_vars,
+            set,
+            sites,
+            eta_long,
+            root,
+            0,
+            &mut vec![],
+            false,
+            false,
+        );
+        if unused_vars.len() == unused_count {
+            return ExprOwned::new(owned_set, idx);
+        }
+    }
+}
+
+/// Rewrites at the match locations of a pattern, see `rewrite_fast()`
+struct PatternSites<'a> {
+    pattern: &'a FinishedPattern,
+    shared: &'a SharedData,
+    inv_name: &'a Node,
+}
+
+impl<'a> RewriteSites<'a> for PatternSites<'a> {
+    fn site(&mut self, node: Idx, _in_func_position: bool) -> Option<RewriteSite<'a>> {
+        let PatternSites {
+            pattern,
+            shared,
+            inv_name,
+        } = *self;
+        // the pattern has to match here, and either we have no conflict (ie corrected_utils doesnt have an entry) or
+        // we have a conflict but we choose to accept it (which is contextless in this top down approach so its the right move)
+        //    && !pattern.pattern.first_zid_of_ivar.iter().any(|zid| // and there are no negative vars anywhere in the arguments
+        //         shared.egraph[shared.arg_of_zid_node[*zid][&unshifted_id].Idx].data.free_vars.iter().any(|var| *var < 0))
+        if pattern
+            .pattern
+            .match_locations
+            .binary_search_by(|(n, _)| n.cmp(&node))
+            .is_err()
+            || !pattern
+                .util_calc
+                .corrected_utils
+                .get(&node)
+                .copied()
+                .unwrap_or(true)
+        {
+            return None;
+        }
+        Some(RewriteSite {
+            inv_name,
+            args: pattern
+                .pattern
+                .first_zid_of_ivar
+                .iter()
+                .map(|zid| {
+                    let arg: &Arg = &shared.arg_of_zid_node[*zid][&node];
+                    SiteArg {
+                        unshifted_id: arg.unshifted_id,
+                        shift: arg.shift,
+                        zip: &shared.zip_of_zid[*zid],
+                    }
+                })
+                .collect(),
+            free_names: &pattern.pattern.free_named_vars,
+        })
+    }
+
+    fn skip_nested(&self, node: Idx) -> bool {
+        self.pattern.util_calc.skip_rewrite_nested.contains(&node)
+    }
+}
+
+/// Rewrites each program in the corpus with `pattern` (as the primitive `inv_name`) at its match locations
+pub fn rewrite_fast(
+    pattern: &FinishedPattern,
+    shared: &SharedData,
+    inv_name: &Node,
+    cost_fn: &ExprCost,
+) -> Vec<ExprOwned> {
+    //  if !shared.cfg.quiet { println!("rewriting with {}", pattern.info(&shared)) }
+
     // println!(
     //     "skip rewrite nested: {:?}",
     //     pattern.util_calc.skip_rewrite_nested
     // );
 
-    let shift_rules = &mut vec![];
     let rewritten_exprs: Vec<ExprOwned> = shared
         .roots
         .iter()
         .map(|root| {
             // println!("ROOT");
-            let mut owned_set = ExprSet::empty(Order::ChildFirst, false, false); // need struct hash off for cost_span later
-            let mut new_vars_mapping: FxHashMap<Symbol, Idx> = FxHashMap::default();
-            let mut used_vars: Vec<Symbol> = vec![];
-            let mut unused_vars: FxHashSet<Symbol> = FxHashSet::default();
-            let mut unused_count = 0;
-            let mut idx = helper(
-                &mut owned_set,
-                &mut new_vars_mapping,
-                &mut used_vars,
-                &mut unused_vars,
+            let mut sites = PatternSites {
                 pattern,
                 shared,
-                *root,
-                0,
-                shift_rules,
                 inv_name,
-                false,
-            );
-            while unused_vars.len() != unused_count {
-                unused_count = unused_vars.len();
-                owned_set = ExprSet::empty(Order::ChildFirst, false, false);
-                used_vars = vec![];
-                new_vars_mapping = FxHashMap::default();
-                idx = helper(
-                    &mut owned_set,
-                    &mut new_vars_mapping,
-                    &mut used_vars,
-                    &mut unused_vars,
-                    pattern,
-                    shared,
-                    *root,
-                    0,
-                    shift_rules,
-                    inv_name,
-                    false,
-                );
-            }
-            ExprOwned {
-                set: owned_set,
-                idx,
-            }
+            };
+            rewrite_program(&shared.set, *root, &mut sites, shared.cfg.eta_long)
         })
         .collect();
 
@@ -421,13 +495,14 @@ pub fn rewrite_fast(
             shared
                 .root_idxs_of_task
                 .iter()
-                .map(|root_idxs| root_idxs
-                    .iter()
-                    .map(|idx| (rewritten_exprs[*idx].cost(cost_fn) as f32
-                        * shared.weight_by_root_idx[*idx])
-                        .round() as i32)
-                    .min()
-                    .unwrap())
+                .map(|root_idxs| task_cost(
+                    root_idxs
+                        .iter()
+                        .map(|idx| (rewritten_exprs[*idx].cost(cost_fn) as f32
+                            * shared.weight_by_root_idx[*idx])
+                            .round() as i32),
+                    shared.cfg.expected_cost
+                ))
                 .sum::<i32>(),
             shared.init_cost_weighted - pattern.util_calc.util,
             "\n{}\n",
@@ -439,12 +514,14 @@ pub fn rewrite_fast(
 }
 
 /// Rewrite with the given abstractions by performing a ultra heavily pruned version of the compression search
-/// using follow/track.
+/// using follow/track. `tasks` and `weights` are as in `multistep_compression()`.
 pub fn rewrite_with_inventions(
     programs: &[String],
+    tasks: Option<Vec<String>>,
+    weights: Option<Vec<f32>>,
     invs: &[Invention],
     cfg: &MultistepCompressionConfig,
-) -> (Vec<String>, Vec<CompressionStepResult>, serde_json::Value) {
+) -> Result<(Vec<String>, Vec<CompressionStepResult>, serde_json::Value), StitchError> {
     // if invs.is_empty() {
     //     return programs.to_vec()
     // }
@@ -454,7 +531,7 @@ pub fn rewrite_with_inventions(
     // programs.to_vec()
     let follow = Some(invs.to_vec());
     cfg.iterations = invs.len();
-    cfg.step.max_arity = invs.iter().map(|inv| inv.arity).max().unwrap();
+    cfg.step.max_arity = invs.iter().map(|inv| inv.arity).max().unwrap_or(0);
     cfg.silent = true;
     cfg.no_opt = true;
     cfg.step.allow_single_task = true;
@@ -468,7 +545,8 @@ pub fn rewrite_with_inventions(
     // cfg.step.rewritten_dreamcoder = true;
     // cfg.step.rewritten_intermediates = true;
 
-    let (step_results, json_res) = multistep_compression(programs, None, None, None, follow, &cfg);
+    let (step_results, json_res) =
+        multistep_compression(programs, tasks, weights, None, follow, None, &cfg)?;
 
     // return the last one - note that if an abstraction wasn't used anywhere it will not be included in the step_results so this
     // may be shorter than invs.len(), however we do ensure that we continue searching for the rest of the abstractions if this happens
@@ -477,5 +555,982 @@ pub fn rewrite_with_inventions(
         .last()
         .map(|res| res.rewritten.iter().map(|s| s.to_string()).collect())
         .unwrap_or_else(|| programs.to_vec());
-    (rewritten, step_results, json_res)
+    Ok((rewritten, step_results, json_res))
+}
+
+/// Where an invention body matched an argument: the node in the corpus that fills the ivar, how much it
+/// has to be shifted by (minus the number of lambdas in the body above it) and the zipper from the root of
+/// the body down to the ivar (used for eta-long expansion).
+#[derive(Clone, Debug)]
+struct DirectArg {
+    unshifted_id: Idx,
+    shift: i32,
+    zip: Vec<ZNode>,
+}
+
+/// Tries to match the invention `body` against the corpus subtree rooted at `node`, filling in `args` with the
+/// arguments each ivar takes. `depth` is the number of lambdas in the body above the current point, and `names`
+/// pairs up the named variables bound by lets in the body so far with the ones they matched in the corpus.
+#[allow(clippy::too_many_arguments)]
+fn match_invention(
+    body: Expr,
+    set: &ExprSet,
+    node: Idx,
+    depth: i32,
+    zip: &mut Vec<ZNode>,
+    names: &mut Vec<(Symbol, Symbol)>,
+    args: &mut Vec<Option<DirectArg>>,
+    analyzed_free_vars: &AnalyzedExpr<FreeVarAnalysis>,
+    analyzed_cost: &AnalyzedExpr<ExprCost>,
+    fused_lambda_tags: &Option<FxHashSet<Tag>>,
+) -> bool {
+    // the corpus variable that a named variable bound in the body matched
+    fn matched_name<'a>(names: &'a [(Symbol, Symbol)], name: &Symbol) -> Option<&'a Symbol> {
+        names
+            .iter()
+            .rev()
+            .find(|(body_name, _)| body_name == name)
+            .map(|(_, corpus_name)| corpus_name)
+    }
+    match (body.node(), &set[node]) {
+        (Node::IVar(i), _) => {
+            // the argument can't point to any lambdas or lets that are part of the body
+            if analyzed_free_vars[node].iter().any(|var| *var < depth)
+                || names
+                    .iter()
+                    .any(|(_, name)| analyzed_cost[node].1.contains_key(name))
+                || invalid_match_location(set, fused_lambda_tags, node)
+            {
+                return false;
+            }
+            match &args[*i as usize] {
+                // ivars used more than once must take the same argument everywhere
+                Some(arg) => args_equal(set, arg.unshifted_id, -arg.shift, node, depth, 0),
+                None => {
+                    args[*i as usize] = Some(DirectArg {
+                        unshifted_id: node,
+                        shift: -depth,
+                        zip: zip.clone(),
+                    });
+                    true
+                }
+            }
+        }
+        (
+            Node::NVar(name) | Node::NLinkVar(name, _),
+            Node::NVar(other_name) | Node::NLinkVar(other_name, _),
+        ) if matched_name(names, name).is_some() => matched_name(names, name) == Some(other_name),
+        // like in get_zippers() we look through linked variables to what they are bound to, unless
+        // they are bound in the body
+        (_, Node::NLinkVar(other_name, link))
+            if names.iter().all(|(_, name)| name != other_name) =>
+        {
+            match_invention(
+                body,
+                set,
+                *link,
+                depth,
+                zip,
+                names,
+                args,
+                analyzed_free_vars,
+                analyzed_cost,
+                fused_lambda_tags,
+            )
+        }
+        (Node::Prim(p), Node::Prim(q)) => p == q,
+        (Node::Var(i, tag), Node::Var(j, other_tag)) => i == j && tag == other_tag,
+        (Node::Lam(b, tag), Node::Lam(other_b, other_tag)) => {
+            if tag != other_tag {
+                return false;
+            }
+            zip.push(ZNode::Body);
+            let res = match_invention(
+                body.get(*b),
+                set,
+                *other_b,
+                depth + 1,
+                zip,
+                names,
+                args,
+                analyzed_free_vars,
+                analyzed_cost,
+                fused_lambda_tags,
+            );
+            zip.pop();
+            res
+        }
+        (Node::App(f, x), Node::App(other_f, other_x)) => {
+            zip.push(ZNode::Func);
+            let res = match_invention(
+                body.get(*f),
+                set,
+                *other_f,
+                depth,
+                zip,
+                names,
+                args,
+                analyzed_free_vars,
+                analyzed_cost,
+                fused_lambda_tags,
+            );
+            zip.pop();
+            if !res {
+                return false;
+            }
+            zip.push(ZNode::Arg);
+            let res = match_invention(
+                body.get(*x),
+                set,
+                *other_x,
+                depth,
+                zip,
+                names,
+                args,
+                analyzed_free_vars,
+                analyzed_cost,
+                fused_lambda_tags,
+            );
+            zip.pop();
+            res
+        }
+        // lets have their body under the Func zipper and their definition under the Arg zipper
+        (
+            Node::Let { var, def, body: b },
+            Node::Let {
+                var: other_var,
+                def: other_def,
+                body: other_b,
+            },
+        ) => {
+            zip.push(ZNode::Arg);
+            let res = match_invention(
+                body.get(*def),
+                set,
+                *other_def,
+                depth,
+                zip,
+                names,
+                args,
+                analyzed_free_vars,
+                analyzed_cost,
+                fused_lambda_tags,
+            );
+            zip.pop();
+            if !res {
+                return false;
+            }
+            names.push((var.clone(), other_var.clone()));
+            zip.push(ZNode::Func);
+            let res = match_invention(
+                body.get(*b),
+                set,
+                *other_b,
+                depth,
+                zip,
+                names,
+                args,
+                analyzed_free_vars,
+                analyzed_cost,
+                fused_lambda_tags,
+            );
+            zip.pop();
+            names.pop();
+            res
+        }
+        (
+            Node::RevLet {
+                inp_var,
+                def_vars,
+                def,
+                body: b,
+            },
+            Node::RevLet {
+                inp_var: other_inp_var,
+                def_vars: other_def_vars,
+                def: other_def,
+                body: other_b,
+            },
+        ) => {
+            // an input that isn't bound in the body is a free name of it, which has to be the same name in the
+            // corpus and not one of the variables the body binds
+            let inp_var_matches = match matched_name(names, inp_var) {
+                Some(name) => name == other_inp_var,
+                None => {
+                    inp_var == other_inp_var && names.iter().all(|(_, name)| name != other_inp_var)
+                }
+            };
+            if def_vars.len() != other_def_vars.len() || !inp_var_matches {
+                return false;
+            }
+            names.extend(def_vars.iter().cloned().zip(other_def_vars.iter().cloned()));
+            zip.push(ZNode::Arg);
+            let mut res = match_invention(
+                body.get(*def),
+                set,
+                *other_def,
+                depth,
+                zip,
+                names,
+                args,
+                analyzed_free_vars,
+                analyzed_cost,
+                fused_lambda_tags,
+            );
+            zip.pop();
+            if res {
+                zip.push(ZNode::Func);
+                res = match_invention(
+                    body.get(*b),
+                    set,
+                    *other_b,
+                    depth,
+                    zip,
+                    names,
+                    args,
+                    analyzed_free_vars,
+                    analyzed_cost,
+                    fused_lambda_tags,
+                );
+                zip.pop();
+            }
+            names.truncate(names.len() - def_vars.len());
+            res
+        }
+        _ => false,
+    }
+}
+
+/// structural equality of two arguments after shifting them down by `shift_a` and `shift_b` respectively.
+/// `depth` is the number of lambdas we are inside of within the arguments themselves.
+fn args_equal(set: &ExprSet, a: Idx, shift_a: i32, b: Idx, shift_b: i32, depth: i32) -> bool {
+    match (&set[a], &set[b]) {
+        (Node::NLinkVar(_, link), _) => args_equal(set, *link, shift_a, b, shift_b, depth),
+        (_, Node::NLinkVar(_, link)) => args_equal(set, a, shift_a, *link, shift_b, depth),
+        (Node::Var(i, tag), Node::Var(j, other_tag)) => {
+            tag == other_tag
+                && if *i < depth || *j < depth {
+                    i == j
+                } else {
+                    i - shift_a == j - shift_b
+                }
+        }
+        (Node::Prim(p), Node::Prim(q)) => p == q,
+        (Node::NVar(v), Node::NVar(w)) => v == w,
+        (Node::Lam(b1, tag), Node::Lam(b2, other_tag)) => {
+            tag == other_tag && args_equal(set, *b1, shift_a, *b2, shift_b, depth + 1)
+        }
+        (Node::App(f1, x1), Node::App(f2, x2)) => {
+            args_equal(set, *f1, shift_a, *f2, shift_b, depth)
+                && args_equal(set, *x1, shift_a, *x2, shift_b, depth)
+        }
+        _ => a == b,
+    }
+}
+
+/// The decisions made by `direct_rewrite_decisions()` for each node of the corpus
+struct DirectRewrite {
+    chosen: Vec<Option<(usize, Vec<DirectArg>)>>, // which invention (and its args) to rewrite with at each node, if any
+    free_names: Vec<Vec<Symbol>>, // the free RevLet inputs of each invention body, which stay used wherever it's applied
+}
+
+/// the inputs of RevLets in `e` that aren't bound within it (like `Pattern::free_named_vars`)
+fn free_rev_inputs(e: Expr, bound: &mut Vec<Symbol>, free: &mut Vec<Symbol>) {
+    match e.node() {
+        Node::Lam(b, _) => free_rev_inputs(e.get(*b), bound, free),
+        Node::App(f, x) => {
+            free_rev_inputs(e.get(*f), bound, free);
+            free_rev_inputs(e.get(*x), bound, free);
+        }
+        Node::Let { var, def, body } => {
+            free_rev_inputs(e.get(*def), bound, free);
+            bound.push(var.clone());
+            free_rev_inputs(e.get(*body), bound, free);
+            bound.pop();
+        }
+        Node::RevLet {
+            inp_var,
+            def_vars,
+            def,
+            body,
+        } => {
+            if !bound.contains(inp_var) {
+                free.push(inp_var.clone());
+            }
+            bound.extend(def_vars.iter().cloned());
+            free_rev_inputs(e.get(*def), bound, free);
+            free_rev_inputs(e.get(*body), bound, free);
+            bound.truncate(bound.len() - def_vars.len());
+        }
+        _ => {}
+    }
+}
+
+/// Bottom up pass over the corpus deciding where to apply which of the `invs`. Like `bottom_up_utility_correction()`
+/// we only rewrite at a match location if that beats the best we could do by rewriting inside it instead, which
+/// resolves conflicts between overlapping match locations. With more than one invention this finds the
+/// minimum cost cover of the corpus using all of them jointly (ties go to the earlier invention). `bodies` are
+/// the bodies to match for each invention, which may differ from `invs[i].body` (see `inline_inventions()`).
+fn direct_rewrite_decisions(
+    invs: &[Invention],
+    bodies: &[ExprOwned],
+    set: &ExprSet,
+    corpus_span: &Span,
+    cost_fn: &ExprCost,
+    cfg: &CompressionStepConfig,
+) -> DirectRewrite {
+    let mut analyzed_free_vars = AnalyzedExpr::new(FreeVarAnalysis);
+    analyzed_free_vars.analyze(set);
+    let mut analyzed_cost = AnalyzedExpr::new(cost_fn.clone());
+    analyzed_cost.analyze(set);
+
+    let app_penalties: Vec<i32> = invs
+        .iter()
+        .map(|inv| {
+            prim_cost(cost_fn, &Symbol::from(inv.name.as_str()))
+                + cost_fn.cost_app * inv.arity as i32
+        })
+        .collect();
+
+    // savings[node] is the most we can reduce the cost of `node` by rewriting in it, and inner_savings[node]
+    // is the same but without rewriting at `node` itself
+    let mut savings: Vec<i32> = vec![0; corpus_span.len()];
+    let mut inner_savings: Vec<i32> = vec![0; corpus_span.len()];
+    let mut chosen: Vec<Option<(usize, Vec<DirectArg>)>> = vec![None; corpus_span.len()];
+
+    for node in corpus_span.clone() {
+        inner_savings[node] = match &set[node] {
+            // no abstraction bodies can be rooted to the left of an app with these flags
+            Node::App(f, x) if cfg.eta_long || cfg.no_curried_bodies => {
+                inner_savings[*f] + savings[*x]
+            }
+            Node::App(f, x) => savings[*f] + savings[*x],
+            Node::Lam(b, _) => savings[*b],
+            Node::Let { def, body, .. } | Node::RevLet { def, body, .. } => {
+                savings[*def] + savings[*body]
+            }
+            _ => 0,
+        };
+        savings[node] = inner_savings[node];
+
+        if invalid_match_location(set, &cfg.fused_lambda_tags.tags, node) {
+            continue;
+        }
+        for (inv_idx, (inv, body)) in invs.iter().zip(bodies.iter()).enumerate() {
+            let mut args = vec![None; inv.arity];
+            if !match_invention(
+                body.immut(),
+                set,
+                node,
+                0,
+                &mut vec![],
+                &mut vec![],
+                &mut args,
+                &analyzed_free_vars,
+                &analyzed_cost,
+                &cfg.fused_lambda_tags.tags,
+            ) {
+                continue;
+            }
+            // an ivar that is never used in the body can't be filled in
+            if args.iter().any(|arg| arg.is_none()) {
+                continue;
+            }
+            let args: Vec<DirectArg> = args.into_iter().map(|arg| arg.unwrap()).collect();
+
+            let cost_with_rewrite = app_penalties[inv_idx]
+                + args
+                    .iter()
+                    .map(|arg| {
+                        let eta_cost = if cfg.eta_long {
+                            arity_of_arg(&arg.zip) as i32
+                                * (cost_fn.cost_lam + cost_fn.cost_app + cost_fn.cost_var)
+                        } else {
+                            0
+                        };
+                        analyzed_cost[arg.unshifted_id].0 - savings[arg.unshifted_id] + eta_cost
+                    })
+                    .sum::<i32>();
+            let savings_with_rewrite = analyzed_cost[node].0 - cost_with_rewrite;
+            if savings_with_rewrite > savings[node] {
+                savings[node] = savings_with_rewrite;
+                chosen[node] = Some((inv_idx, args));
+            }
+        }
+    }
+
+    let free_names = bodies
+        .iter()
+        .map(|body| {
+            let mut free = vec![];
+            free_rev_inputs(body.immut(), &mut vec![], &mut free);
+            free
+        })
+        .collect();
+
+    DirectRewrite { chosen, free_names }
+}
+
+/// the number of apps directly up and to the right of an argument, ie how many Funcs are at the end of
+/// the zipper to it. This is how many times it needs to be eta expanded to be in eta-long form.
+fn arity_of_arg(zip: &[ZNode]) -> usize {
+    zip.iter()
+        .rev()
+        .take_while(|znode| **znode == ZNode::Func)
+        .count()
+}
+
+/// Rewrites at the nodes `direct_rewrite_decisions()` chose, counting the uses of each invention
+struct DirectSites<'a> {
+    decisions: &'a DirectRewrite,
+    inv_names: &'a [Node],
+    cfg: &'a CompressionStepConfig,
+    uses: Vec<usize>,
+}
+
+impl<'a> RewriteSites<'a> for DirectSites<'a> {
+    fn site(&mut self, node: Idx, in_func_position: bool) -> Option<RewriteSite<'a>> {
+        // no abstraction bodies can be rooted to the left of an app with these flags
+        if in_func_position && (self.cfg.eta_long || self.cfg.no_curried_bodies) {
+            return None;
+        }
+        let (decisions, inv_names): (&'a DirectRewrite, &'a [Node]) =
+            (self.decisions, self.inv_names);
+        let (inv_idx, args) = decisions.chosen[node].as_ref()?;
+        self.uses[*inv_idx] += 1;
+        Some(RewriteSite {
+            inv_name: &inv_names[*inv_idx],
+            args: args
+                .iter()
+                .map(|arg| SiteArg {
+                    unshifted_id: arg.unshifted_id,
+                    shift: arg.shift,
+                    zip: &arg.zip,
+                })
+                .collect(),
+            free_names: &decisions.free_names[*inv_idx],
+        })
+    }
+
+    fn start_pass(&mut self) {
+        self.uses.iter_mut().for_each(|uses| *uses = 0);
+    }
+}
+
+/// Rewrites a single program using the decisions from `direct_rewrite_decisions()`, adding the number of times
+/// each invention was used to `uses`
+fn rewrite_direct(
+    root: Idx,
+    set: &ExprSet,
+    decisions: &DirectRewrite,
+    inv_names: &[Node],
+    cfg: &CompressionStepConfig,
+    uses: &mut [usize],
+) -> ExprOwned {
+    let mut sites = DirectSites {
+        decisions,
+        inv_names,
+        cfg,
+        uses: vec![0; uses.len()],
+    };
+    let rewritten = rewrite_program(set, root, &mut sites, cfg.eta_long);
+    for (total, program_uses) in uses.iter_mut().zip(sites.uses) {
+        *total += program_uses;
+    }
+    rewritten
+}
+
+/// Rewrites the programs with all of `invs` in a single bottom up pass, matching `bodies[i]` for `invs[i]`.
+/// Returns the rewritten programs along with the number of times each invention was used.
+fn rewrite_jointly(
+    programs: &[ExprOwned],
+    invs: &[Invention],
+    bodies: &[ExprOwned],
+    cfg: &CompressionStepConfig,
+) -> (Vec<ExprOwned>, Vec<usize>) {
+    let cost_fn = cfg.cost.expr_cost();
+
+    // structurally hashed exprset, so that we only make each decision once per unique subtree
+    let mut set = ExprSet::empty(Order::ChildFirst, false, true);
+    let roots: Vec<Idx> = programs
+        .iter()
+        .map(|e| e.immut().copy_rec(&mut set))
+        .collect();
+    let corpus_span: Span = 0..set.len();
+
+    let decisions = direct_rewrite_decisions(invs, bodies, &set, &corpus_span, &cost_fn, cfg);
+
+    let inv_names: Vec<Node> = invs
+        .iter()
+        .map(|inv| Node::Prim(inv.name.clone().into()))
+        .collect();
+    let mut uses = vec![0; invs.len()];
+    let rewritten = roots
+        .iter()
+        .map(|root| rewrite_direct(*root, &set, &decisions, &inv_names, cfg, &mut uses))
+        .collect();
+    (rewritten, uses)
+}
+
+/// Rewrite with the given abstractions (applied in order) by matching each abstraction body directly against
+/// the corpus, without building the zipper tables or running a pruned search like `rewrite_with_inventions()` does.
+/// Returns the rewritten programs along with the number of times each abstraction was used.
+pub fn rewrite_with_inventions_direct(
+    programs: &[ExprOwned],
+    invs: &[Invention],
+    cfg: &CompressionStepConfig,
+) -> (Vec<ExprOwned>, Vec<usize>) {
+    let mut rewritten: Vec<ExprOwned> = programs.to_vec();
+    let mut uses_of_inv: Vec<usize> = vec![];
+    for inv in invs {
+        let (new_rewritten, uses) = rewrite_jointly(
+            &rewritten,
+            std::slice::from_ref(inv),
+            std::slice::from_ref(&inv.body),
+            cfg,
+        );
+        rewritten = new_rewritten;
+        uses_of_inv.push(uses[0]);
+    }
+    (rewritten, uses_of_inv)
+}
+
+/// Rewrite with the whole library at once, picking the minimum cost cover of each program under the `ExprCost`
+/// instead of greedily applying inventions one at a time in the order they were learned. Invention bodies that
+/// use earlier inventions are matched in their inlined form (see `inline_inventions()`), so for example fn_3
+/// can be used somewhere that fn_0 would have been applied first in greedy rewriting.
+/// Returns the rewritten programs along with the number of times each abstraction was used, or an error if the
+/// inventions can't be inlined (see `inline_inventions()`).
+pub fn rewrite_with_library(
+    programs: &[ExprOwned],
+    invs: &[Invention],
+    cfg: &CompressionStepConfig,
+) -> Result<(Vec<ExprOwned>, Vec<usize>), StitchError> {
+    let bodies = inline_inventions(invs)?;
+    Ok(rewrite_jointly(programs, invs, &bodies, cfg))
+}
+
+/// Returns the body of each invention with any uses of other inventions in it inlined, so that the
+/// bodies only refer to primitives from the original corpus. Partial applications of earlier inventions
+/// are left as is. Arguments that were eta expanded when rewriting with `--eta-long` are eta reduced again
+/// when inlined so that the result stays in beta-normal form. Returns an error if the inventions use each other
+/// in a cycle (see `library_order()`) or use named variables they don't bind.
+pub fn inline_inventions(invs: &[Invention]) -> Result<Vec<ExprOwned>, StitchError> {
+    let mut inlined: Vec<Option<ExprOwned>> = vec![None; invs.len()];
+    for i in library_order(invs)? {
+        let bodies: Vec<Option<Expr>> = inlined
+            .iter()
+            .map(|body| body.as_ref().map(|body| body.immut()))
+            .collect();
+        let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+        let idx = inline_rec(
+            invs[i].body.immut(),
+            &mut set,
+            invs,
+            &bodies,
+            &mut Default::default(),
+        )?;
+        inlined[i] = Some(ExprOwned::new(set, idx));
+    }
+    Ok(inlined.into_iter().map(|body| body.unwrap()).collect())
+}
+
+/// for each invention, the indices of the other inventions that appear in its body
+fn library_dependencies(invs: &[Invention]) -> Vec<Vec<usize>> {
+    invs.iter()
+        .enumerate()
+        .map(|(i, inv)| {
+            (0..invs.len())
+                .filter(|j| {
+                    *j != i && uses_prim(inv.body.immut(), &Symbol::from(invs[*j].name.as_str()))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// true if invention `i` uses invention `j`, directly or through other inventions
+fn depends_on(deps: &[Vec<usize>], i: usize, j: usize) -> bool {
+    let mut seen = vec![false; deps.len()];
+    let mut stack = vec![i];
+    while let Some(k) = stack.pop() {
+        for dep in deps[k].iter() {
+            if *dep == j {
+                return true;
+            }
+            if !seen[*dep] {
+                seen[*dep] = true;
+                stack.push(*dep);
+            }
+        }
+    }
+    false
+}
+
+/// Indices of `invs` ordered so that every invention comes after all the inventions its body uses. This is
+/// just the order they were learned in unless the library has been refactored (see `refactor_library()`).
+/// Returns an error if there's no such order, ie if some inventions use each other in a cycle.
+pub fn library_order(invs: &[Invention]) -> Result<Vec<usize>, StitchError> {
+    let deps = library_dependencies(invs);
+    let mut done = vec![false; invs.len()];
+    let mut order = vec![];
+    while order.len() < invs.len() {
+        let i = (0..invs.len())
+            .find(|i| !done[*i] && deps[*i].iter().all(|j| done[*j]))
+            .ok_or_else(|| {
+                let cyclic: Vec<&str> = (0..invs.len())
+                    .filter(|i| !done[*i])
+                    .map(|i| invs[i].name.as_str())
+                    .collect();
+                StitchError::InvalidLibrary(format!(
+                    "{} use each other in their bodies",
+                    cyclic.join(", ")
+                ))
+            })?;
+        done[i] = true;
+        order.push(i);
+    }
+    Ok(order)
+}
+
+/// Each invention body is frozen when it's found, so an early invention can contain structure that a later
+/// invention captures. This rewrites the body of each invention (in the order they were learned) with the
+/// other inventions, treating its ivars as opaque leaves, and keeps the new body if it's cheaper. An invention
+/// is never rewritten with one that uses it, directly or indirectly, so the library stays free of cycles as long
+/// as it started out that way. Returns an error if it didn't (see `library_order()`).
+pub fn refactor_library(
+    invs: &[Invention],
+    cfg: &CompressionStepConfig,
+) -> Result<Vec<Invention>, StitchError> {
+    let cost_fn = cfg.cost.expr_cost();
+    let mut invs: Vec<Invention> = invs.to_vec();
+    for i in 0..invs.len() {
+        let deps = library_dependencies(&invs);
+        let allowed: Vec<usize> = (0..invs.len())
+            .filter(|j| *j != i && !depends_on(&deps, *j, i))
+            .collect();
+        if allowed.is_empty() {
+            continue;
+        }
+        // match against the inlined bodies so that eg fn_4 can be found in fn_0 even if fn_4 is written using fn_2
+        let inlined = inline_inventions(&invs)?;
+        let allowed_invs: Vec<Invention> = allowed.iter().map(|j| invs[*j].clone()).collect();
+        let allowed_bodies: Vec<ExprOwned> = allowed.iter().map(|j| inlined[*j].clone()).collect();
+        let (refactored, _) = rewrite_jointly(
+            std::slice::from_ref(&inlined[i]),
+            &allowed_invs,
+            &allowed_bodies,
+            cfg,
+        );
+        let refactored = refactored.into_iter().next().unwrap();
+        if refactored.cost(&cost_fn) < invs[i].body.cost(&cost_fn) {
+            invs[i].body = refactored;
+        }
+    }
+    Ok(invs)
+}
+
+/// inlines every full application of an invention that has a body in `bodies` (indexed like `invs`) into `e`.
+/// Returns an error if `e` or one of the bodies uses a named variable that isn't bound in it.
+fn inline_rec(
+    e: Expr,
+    set: &mut ExprSet,
+    invs: &[Invention],
+    bodies: &[Option<Expr>],
+    links: &mut FxHashMap<Symbol, Idx>,
+) -> Result<Idx, StitchError> {
+    // unwind the app spine to see if this is a full application of an earlier invention
+    let mut head = e;
+    let mut args: Vec<Expr> = vec![];
+    while let Node::App(f, x) = head.node() {
+        args.push(head.get(*x));
+        head = head.get(*f);
+    }
+    args.reverse();
+    if let Node::Prim(p) = head.node() {
+        if let Some(i) = invs
+            .iter()
+            .position(|inv| Symbol::from(inv.name.as_str()) == *p)
+        {
+            if let (Some(body), true) = (bodies[i], args.len() >= invs[i].arity) {
+                let new_args: Vec<Idx> = args
+                    .iter()
+                    .map(|arg| inline_rec(*arg, set, invs, bodies, links))
+                    .collect::<Result<_, _>>()?;
+                let mut idx = instantiate(
+                    body,
+                    set,
+                    &new_args[..invs[i].arity],
+                    0,
+                    0,
+                    &mut Default::default(),
+                )
+                .map_err(|name| unbound_var_error(&invs[i].name, &name))?;
+                for arg in new_args[invs[i].arity..].iter() {
+                    idx = set.add(Node::App(idx, *arg));
+                }
+                return Ok(idx);
+            }
+        }
+    }
+
+    Ok(match e.node() {
+        Node::App(f, x) => {
+            let f = inline_rec(e.get(*f), set, invs, bodies, links)?;
+            let x = inline_rec(e.get(*x), set, invs, bodies, links)?;
+            set.add(Node::App(f, x))
+        }
+        Node::Lam(b, tag) => {
+            let b = inline_rec(e.get(*b), set, invs, bodies, links)?;
+            set.add(Node::Lam(b, *tag))
+        }
+        Node::Let { var, def, body } => {
+            let def = inline_rec(e.get(*def), set, invs, bodies, links)?;
+            links.insert(var.clone(), def);
+            let body = inline_rec(e.get(*body), set, invs, bodies, links)?;
+            set.add(Node::Let {
+                var: var.clone(),
+                def,
+                body,
+            })
+        }
+        Node::RevLet {
+            inp_var,
+            def_vars,
+            def,
+            body,
+        } => {
+            let def = inline_rec(e.get(*def), set, invs, bodies, links)?;
+            // the variables the input gets destructured into are bound in the body
+            for var in def_vars.iter() {
+                links.insert(var.clone(), def);
+            }
+            let body = inline_rec(e.get(*body), set, invs, bodies, links)?;
+            set.add(Node::RevLet {
+                inp_var: inp_var.clone(),
+                def_vars: def_vars.clone(),
+                def,
+                body,
+            })
+        }
+        Node::NLinkVar(name, _) => match links.get(name) {
+            Some(link) => set.add(Node::NLinkVar(name.clone(), *link)),
+            None => {
+                return Err(StitchError::InvalidLibrary(format!(
+                    "{name} is used without being bound"
+                )))
+            }
+        },
+        node => set.add(node.clone()),
+    })
+}
+
+fn unbound_var_error(inv_name: &str, var: &Symbol) -> StitchError {
+    StitchError::InvalidLibrary(format!(
+        "can't inline {inv_name}: {var} isn't bound in its body"
+    ))
+}
+
+/// substitutes `args` in for the ivars of `body`. `depth` is the number of lambdas above us in the
+/// body and `funcs` is the number of apps directly up and to the right of us. Returns the name of any
+/// named variable the body uses without binding it.
+fn instantiate(
+    body: Expr,
+    set: &mut ExprSet,
+    args: &[Idx],
+    depth: i32,
+    funcs: usize,
+    links: &mut FxHashMap<Symbol, Idx>,
+) -> Result<Idx, Symbol> {
+    Ok(match body.node() {
+        Node::IVar(i) => {
+            let arg = eta_reduce(set, args[*i as usize], funcs);
+            if depth == 0 {
+                arg
+            } else {
+                set.get_mut(arg)
+                    .shift(depth, 0, &mut AnalyzedExpr::new(FreeVarAnalysis))
+            }
+        }
+        Node::App(f, x) => {
+            let f = instantiate(body.get(*f), set, args, depth, funcs + 1, links)?;
+            let x = instantiate(body.get(*x), set, args, depth, 0, links)?;
+            set.add(Node::App(f, x))
+        }
+        Node::Lam(b, tag) => {
+            let b = instantiate(body.get(*b), set, args, depth + 1, 0, links)?;
+            set.add(Node::Lam(b, *tag))
+        }
+        Node::Let { var, def, body: b } => {
+            let def = instantiate(body.get(*def), set, args, depth, 0, links)?;
+            links.insert(var.clone(), def);
+            let b = instantiate(body.get(*b), set, args, depth, 0, links)?;
+            set.add(Node::Let {
+                var: var.clone(),
+                def,
+                body: b,
+            })
+        }
+        Node::RevLet {
+            inp_var,
+            def_vars,
+            def,
+            body: b,
+        } => {
+            let def = instantiate(body.get(*def), set, args, depth, 0, links)?;
+            // the variables the input gets destructured into are bound in the body
+            for var in def_vars.iter() {
+                links.insert(var.clone(), def);
+            }
+            let b = instantiate(body.get(*b), set, args, depth, 0, links)?;
+            set.add(Node::RevLet {
+                inp_var: inp_var.clone(),
+                def_vars: def_vars.clone(),
+                def,
+                body: b,
+            })
+        }
+        // named variables in invention bodies should always be bound within them
+        Node::NLinkVar(name, _) => match links.get(name) {
+            Some(link) => set.add(Node::NLinkVar(name.clone(), *link)),
+            None => return Err(name.clone()),
+        },
+        node => set.add(node.clone()),
+    })
+}
+
+/// undoes the eta expansion `rewrite_fast()` does to arguments with `arity` apps directly above them,
+/// ie turns (lam (lam (f $1 $0))) back into f. Leaves anything else unchanged.
+fn eta_reduce(set: &mut ExprSet, arg: Idx, arity: usize) -> Idx {
+    if arity == 0 {
+        return arg;
+    }
+    let mut inner = arg;
+    for _ in 0..arity {
+        match &set[inner] {
+            Node::Lam(b, _) => inner = *b,
+            _ => return arg,
+        }
+    }
+    for i in 0..arity {
+        match &set[inner] {
+            Node::App(f, x) if set[*x] == Node::Var(i as i32, -1) => inner = *f,
+            _ => return arg,
+        }
+    }
+    let mut analyzed_free_vars = AnalyzedExpr::new(FreeVarAnalysis);
+    if analyzed_free_vars
+        .analyze_get(set.get(inner))
+        .iter()
+        .any(|var| *var < arity as i32)
+    {
+        return arg;
+    }
+    set.get_mut(inner)
+        .shift(-(arity as i32), 0, &mut analyzed_free_vars)
+}
+
+/// The result of `prune_library()`.
+#[derive(Debug, Clone)]
+pub struct PrunedLibrary {
+    pub invs: Vec<Invention>, // the inventions that were kept, with removed ones inlined into their bodies
+    pub removed: Vec<String>, // names of the inventions that were removed, in the order they were removed
+    pub rewritten: Vec<ExprOwned>, // the corpus with removed inventions inlined
+    pub original_cost: i32,   // corpus cost plus library size before pruning
+    pub final_cost: i32,      // corpus cost plus library size after pruning
+}
+
+/// Total cost of a library: the cost of the corpus rewritten with it plus the size of each invention body.
+pub fn library_cost(
+    invs: &[Invention],
+    rewritten: &[ExprOwned],
+    weights: &Option<Vec<f32>>,
+    tasks: &Option<Vec<String>>,
+    cost_fn: &ExprCost,
+) -> i32 {
+    min_cost(rewritten, weights, tasks, cost_fn)
+        + invs.iter().map(|inv| inv.body.cost(cost_fn)).sum::<i32>()
+}
+
+/// Later inventions often subsume earlier ones (eg fn_0 ends up only being used inside the body of fn_3),
+/// in which case the earlier one is just paying for its own body. This tries removing each invention in the
+/// order they were learned by inlining it back into the bodies of the other inventions and into the corpus,
+/// and keeps the removal if the total cost (see `library_cost()`) doesn't get worse. Inventions that are
+/// still partially applied somewhere can't be inlined away, so they're always kept. Returns an error if an
+/// invention body uses a named variable it doesn't bind.
+pub fn prune_library(
+    invs: &[Invention],
+    rewritten: &[ExprOwned],
+    weights: &Option<Vec<f32>>,
+    tasks: &Option<Vec<String>>,
+    cost_fn: &ExprCost,
+) -> Result<PrunedLibrary, StitchError> {
+    let mut invs: Vec<Invention> = invs.to_vec();
+    let mut rewritten: Vec<ExprOwned> = rewritten.to_vec();
+    let original_cost = library_cost(&invs, &rewritten, weights, tasks, cost_fn);
+    let mut cost = original_cost;
+    let mut removed: Vec<String> = vec![];
+
+    let names: Vec<String> = invs.iter().map(|inv| inv.name.clone()).collect();
+    for name in names {
+        let i = invs.iter().position(|inv| inv.name == name).unwrap();
+        let prim = Symbol::from(name.as_str());
+        let mut bodies: Vec<Option<Expr>> = vec![None; invs.len()];
+        bodies[i] = Some(invs[i].body.immut());
+        let inline = |e: Expr| -> Result<ExprOwned, StitchError> {
+            let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+            let idx = inline_rec(e, &mut set, &invs, &bodies, &mut Default::default())?;
+            Ok(ExprOwned::new(set, idx))
+        };
+
+        let new_invs: Vec<Invention> = invs
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, inv)| {
+                Ok(Invention::new(
+                    inline(inv.body.immut())?,
+                    inv.arity,
+                    &inv.name,
+                ))
+            })
+            .collect::<Result<_, StitchError>>()?;
+        let new_rewritten: Vec<ExprOwned> = rewritten
+            .iter()
+            .map(|p| inline(p.immut()))
+            .collect::<Result<_, _>>()?;
+
+        if new_invs
+            .iter()
+            .map(|inv| &inv.body)
+            .chain(new_rewritten.iter())
+            .any(|e| uses_prim(e.immut(), &prim))
+        {
+            continue;
+        }
+
+        let new_cost = library_cost(&new_invs, &new_rewritten, weights, tasks, cost_fn);
+        if new_cost <= cost {
+            invs = new_invs;
+            rewritten = new_rewritten;
+            cost = new_cost;
+            removed.push(name);
+        }
+    }
+
+    Ok(PrunedLibrary {
+        invs,
+        removed,
+        rewritten,
+        original_cost,
+        final_cost: cost,
+    })
+}
+
+/// true if the primitive `prim` appears anywhere in `e`
+fn uses_prim(e: Expr, prim: &Symbol) -> bool {
+    match e.node() {
+        Node::Prim(p) => p == prim,
+        _ => e.children().any(|child| uses_prim(e.get(child), prim)),
+    }
 }
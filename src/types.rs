@@ -0,0 +1,597 @@
+use lambdas::*;
+use rustc_hash::FxHashMap;
+use std::fmt::{self, Display, Formatter};
+
+/// A polymorphic type in the style of the DreamCoder type system, e.g. `(t0 -> t1) -> list(t0) -> list(t1)`.
+/// Arrows are represented as the constructor "->" with two arguments.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DcType {
+    Var(usize),
+    Cons(String, Vec<DcType>),
+}
+
+impl DcType {
+    pub fn arrow(from: DcType, to: DcType) -> DcType {
+        DcType::Cons("->".into(), vec![from, to])
+    }
+
+    /// parse a DreamCoder type string like `(t0 -> t1) -> list(t0) -> list(t1)`. Type variables
+    /// are written t0, t1, etc.
+    pub fn parse(s: &str) -> Result<DcType, String> {
+        let tokens = tokenize_type(s);
+        let mut pos = 0;
+        let ty = parse_arrow(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(format!("unexpected trailing tokens in type: {s}"));
+        }
+        Ok(ty)
+    }
+
+    /// splits a type like `a -> b -> c` into its argument types `[a, b]` and return type `c`, taking at most `max_args` arguments
+    pub fn uncurry(&self, max_args: usize) -> (Vec<&DcType>, &DcType) {
+        let mut args = vec![];
+        let mut ty = self;
+        while args.len() < max_args {
+            match ty {
+                DcType::Cons(name, children) if name == "->" => {
+                    args.push(&children[0]);
+                    ty = &children[1];
+                }
+                _ => break,
+            }
+        }
+        (args, ty)
+    }
+
+    fn is_arrow(&self) -> bool {
+        matches!(self, DcType::Cons(name, _) if name == "->")
+    }
+
+    fn map_vars(&self, f: &mut impl FnMut(usize) -> DcType) -> DcType {
+        match self {
+            DcType::Var(i) => f(*i),
+            DcType::Cons(name, children) => DcType::Cons(
+                name.clone(),
+                children.iter().map(|child| child.map_vars(f)).collect(),
+            ),
+        }
+    }
+}
+
+impl Display for DcType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DcType::Var(i) => write!(f, "t{i}"),
+            DcType::Cons(name, children) if name == "->" => {
+                if children[0].is_arrow() {
+                    write!(f, "({}) -> {}", children[0], children[1])
+                } else {
+                    write!(f, "{} -> {}", children[0], children[1])
+                }
+            }
+            DcType::Cons(name, children) if children.is_empty() => write!(f, "{name}"),
+            DcType::Cons(name, children) => write!(
+                f,
+                "{}({})",
+                name,
+                children
+                    .iter()
+                    .map(|child| child.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+fn tokenize_type(s: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut curr = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | ')' | ',' => {
+                if !curr.is_empty() {
+                    tokens.push(std::mem::take(&mut curr));
+                }
+                tokens.push(c.to_string());
+            }
+            '-' if chars.peek() == Some(&'>') => {
+                chars.next();
+                if !curr.is_empty() {
+                    tokens.push(std::mem::take(&mut curr));
+                }
+                tokens.push("->".into());
+            }
+            c if c.is_whitespace() => {
+                if !curr.is_empty() {
+                    tokens.push(std::mem::take(&mut curr));
+                }
+            }
+            c => curr.push(c),
+        }
+    }
+    if !curr.is_empty() {
+        tokens.push(curr);
+    }
+    tokens
+}
+
+fn parse_arrow(tokens: &[String], pos: &mut usize) -> Result<DcType, String> {
+    let from = parse_atom(tokens, pos)?;
+    if tokens.get(*pos).map(|t| t.as_str()) == Some("->") {
+        *pos += 1;
+        let to = parse_arrow(tokens, pos)?;
+        return Ok(DcType::arrow(from, to));
+    }
+    Ok(from)
+}
+
+fn parse_atom(tokens: &[String], pos: &mut usize) -> Result<DcType, String> {
+    let token = tokens
+        .get(*pos)
+        .ok_or_else(|| "unexpected end of type".to_string())?;
+    *pos += 1;
+    if token == "(" {
+        let ty = parse_arrow(tokens, pos)?;
+        expect_token(tokens, pos, ")")?;
+        return Ok(ty);
+    }
+    if token == ")" || token == "," || token == "->" {
+        return Err(format!("unexpected token in type: {token}"));
+    }
+    if let Some(Ok(i)) = token.strip_prefix('t').map(|i| i.parse::<usize>()) {
+        return Ok(DcType::Var(i));
+    }
+    let mut children = vec![];
+    if tokens.get(*pos).map(|t| t.as_str()) == Some("(") {
+        *pos += 1;
+        loop {
+            children.push(parse_arrow(tokens, pos)?);
+            match tokens.get(*pos).map(|t| t.as_str()) {
+                Some(",") => *pos += 1,
+                _ => break,
+            }
+        }
+        expect_token(tokens, pos, ")")?;
+    }
+    Ok(DcType::Cons(token.clone(), children))
+}
+
+fn expect_token(tokens: &[String], pos: &mut usize, expected: &str) -> Result<(), String> {
+    if tokens.get(*pos).map(|t| t.as_str()) != Some(expected) {
+        return Err(format!("expected {expected} in type"));
+    }
+    *pos += 1;
+    Ok(())
+}
+
+/// A substitution built up through unification
+#[derive(Debug, Clone, Default)]
+pub struct TypeContext {
+    subst: Vec<Option<DcType>>,
+}
+
+impl TypeContext {
+    pub fn fresh(&mut self) -> DcType {
+        self.subst.push(None);
+        DcType::Var(self.subst.len() - 1)
+    }
+
+    /// fully applies the substitution to a type
+    pub fn apply(&self, ty: &DcType) -> DcType {
+        ty.map_vars(&mut |i| match &self.subst[i] {
+            Some(bound) => self.apply(bound),
+            None => DcType::Var(i),
+        })
+    }
+
+    fn occurs(&self, var: usize, ty: &DcType) -> bool {
+        match ty {
+            DcType::Var(i) => {
+                *i == var
+                    || match &self.subst[*i] {
+                        Some(bound) => self.occurs(var, bound),
+                        None => false,
+                    }
+            }
+            DcType::Cons(_, children) => children.iter().any(|child| self.occurs(var, child)),
+        }
+    }
+
+    fn resolve(&self, ty: &DcType) -> DcType {
+        match ty {
+            DcType::Var(i) => match &self.subst[*i] {
+                Some(bound) => self.resolve(bound),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    pub fn unify(&mut self, a: &DcType, b: &DcType) -> Result<(), String> {
+        let a = self.resolve(a);
+        let b = self.resolve(b);
+        match (&a, &b) {
+            (DcType::Var(i), DcType::Var(j)) if i == j => Ok(()),
+            (DcType::Var(i), ty) | (ty, DcType::Var(i)) => {
+                if self.occurs(*i, ty) {
+                    return Err(format!("occurs check failed unifying {a} and {b}"));
+                }
+                self.subst[*i] = Some(ty.clone());
+                Ok(())
+            }
+            (DcType::Cons(name_a, children_a), DcType::Cons(name_b, children_b)) => {
+                if name_a != name_b || children_a.len() != children_b.len() {
+                    return Err(format!("can't unify {a} and {b}"));
+                }
+                for (child_a, child_b) in children_a.iter().zip(children_b.iter()) {
+                    self.unify(child_a, child_b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// instantiate a type whose variables are local to it (like the type of a primitive) with fresh variables
+    pub fn instantiate(&mut self, ty: &DcType) -> DcType {
+        let mut mapping: FxHashMap<usize, DcType> = FxHashMap::default();
+        ty.map_vars(&mut |i| mapping.entry(i).or_insert_with(|| self.fresh()).clone())
+    }
+}
+
+/// The principal type of a subtree of the corpus along with the types it requires of its free variables,
+/// so that it can be used in any context. Type variables are numbered from 0 and are local to the typing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeTyping {
+    pub ty: DcType,
+    pub free_vars: Vec<(i32, DcType)>, // types of the free $i variables
+    pub named_vars: Vec<(Symbol, DcType)>, // types of the free named variables
+}
+
+impl NodeTyping {
+    /// instantiate with fresh type variables in `ctx`
+    pub fn instantiate(&self, ctx: &mut TypeContext) -> NodeTyping {
+        let mut mapping: FxHashMap<usize, DcType> = FxHashMap::default();
+        let mut inst = |ty: &DcType| {
+            ty.map_vars(&mut |i| mapping.entry(i).or_insert_with(|| ctx.fresh()).clone())
+        };
+        NodeTyping {
+            ty: inst(&self.ty),
+            free_vars: self
+                .free_vars
+                .iter()
+                .map(|(i, ty)| (*i, inst(ty)))
+                .collect(),
+            named_vars: self
+                .named_vars
+                .iter()
+                .map(|(name, ty)| (name.clone(), inst(ty)))
+                .collect(),
+        }
+    }
+
+    /// apply the substitution in `ctx` and renumber type variables from 0 in order of appearance
+    pub fn canonicalize(&self, ctx: &TypeContext) -> NodeTyping {
+        let mut mapping: FxHashMap<usize, usize> = FxHashMap::default();
+        let mut canon = |ty: &DcType| {
+            ctx.apply(ty).map_vars(&mut |i| {
+                let next = mapping.len();
+                DcType::Var(*mapping.entry(i).or_insert(next))
+            })
+        };
+        let ty = canon(&self.ty);
+        let mut free_vars: Vec<(i32, DcType)> = self.free_vars.clone();
+        free_vars.sort_by_key(|(i, _)| *i);
+        let mut named_vars: Vec<(Symbol, DcType)> = self.named_vars.clone();
+        named_vars.sort_by_key(|(name, _)| name.to_string());
+        NodeTyping {
+            ty,
+            free_vars: free_vars.iter().map(|(i, ty)| (*i, canon(ty))).collect(),
+            named_vars: named_vars
+                .iter()
+                .map(|(name, ty)| (name.clone(), canon(ty)))
+                .collect(),
+        }
+    }
+}
+
+/// adds the free variable types of `other` into `free_vars` and `named_vars`, unifying the types of variables
+/// that appear in both
+fn merge_vars(
+    free_vars: &mut Vec<(i32, DcType)>,
+    named_vars: &mut Vec<(Symbol, DcType)>,
+    other: &NodeTyping,
+    ctx: &mut TypeContext,
+) -> Result<(), String> {
+    for (i, ty) in other.free_vars.iter() {
+        match free_vars.iter().find(|(j, _)| j == i) {
+            Some((_, existing)) => ctx.unify(&existing.clone(), ty)?,
+            None => free_vars.push((*i, ty.clone())),
+        }
+    }
+    for (name, ty) in other.named_vars.iter() {
+        match named_vars.iter().find(|(other_name, _)| other_name == name) {
+            Some((_, existing)) => ctx.unify(&existing.clone(), ty)?,
+            None => named_vars.push((name.clone(), ty.clone())),
+        }
+    }
+    Ok(())
+}
+
+/// Infers the principal typing of each node in `set` past the ones `typings` already has, bottom up given the
+/// types of the primitives. Primitives without a known type are given a fresh type variable. Nodes that fail to
+/// typecheck get `None`.
+pub fn infer_typings(
+    set: &ExprSet,
+    prim_types: &FxHashMap<Symbol, DcType>,
+    typings: &mut Vec<Option<NodeTyping>>,
+) {
+    typings.reserve(set.len().saturating_sub(typings.len()));
+    for node in typings.len()..set.len() {
+        let typing = infer_node(&set[node], typings, prim_types).ok();
+        typings.push(typing);
+    }
+}
+
+fn infer_node(
+    node: &Node,
+    typings: &[Option<NodeTyping>],
+    prim_types: &FxHashMap<Symbol, DcType>,
+) -> Result<NodeTyping, String> {
+    let mut ctx = TypeContext::default();
+    let child = |idx: &Idx, ctx: &mut TypeContext| -> Result<NodeTyping, String> {
+        typings[*idx]
+            .as_ref()
+            .map(|typing| typing.instantiate(ctx))
+            .ok_or_else(|| "ill typed child".to_string())
+    };
+    let typing = match node {
+        Node::Prim(p) => NodeTyping {
+            ty: match prim_types.get(p) {
+                Some(ty) => ctx.instantiate(ty),
+                None => ctx.fresh(),
+            },
+            free_vars: vec![],
+            named_vars: vec![],
+        },
+        Node::Var(i, _) => {
+            let ty = ctx.fresh();
+            NodeTyping {
+                ty: ty.clone(),
+                free_vars: vec![(*i, ty)],
+                named_vars: vec![],
+            }
+        }
+        Node::IVar(_) => NodeTyping {
+            ty: ctx.fresh(),
+            free_vars: vec![],
+            named_vars: vec![],
+        },
+        Node::NVar(name) | Node::NLinkVar(name, _) => {
+            let ty = ctx.fresh();
+            NodeTyping {
+                ty: ty.clone(),
+                free_vars: vec![],
+                named_vars: vec![(name.clone(), ty)],
+            }
+        }
+        Node::App(f, x) => {
+            let f = child(f, &mut ctx)?;
+            let x = child(x, &mut ctx)?;
+            let ret = ctx.fresh();
+            ctx.unify(&f.ty, &DcType::arrow(x.ty.clone(), ret.clone()))?;
+            let mut free_vars = f.free_vars;
+            let mut named_vars = f.named_vars;
+            merge_vars(&mut free_vars, &mut named_vars, &x, &mut ctx)?;
+            NodeTyping {
+                ty: ret,
+                free_vars,
+                named_vars,
+            }
+        }
+        Node::Lam(b, _) => {
+            let b = child(b, &mut ctx)?;
+            let arg = match b.free_vars.iter().find(|(i, _)| *i == 0) {
+                Some((_, ty)) => ty.clone(),
+                None => ctx.fresh(),
+            };
+            NodeTyping {
+                ty: DcType::arrow(arg, b.ty),
+                free_vars: b
+                    .free_vars
+                    .into_iter()
+                    .filter(|(i, _)| *i != 0)
+                    .map(|(i, ty)| (i - 1, ty))
+                    .collect(),
+                named_vars: b.named_vars,
+            }
+        }
+        Node::Let { var, def, body } => {
+            let def = child(def, &mut ctx)?;
+            let body = child(body, &mut ctx)?;
+            if let Some((_, ty)) = body.named_vars.iter().find(|(name, _)| name == var) {
+                ctx.unify(ty, &def.ty)?;
+            }
+            let mut free_vars = def.free_vars.clone();
+            let mut named_vars = def.named_vars.clone();
+            let body_without_var = NodeTyping {
+                named_vars: body
+                    .named_vars
+                    .iter()
+                    .filter(|(name, _)| name != var)
+                    .cloned()
+                    .collect(),
+                ..body.clone()
+            };
+            merge_vars(&mut free_vars, &mut named_vars, &body_without_var, &mut ctx)?;
+            NodeTyping {
+                ty: body.ty,
+                free_vars,
+                named_vars,
+            }
+        }
+        Node::RevLet {
+            inp_var,
+            def_vars,
+            def,
+            body,
+        } => {
+            // `def` is a pattern over `def_vars` that destructures `inp_var`
+            let def = child(def, &mut ctx)?;
+            let body = child(body, &mut ctx)?;
+            let mut free_vars = def.free_vars.clone();
+            let mut named_vars = def.named_vars.clone();
+            merge_vars(&mut free_vars, &mut named_vars, &body, &mut ctx)?;
+            let inp = NodeTyping {
+                ty: def.ty.clone(),
+                free_vars: vec![],
+                named_vars: vec![(inp_var.clone(), def.ty.clone())],
+            };
+            merge_vars(&mut free_vars, &mut named_vars, &inp, &mut ctx)?;
+            named_vars.retain(|(name, _)| !def_vars.contains(name));
+            NodeTyping {
+                ty: body.ty,
+                free_vars,
+                named_vars,
+            }
+        }
+    };
+    Ok(typing.canonicalize(&ctx))
+}
+
+/// Infers the type of an invention from its body, giving each ivar a single type. For example the body
+/// `(map #0 empty)` has type `(t0 -> t1) -> list(t1)`. Returns an error if the body is ill typed.
+pub fn infer_invention_type(
+    body: Expr,
+    arity: usize,
+    prim_types: &FxHashMap<Symbol, DcType>,
+) -> Result<DcType, String> {
+    // we infer a copy of the body where each ivar is a shared free variable
+    let mut ctx = TypeContext::default();
+    let ivar_types: Vec<DcType> = (0..arity).map(|_| ctx.fresh()).collect();
+
+    fn helper(
+        e: Expr,
+        ctx: &mut TypeContext,
+        env: &mut Vec<DcType>,
+        named_env: &mut Vec<(Symbol, DcType)>,
+        ivar_types: &[DcType],
+        prim_types: &FxHashMap<Symbol, DcType>,
+    ) -> Result<DcType, String> {
+        match e.node() {
+            Node::Prim(p) => Ok(match prim_types.get(p) {
+                Some(ty) => ctx.instantiate(ty),
+                None => ctx.fresh(),
+            }),
+            Node::Var(i, _) => env
+                .iter()
+                .rev()
+                .nth(*i as usize)
+                .cloned()
+                .ok_or_else(|| "invention body has free variables".to_string()),
+            Node::IVar(i) => Ok(ivar_types[*i as usize].clone()),
+            Node::NVar(name) | Node::NLinkVar(name, _) => named_env
+                .iter()
+                .rev()
+                .find(|(var, _)| var == name)
+                .map(|(_, ty)| ty.clone())
+                .ok_or_else(|| "invention body has free named variables".to_string()),
+            Node::App(f, x) => {
+                let f = helper(e.get(*f), ctx, env, named_env, ivar_types, prim_types)?;
+                let x = helper(e.get(*x), ctx, env, named_env, ivar_types, prim_types)?;
+                let ret = ctx.fresh();
+                ctx.unify(&f, &DcType::arrow(x, ret.clone()))?;
+                Ok(ret)
+            }
+            Node::Lam(b, _) => {
+                let arg = ctx.fresh();
+                env.push(arg.clone());
+                let b = helper(e.get(*b), ctx, env, named_env, ivar_types, prim_types);
+                env.pop();
+                Ok(DcType::arrow(arg, b?))
+            }
+            Node::Let { var, def, body } => {
+                let def = helper(e.get(*def), ctx, env, named_env, ivar_types, prim_types)?;
+                named_env.push((var.clone(), def));
+                let body = helper(e.get(*body), ctx, env, named_env, ivar_types, prim_types);
+                named_env.pop();
+                body
+            }
+            Node::RevLet {
+                inp_var,
+                def_vars,
+                def,
+                body,
+            } => {
+                // `def` is a pattern over `def_vars` that destructures `inp_var`, which may be free in the
+                // body (see `Pattern::free_named_vars`) in which case it's whatever is bound where it's used
+                let inp = named_env
+                    .iter()
+                    .rev()
+                    .find(|(var, _)| var == inp_var)
+                    .map(|(_, ty)| ty.clone())
+                    .unwrap_or_else(|| ctx.fresh());
+                for var in def_vars.iter() {
+                    named_env.push((var.clone(), ctx.fresh()));
+                }
+                let res = helper(e.get(*def), ctx, env, named_env, ivar_types, prim_types)
+                    .and_then(|def| ctx.unify(&inp, &def))
+                    .and_then(|_| {
+                        helper(e.get(*body), ctx, env, named_env, ivar_types, prim_types)
+                    });
+                named_env.truncate(named_env.len() - def_vars.len());
+                res
+            }
+        }
+    }
+
+    let body_ty = helper(
+        body,
+        &mut ctx,
+        &mut vec![],
+        &mut vec![],
+        &ivar_types,
+        prim_types,
+    )?;
+    let ty = ivar_types
+        .iter()
+        .rev()
+        .fold(body_ty, |acc, ivar_ty| DcType::arrow(ivar_ty.clone(), acc));
+    Ok(NodeTyping {
+        ty,
+        free_vars: vec![],
+        named_vars: vec![],
+    }
+    .canonicalize(&ctx)
+    .ty)
+}
+
+/// Checks that a use of an invention with type `inv_type` on arguments with the given typings is well typed.
+/// The arguments must already be shifted to the context of the use so that shared free variables line up.
+pub fn check_invention_use(inv_type: &DcType, arity: usize, args: &[&NodeTyping]) -> bool {
+    let mut ctx = TypeContext::default();
+    let inv_type = ctx.instantiate(inv_type);
+    let (arg_types, _) = inv_type.uncurry(arity);
+    let mut free_vars = vec![];
+    let mut named_vars = vec![];
+    for (arg_ty, arg) in arg_types.iter().zip(args.iter()) {
+        let arg = arg.instantiate(&mut ctx);
+        if ctx.unify(arg_ty, &arg.ty).is_err()
+            || merge_vars(&mut free_vars, &mut named_vars, &arg, &mut ctx).is_err()
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// parses the type strings of primitives
+pub fn parse_prim_types(
+    prim_types: &[(String, String)],
+) -> Result<FxHashMap<Symbol, DcType>, String> {
+    prim_types
+        .iter()
+        .map(|(name, ty)| Ok((Symbol::from(name.as_str()), DcType::parse(ty)?)))
+        .collect()
+}
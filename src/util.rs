@@ -1,13 +1,39 @@
 use crate::*;
 use lambdas::*;
 
+/// parses each program into its own `ExprOwned`, reporting the index of the first one that doesn't parse
+pub fn parse_programs(programs: &[String]) -> Result<Vec<ExprOwned>, StitchError> {
+    programs
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+            let idx = set.parse_extend(p).map_err(|message| StitchError::Parse {
+                program: i,
+                message,
+            })?;
+            Ok(ExprOwned::new(set, idx))
+        })
+        .collect()
+}
+
 pub fn min_cost(
     programs: &[ExprOwned],
     weights: &Option<Vec<f32>>,
     tasks: &Option<Vec<String>>,
     cost_fn: &ExprCost,
 ) -> i32 {
-    let weights = weights.clone().unwrap_or(vec![1.0; programs.len()]);
+    let costs: Vec<i32> = programs.iter().map(|p| p.cost(cost_fn)).collect();
+    min_cost_of_costs(&costs, weights, tasks)
+}
+
+/// `min_cost()` given the cost of each program rather than the programs
+pub fn min_cost_of_costs(
+    costs: &[i32],
+    weights: &Option<Vec<f32>>,
+    tasks: &Option<Vec<String>>,
+) -> i32 {
+    let weights = weights.clone().unwrap_or(vec![1.0; costs.len()]);
     if let Some(tasks) = tasks {
         let mut unique_tasks = tasks.to_vec();
         unique_tasks.sort();
@@ -17,10 +43,10 @@ pub fn min_cost(
             .map(|task| {
                 tasks
                     .iter()
-                    .zip(programs.iter().zip(weights.iter()))
-                    .filter_map(|(t, (p, w))| {
+                    .zip(costs.iter().zip(weights.iter()))
+                    .filter_map(|(t, (c, w))| {
                         if task == t {
-                            Some((p.cost(cost_fn) as f32 * w).round() as i32)
+                            Some((*c as f32 * w).round() as i32)
                         } else {
                             None
                         }
@@ -30,18 +56,56 @@ pub fn min_cost(
             })
             .sum::<i32>()
     } else {
-        programs
+        costs
             .iter()
             .zip(weights.iter())
-            .map(|(e, w)| (e.cost(cost_fn) as f32 * w).round() as i32)
+            .map(|(c, w)| (*c as f32 * w).round() as i32)
             .sum::<i32>()
     }
 }
 
-/// print some info about a Vec of programs
-pub fn programs_info(programs: &[ExprOwned], cost_fn: &ExprCost) {
-    let max_cost = programs.iter().map(|e| e.cost(cost_fn)).max().unwrap();
-    let max_depth = programs.iter().map(|e| e.depth()).max().unwrap();
+/// The cost of a task given the weighted costs of its programs: the cost of its cheapest program, or the sum of
+/// them with `expected_cost` (see `CompressionStepConfig::expected_cost`)
+pub fn task_cost(weighted_costs: impl Iterator<Item = i32>, expected_cost: bool) -> i32 {
+    if expected_cost {
+        weighted_costs.sum()
+    } else {
+        weighted_costs.min().unwrap()
+    }
+}
+
+/// Like `min_cost()`, but with `expected_cost` each task costs the sum of the weighted costs of its programs
+/// (see `task_cost()`)
+pub fn corpus_cost(
+    programs: &[ExprOwned],
+    weights: &Option<Vec<f32>>,
+    tasks: &Option<Vec<String>>,
+    cost_fn: &ExprCost,
+    expected_cost: bool,
+) -> i32 {
+    let costs: Vec<i32> = programs.iter().map(|p| p.cost(cost_fn)).collect();
+    corpus_cost_of_costs(&costs, weights, tasks, expected_cost)
+}
+
+/// `corpus_cost()` given the cost of each program rather than the programs
+pub fn corpus_cost_of_costs(
+    costs: &[i32],
+    weights: &Option<Vec<f32>>,
+    tasks: &Option<Vec<String>>,
+    expected_cost: bool,
+) -> i32 {
+    if expected_cost {
+        // summing within each task and then over tasks is just summing over all the programs
+        min_cost_of_costs(costs, weights, &None)
+    } else {
+        min_cost_of_costs(costs, weights, tasks)
+    }
+}
+
+/// print some info about a set of programs
+pub fn programs_info(programs: &ProgramSet, cost_fn: &ExprCost) {
+    let max_cost = programs.costs(cost_fn).into_iter().max().unwrap();
+    let max_depth = programs.depths().into_iter().max().unwrap();
     println!("Programs:");
     println!("\t num: {}", programs.len());
     println!("\t max cost: {max_cost}");
@@ -53,6 +117,14 @@ pub fn timestamp() -> String {
     format!("{}", chrono::Local::now().format("%Y-%m-%d_%H-%M-%S"))
 }
 
+/// the cost of a primitive under `cost_fn`, which is `cost_prim_default` unless it has its own cost
+pub fn prim_cost(cost_fn: &ExprCost, prim: &Symbol) -> i32 {
+    *cost_fn
+        .cost_prim
+        .get(prim)
+        .unwrap_or(&cost_fn.cost_prim_default)
+}
+
 pub fn compression_factor(original: i32, compressed: i32) -> f64 {
     f64::from(original) / f64::from(compressed)
 }
@@ -71,9 +143,11 @@ pub fn ivar_to_dc(e: &mut ExprMut, depth: i32, arity: i32) {
         Node::Lam(b, _) => {
             ivar_to_dc(&mut e.get(b), depth + 1, arity);
         }
-        Node::NVar(_) | Node::NLinkVar(_, _) => unreachable!(),
-        Node::Let { .. } => unreachable!(),
-        Node::RevLet { .. } => unreachable!(),
+        Node::NVar(_) | Node::NLinkVar(_, _) => {}
+        Node::Let { def, body, .. } | Node::RevLet { def, body, .. } => {
+            ivar_to_dc(&mut e.get(def), depth, arity);
+            ivar_to_dc(&mut e.get(body), depth, arity);
+        }
     }
 }
 
@@ -1,6 +1,7 @@
 use std::path::Path;
 
 use clap::Parser;
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde_json::Value;
 use stitch_core::*;
 
@@ -30,14 +31,37 @@ fn run_compression(inputs: &Input, cfg: &MultistepCompressionConfig) -> Value {
     multistep_compression(
         &inputs.train_programs,
         inputs.tasks.clone(),
-        None,
+        inputs.weights.clone(),
         inputs.name_mapping.clone(),
         None,
+        None,
         cfg,
     )
+    .unwrap()
     .1
 }
 
+/// what calling the compression step functions directly takes besides a config
+struct StepFixture {
+    programs: Vec<ExprOwned>,
+    tasks: Vec<String>,
+    weights: Vec<f32>,
+    observer: std::sync::Arc<dyn CompressionObserver>,
+}
+
+/// parses `programs`, with each one in its own task if `tasks` is None and all of them weighted 1
+fn step_fixture(programs: &[String], tasks: Option<Vec<String>>) -> StepFixture {
+    let programs = parse_programs(programs).unwrap();
+    let tasks = tasks.unwrap_or_else(|| (0..programs.len()).map(|i| i.to_string()).collect());
+    let weights = vec![1.0; programs.len()];
+    StepFixture {
+        programs,
+        tasks,
+        weights,
+        observer: std::sync::Arc::new(NoObserver),
+    }
+}
+
 fn compare_out_jsons(file: &str, expected_out_file: &str, args: &str, input_format: InputFormat) {
     let input = input_format
         .load_programs_and_tasks(std::path::Path::new(file))
@@ -441,6 +465,919 @@ fn simple21_a3_i2() {
     );
 }
 
+fn compare_direct_rewrite(file: &str, args: &str) {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new(file))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from(format!("compress {args}").split_whitespace());
+    let (step_results, _) = multistep_compression(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        input.name_mapping.clone(),
+        None,
+        None,
+        &cfg,
+    )
+    .unwrap();
+    let programs: Vec<ExprOwned> = parse_programs(&input.train_programs).unwrap();
+    let invs: Vec<Invention> = step_results.iter().map(|r| r.inv.clone()).collect();
+    let (rewritten, uses) = rewrite_with_inventions_direct(&programs, &invs, &cfg.step);
+
+    for (step_result, uses) in step_results.iter().zip(uses.iter()) {
+        assert_eq!(
+            step_result.uses, *uses as i32,
+            "uses of {}",
+            step_result.inv.name
+        );
+    }
+    if let Some(last) = step_results.last() {
+        let expected: Vec<String> = last.rewritten.iter().map(|p| p.to_string()).collect();
+        let actual: Vec<String> = rewritten.iter().map(|p| p.to_string()).collect();
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn direct_rewrite_simple() {
+    for i in 1..=17 {
+        compare_direct_rewrite(&format!("data/basic/simple{i}.json"), "-i1 -a3");
+    }
+}
+
+#[test]
+fn direct_rewrite_nuts_bolts() {
+    compare_direct_rewrite("data/cogsci/nuts-bolts.json", "-i10 -a3");
+}
+
+// timing based, so only run on request in release mode: cargo test --release -- --ignored
+#[test]
+#[ignore]
+fn direct_rewrite_speedup_dc() {
+    let input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/logo_iteration_1.json"))
+        .unwrap();
+    let mut cfg =
+        MultistepCompressionConfig::parse_from("compress -i5 -a3 --quiet".split_whitespace());
+    cfg.previous_abstractions = input.name_mapping.clone().unwrap_or_default().len();
+    let (step_results, _) = multistep_compression(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        input.name_mapping.clone(),
+        None,
+        None,
+        &cfg,
+    )
+    .unwrap();
+    let invs: Vec<Invention> = step_results.iter().map(|r| r.inv.clone()).collect();
+    assert!(!invs.is_empty());
+    let programs = parse_programs(&input.train_programs).unwrap();
+    let cost_fn = cfg.step.cost.expr_cost();
+
+    let tstart = std::time::Instant::now();
+    let (search, _, _) = rewrite_with_inventions(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        &invs,
+        &cfg,
+    )
+    .unwrap();
+    let search_time = tstart.elapsed();
+
+    let tstart = std::time::Instant::now();
+    let (direct, _) = rewrite_with_inventions_direct(&programs, &invs, &cfg.step);
+    let direct_time = tstart.elapsed();
+
+    let search_cost: i32 = parse_programs(&search)
+        .unwrap()
+        .iter()
+        .map(|p| p.cost(&cost_fn))
+        .sum();
+    let direct_cost: i32 = direct.iter().map(|p| p.cost(&cost_fn)).sum();
+    assert_eq!(direct_cost, search_cost);
+    assert!(
+        direct_time * 10 <= search_time,
+        "direct rewriting took {direct_time:?}, rewriting by search took {search_time:?}"
+    );
+}
+
+#[test]
+fn joint_rewrite_no_worse_than_greedy() {
+    for (file, args) in [
+        ("data/cogsci/nuts-bolts.json", "-i10 -a3"),
+        ("data/cogsci/furniture.json", "-i10 -a2"),
+    ] {
+        let input = InputFormat::ProgramsList
+            .load_programs_and_tasks(std::path::Path::new(file))
+            .unwrap();
+        let cfg =
+            MultistepCompressionConfig::parse_from(format!("compress {args}").split_whitespace());
+        let (step_results, _) = multistep_compression(
+            &input.train_programs,
+            input.tasks.clone(),
+            None,
+            input.name_mapping.clone(),
+            None,
+            None,
+            &cfg,
+        )
+        .unwrap();
+        let programs: Vec<ExprOwned> = parse_programs(&input.train_programs).unwrap();
+        let invs: Vec<Invention> = step_results.iter().map(|r| r.inv.clone()).collect();
+        let cost_fn = cfg.step.cost.expr_cost();
+
+        let (greedy, _) = rewrite_with_inventions_direct(&programs, &invs, &cfg.step);
+        let (joint, _) = rewrite_with_library(&programs, &invs, &cfg.step).unwrap();
+        let greedy_cost: i32 = greedy.iter().map(|p| p.cost(&cost_fn)).sum();
+        let joint_cost: i32 = joint.iter().map(|p| p.cost(&cost_fn)).sum();
+        assert!(
+            joint_cost <= greedy_cost,
+            "{}: {} > {}",
+            file,
+            joint_cost,
+            greedy_cost
+        );
+    }
+}
+
+#[test]
+fn logo_held_out() {
+    let train = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/logo/train_19.json"))
+        .unwrap();
+    let test = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/logo/test_111.json"))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from("compress -i3 -a3".split_whitespace());
+    let (step_results, output) = multistep_compression(
+        &train.train_programs,
+        None,
+        None,
+        None,
+        None,
+        Some(test.train_programs.clone()),
+        &cfg,
+    )
+    .unwrap();
+
+    let test_original_cost = output["test_original_cost"].as_i64().unwrap();
+    let test_final_cost = output["test_final_cost"].as_i64().unwrap();
+    assert!(test_final_cost <= test_original_cost);
+    assert_eq!(
+        output["test_rewritten"].as_array().unwrap().len(),
+        test.train_programs.len()
+    );
+    for (i, abstraction) in output["abstractions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .enumerate()
+    {
+        assert!(abstraction["test_final_cost"].is_i64());
+        assert!(abstraction["test_compression_ratio"].is_f64());
+        if i + 1 == step_results.len() {
+            assert_eq!(
+                abstraction["test_final_cost"].as_i64().unwrap(),
+                test_final_cost
+            );
+        }
+    }
+}
+
+#[test]
+fn nuts_bolts_prim_costs() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    // --rewrite-check makes sure the utility (computed with the per-primitive costs) matches the actual
+    // change in cost from rewriting
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i3 -a3 --rewrite-check --prim-costs data/cogsci/nuts-bolts-prim-costs.json"
+            .split_whitespace(),
+    );
+    let cost_fn = cfg.step.cost.expr_cost();
+    assert_eq!(prim_cost(&cost_fn, &Symbol::from("repeat")), 300);
+    assert_eq!(prim_cost(&cost_fn, &Symbol::from("fn_0")), 100);
+    assert_eq!(prim_cost(&cost_fn, &Symbol::from("fn_1")), 50);
+
+    let (step_results, output) =
+        multistep_compression(&input.train_programs, None, None, None, None, None, &cfg).unwrap();
+    assert!(!step_results.is_empty());
+    let programs: Vec<ExprOwned> = parse_programs(&input.train_programs).unwrap();
+    let original_cost: i32 = programs.iter().map(|p| p.cost(&cost_fn)).sum();
+    assert_eq!(
+        output["original_cost"].as_i64().unwrap(),
+        original_cost as i64
+    );
+}
+
+#[test]
+fn costs_from_log_probs() {
+    let input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/logo_iteration_1.json"))
+        .unwrap();
+    let mut cfg = MultistepCompressionConfig::parse_from(
+        "compress -i2 -a3 --rewrite-check --costs-from-log-probs".split_whitespace(),
+    );
+    cfg.previous_abstractions = input.name_mapping.clone().unwrap_or_default().len();
+    cfg.step
+        .cost
+        .apply_log_probabilities(&input.log_probabilities)
+        .unwrap();
+
+    let log_probabilities = input.log_probabilities.as_ref().unwrap();
+    let cost_fn = cfg.step.cost.expr_cost();
+    assert_eq!(cost_fn.cost_app, 0);
+    assert_eq!(
+        cost_fn.cost_var,
+        (-log_probabilities.log_variable * 100.).round() as i32
+    );
+    for (name, log_prob) in log_probabilities.productions.iter() {
+        assert_eq!(
+            prim_cost(&cost_fn, &Symbol::from(name.as_str())),
+            (-log_prob * 100.).round() as i32
+        );
+    }
+
+    // --rewrite-check makes sure the utility under these costs matches the actual change in cost
+    run_compression(&input, &cfg);
+}
+
+#[test]
+fn invention_types() {
+    let prim_types = parse_prim_types(&[
+        ("map".into(), "(t0 -> t1) -> list(t0) -> list(t1)".into()),
+        ("empty".into(), "list(t0)".into()),
+        ("+".into(), "int -> int -> int".into()),
+        ("1".into(), "int".into()),
+    ])
+    .unwrap();
+    let infer = |body: &str, arity: usize| {
+        let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+        let idx = set.parse_extend(body).unwrap();
+        infer_invention_type(set.get(idx), arity, &prim_types).map(|ty| ty.to_string())
+    };
+    assert_eq!(
+        infer("(map #0 empty)", 1).unwrap(),
+        "(t0 -> t1) -> list(t1)"
+    );
+    assert_eq!(
+        infer("(map (lam (+ $0 #0)) #1)", 2).unwrap(),
+        "int -> list(int) -> list(int)"
+    );
+    // #0 can't be both a function and an int
+    assert!(infer("(+ (#0 1) #0)", 1).is_err());
+
+    let ty = DcType::parse("(t0 -> t1 -> t1) -> list(t0) -> t1 -> t1").unwrap();
+    assert_eq!(ty.to_string(), "(t0 -> t1 -> t1) -> list(t0) -> t1 -> t1");
+}
+
+#[test]
+fn list_with_revs_typed() {
+    let input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/list_with_revs.json"))
+        .unwrap();
+    let mut cfg =
+        MultistepCompressionConfig::parse_from("compress -i3 -a3 --typed".split_whitespace());
+    cfg.previous_abstractions = input.name_mapping.clone().unwrap_or_default().len();
+    cfg.step.apply_dreamcoder_types(&input.prim_types).unwrap();
+    assert!(!cfg.step.prim_types.types.is_empty());
+
+    let output = run_compression(&input, &cfg);
+    for abstraction in output["abstractions"].as_array().unwrap() {
+        let ty = abstraction["type"].as_str().unwrap();
+        let ty = DcType::parse(ty).unwrap();
+        let arity = abstraction["arity"].as_u64().unwrap() as usize;
+        assert_eq!(ty.uncurry(arity).0.len(), arity);
+    }
+}
+
+#[test]
+fn bad_prim_types() {
+    let programs: Vec<String> = vec!["(foo (bar 1))".into(), "(foo (bar 2))".into()];
+    let StepFixture {
+        programs: parsed,
+        tasks,
+        weights,
+        observer,
+    } = step_fixture(&programs, None);
+    let mut cfg = MultistepCompressionConfig::parse_from("compress -a3 --quiet".split_whitespace());
+    cfg.step.prim_types.types = vec![("foo".into(), "int -> (".into())];
+    let res = compression_step(
+        &parsed,
+        "fn_0",
+        &cfg,
+        &tasks,
+        &weights,
+        1,
+        &[],
+        &observer,
+        &CancellationHandle::new(),
+    );
+    assert!(matches!(res, Err(StitchError::InvalidConfig(_))));
+}
+
+#[test]
+fn invalid_step_config() {
+    let programs: Vec<String> = vec!["(foo (bar 1))".into(), "(foo (bar 2))".into()];
+    let StepFixture {
+        programs: parsed,
+        tasks,
+        weights,
+        observer,
+    } = step_fixture(&programs, None);
+    let cancellation = CancellationHandle::new();
+    for args in [
+        "compress -a3 --quiet --eta-long --utility-by-rewrite --let-bodies",
+        "compress -a3 --quiet --eta-long",
+    ] {
+        let cfg = MultistepCompressionConfig::parse_from(args.split_whitespace());
+        let res = compression_step(
+            &parsed,
+            "fn_0",
+            &cfg,
+            &tasks,
+            &weights,
+            1,
+            &[],
+            &observer,
+            &cancellation,
+        );
+        assert!(matches!(res, Err(StitchError::InvalidConfig(_))), "{args}");
+    }
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -a3 -i2 --quiet --lookahead-beam 2 --batch-size 2".split_whitespace(),
+    );
+    let res = multistep_compression_internal(
+        &ProgramSet::parse(&programs).unwrap(),
+        Some(tasks),
+        Some(weights),
+        &mut vec![],
+        None,
+        &cfg,
+        &observer,
+        &cancellation,
+    );
+    assert!(matches!(res, Err(StitchError::InvalidConfig(_))));
+}
+
+#[test]
+fn list_with_revs_dreamcoder_dsl() {
+    let input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/list_with_revs.json"))
+        .unwrap();
+    let mut cfg = MultistepCompressionConfig::parse_from("compress -i3 -a3".split_whitespace());
+    cfg.previous_abstractions = input.name_mapping.clone().unwrap_or_default().len();
+    let (step_results, _) = multistep_compression(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        input.name_mapping.clone(),
+        None,
+        None,
+        &cfg,
+    )
+    .unwrap();
+
+    let dsl = input.dsl.as_ref().unwrap();
+    let new_dsl = dreamcoder_dsl_of_step_results(dsl, &step_results, -1.5).unwrap();
+    let old_productions = dsl["productions"].as_array().unwrap();
+    let new_productions = new_dsl["productions"].as_array().unwrap();
+    assert_eq!(
+        new_productions.len(),
+        old_productions.len() + step_results.len()
+    );
+    assert_eq!(new_dsl["logVariable"], dsl["logVariable"]);
+    for (prod, res) in new_productions[old_productions.len()..]
+        .iter()
+        .zip(step_results.iter())
+    {
+        assert_eq!(prod["expression"].as_str().unwrap(), res.dc_inv_str);
+        assert_eq!(prod["logProbability"].as_f64().unwrap(), -1.5);
+        assert!(prod["is_reversible"].is_boolean());
+        DcType::parse(prod["type"].as_str().unwrap()).unwrap();
+    }
+
+    // without the types of the productions there's no type to give the inventions
+    let mut untyped_dsl = dsl.clone();
+    for prod in untyped_dsl["productions"].as_array_mut().unwrap() {
+        prod.as_object_mut().unwrap().remove("type");
+    }
+    assert!(matches!(
+        dreamcoder_dsl_of_step_results(&untyped_dsl, &step_results, -1.5),
+        Err(StitchError::InvalidDsl(_))
+    ));
+}
+
+#[test]
+fn invention_reversibility() {
+    let reversible: FxHashMap<Symbol, bool> =
+        [("cons", true), ("map", true), ("+", true), ("car", false)]
+            .iter()
+            .map(|(p, r)| (Symbol::from(*p), *r))
+            .collect();
+    let is_reversible = |body: &str| {
+        let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+        let idx = set.parse_extend(body).unwrap();
+        invention_is_reversible(set.get(idx), &reversible)
+    };
+    assert!(is_reversible("(cons #0 (cons #1 #0))"));
+    assert!(is_reversible("(map (lam (+ $0 $0)) #0)"));
+    // car can't be run backwards
+    assert!(!is_reversible("(cons (car #0) #1)"));
+    // ivars inside a lambda are part of a fixed function, not something we can recover
+    assert!(!is_reversible("(map (lam (+ $0 #1)) #0)"));
+    // ivars in function position
+    assert!(!is_reversible("(#0 (cons #1 #1))"));
+}
+
+#[test]
+fn list_with_revs_reversible_only() {
+    let input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/list_with_revs.json"))
+        .unwrap();
+    let mut cfg = MultistepCompressionConfig::parse_from(
+        "compress -i3 -a3 --reversible-abstractions only".split_whitespace(),
+    );
+    cfg.previous_abstractions = input.name_mapping.clone().unwrap_or_default().len();
+    // without knowing which primitives are reversible there's nothing to restrict the search with
+    let res = multistep_compression(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        input.name_mapping.clone(),
+        None,
+        None,
+        &cfg,
+    );
+    assert!(matches!(res, Err(StitchError::InvalidConfig(_))));
+
+    cfg.step
+        .apply_dreamcoder_reversibility(&input.reversible_prims);
+    assert!(!cfg.step.reversible_prims.prims.is_empty());
+
+    let output = run_compression(&input, &cfg);
+    for abstraction in output["abstractions"].as_array().unwrap() {
+        assert_eq!(abstraction["is_reversible"].as_bool(), Some(true));
+    }
+}
+
+#[test]
+fn list_with_revs_let_bodies() {
+    let input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/list_with_revs.json"))
+        .unwrap();
+    let mut cfg = MultistepCompressionConfig::parse_from(
+        "compress -i1 -a3 --rewrite-check".split_whitespace(),
+    );
+    cfg.previous_abstractions = input.name_mapping.clone().unwrap_or_default().len();
+    let output = run_compression(&input, &cfg);
+
+    // abstractions spanning lets only add to the search space, so we can only do better
+    cfg.step.let_bodies = true;
+    let output_let_bodies = run_compression(&input, &cfg);
+    assert!(
+        output_let_bodies["abstractions"][0]["utility"]
+            .as_i64()
+            .unwrap()
+            >= output["abstractions"][0]["utility"].as_i64().unwrap()
+    );
+
+    // a body spanning the RevLet that destructures the (free) input of the program can be found, and the
+    // programs it's used in still rewrite correctly
+    let body = "let $v1, $v2 = rev($inp0 = (cons $v1 $v2)) in let $v3 = 1 in let $v4 = (repeat $v1 $v3) in (concat #0 $v4)";
+    cfg.step.follow = Some(body.to_string());
+    cfg.step.follow_prune = true;
+    let output_rev = run_compression(&input, &cfg);
+    assert_eq!(output_rev["abstractions"][0]["body"].as_str(), Some(body));
+    assert!(output_rev["abstractions"][0]["num_uses"].as_i64().unwrap() > 1);
+}
+
+#[test]
+fn step_limit_not_proven_optimal() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/furniture.json"))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from("compress -i1 -a3".split_whitespace());
+    let output = run_compression(&input, &cfg);
+    let abstraction = &output["abstractions"][0];
+    assert_eq!(abstraction["proven_optimal"].as_bool(), Some(true));
+    assert!(abstraction["remaining_upper_bound"].is_null());
+
+    // the arity zero inventions are found before the search starts, so there's always something to return. A
+    // deadline at the start of the step stops the search before it hands out a single worklist item
+    for args in [
+        "compress -i1 -a3 --step-limit 10",
+        "compress -i1 -a3 --time-limit 0",
+    ] {
+        let cfg = MultistepCompressionConfig::parse_from(args.split_whitespace());
+        let output = run_compression(&input, &cfg);
+        let abstractions = output["abstractions"].as_array().unwrap();
+        assert_eq!(abstractions.len(), 1, "{args}");
+        let abstraction = &abstractions[0];
+        assert_eq!(
+            abstraction["proven_optimal"].as_bool(),
+            Some(false),
+            "{args}"
+        );
+        assert!(
+            abstraction["remaining_upper_bound"].as_i64().unwrap()
+                > abstraction["utility"].as_i64().unwrap(),
+            "{args}"
+        );
+    }
+}
+
+#[test]
+fn lookahead_beam_beats_greedy() {
+    // T = (f A B) is the best single abstraction, but A and B are also used on their own, so abstracting them
+    // both compresses more than T followed by either one of them. With the default costs (per use savings of
+    // 1010 for T and 404 for A and B):
+    //   greedy: T (4 uses) then A (4 uses) saves 4*1010 + 4*404 = 5656
+    //   beam:   A (8 uses) then B (8 uses) saves 8*404 + 8*404 = 6464
+    let a = "(a1 a2 a3 a4 a5)";
+    let b = "(b1 b2 b3 b4 b5)";
+    let mut programs: Vec<String> = vec![];
+    for i in 0..4 {
+        programs.push(format!("(x{i} (f {a} {b}))"));
+        programs.push(format!("(y{i} {a})"));
+        programs.push(format!("(z{i} {b})"));
+    }
+    let run = |args: &str| {
+        let cfg = MultistepCompressionConfig::parse_from(args.split_whitespace());
+        multistep_compression(&programs, None, None, None, None, None, &cfg)
+            .unwrap()
+            .0
+    };
+    let final_cost =
+        |step_results: &[CompressionStepResult]| step_results.last().unwrap().final_cost;
+
+    let greedy = run("compress -i2 -a2");
+    assert_eq!(greedy[0].inv.body.to_string(), format!("(f {a} {b})"));
+    assert_eq!(
+        final_cost(&run("compress -i2 -a2 -n3")),
+        final_cost(&greedy)
+    );
+    let beam = run("compress -i2 -a2 -n3 --lookahead-beam 3");
+    let mut bodies: Vec<String> = beam.iter().map(|res| res.inv.body.to_string()).collect();
+    bodies.sort();
+    assert_eq!(bodies, vec![a.to_string(), b.to_string()]);
+    assert_eq!(final_cost(&greedy) - final_cost(&beam), 6464 - 5656);
+}
+
+#[test]
+fn diverse_inv_candidates() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let StepFixture {
+        programs,
+        tasks,
+        weights,
+        observer,
+    } = step_fixture(&input.train_programs, None);
+    let candidates = |args: &str| {
+        let cfg = MultistepCompressionConfig::parse_from(args.split_whitespace());
+        compression_step(
+            &programs,
+            "fn_0",
+            &cfg,
+            &tasks,
+            &weights,
+            1,
+            &[],
+            &observer,
+            &CancellationHandle::new(),
+        )
+        .unwrap()
+    };
+    let all = candidates("compress -a3 -n5 --quiet");
+    let diverse = candidates("compress -a3 -n5 --quiet --diversity-threshold 0.5");
+    assert!(!diverse.is_empty() && diverse.len() <= 5);
+    // the best candidate is never suppressed
+    assert_eq!(diverse[0].done.utility, all[0].done.utility);
+    for (i, a) in diverse.iter().enumerate() {
+        for b in diverse[i + 1..].iter() {
+            let locs_a = &a.done.pattern.match_locations;
+            let locs_b = &b.done.pattern.match_locations;
+            let common = locs_a
+                .iter()
+                .filter(|(loc, _)| locs_b.iter().any(|(other, _)| other == loc))
+                .count();
+            assert!(2 * common < std::cmp::min(locs_a.len(), locs_b.len()));
+        }
+    }
+    // with a pool of just the 5 best candidates, the diverse ones are picked from those
+    let pooled =
+        candidates("compress -a3 -n5 --quiet --diversity-threshold 0.5 --diversity-pool 1");
+    for res in pooled.iter() {
+        assert!(all
+            .iter()
+            .any(|other| other.inv.body.to_string() == res.inv.body.to_string()));
+    }
+}
+
+#[test]
+fn batch_size_multiple_inventions_per_iteration() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from("compress -i4 -a3".split_whitespace());
+    let sequential = run_compression(&input, &cfg);
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i4 -a3 -n6 --batch-size 2".split_whitespace(),
+    );
+    let batched = run_compression(&input, &cfg);
+    let abstractions = batched["abstractions"].as_array().unwrap();
+    assert!(!abstractions.is_empty() && abstractions.len() <= 4);
+    // the best invention of the first iteration is the same either way
+    assert_eq!(
+        abstractions[0]["body"],
+        sequential["abstractions"][0]["body"]
+    );
+    let mut prev_cost = i64::MAX;
+    for (i, abstraction) in abstractions.iter().enumerate() {
+        assert_eq!(abstraction["name"].as_str().unwrap(), format!("fn_{}", i));
+        let final_cost = abstraction["final_cost"].as_i64().unwrap();
+        assert!(final_cost < prev_cost);
+        prev_cost = final_cost;
+    }
+}
+
+/// every node under any of `roots`
+fn descendants(set: &ExprSet, roots: &[Idx]) -> FxHashSet<Idx> {
+    let mut seen: FxHashSet<Idx> = FxHashSet::default();
+    let mut stack: Vec<Idx> = roots.to_vec();
+    while let Some(idx) = stack.pop() {
+        if seen.insert(idx) {
+            stack.extend(set.get(idx).children());
+        }
+    }
+    seen
+}
+
+#[test]
+fn batch_size_matches_sequential() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i2 -a3 -n6 --batch-size 2".split_whitespace(),
+    );
+    let (batched, _) =
+        multistep_compression(&input.train_programs, None, None, None, None, None, &cfg).unwrap();
+    assert_eq!(batched.len(), 2);
+    // both came out of the first step, so their uses are in the same corpus, and they don't overlap
+    assert_eq!(batched[0].initial_cost, batched[1].initial_cost);
+    let uses_0 = descendants(&batched[0].set, &batched[0].use_exprs);
+    let uses_1 = descendants(&batched[1].set, &batched[1].use_exprs);
+    assert!(!uses_1.is_empty());
+    assert!(uses_0.is_disjoint(&uses_1));
+
+    // learning the same inventions one step at a time gives the same costs
+    let cfg = MultistepCompressionConfig::parse_from("compress -i2 -a3".split_whitespace());
+    let follow: Vec<Invention> = batched.iter().map(|res| res.inv.clone()).collect();
+    let (sequential, _) = multistep_compression(
+        &input.train_programs,
+        None,
+        None,
+        None,
+        Some(follow),
+        None,
+        &cfg,
+    )
+    .unwrap();
+    assert_eq!(sequential.len(), 2);
+    for (b, s) in batched.iter().zip(sequential.iter()) {
+        assert_eq!(b.inv.body.to_string(), s.inv.body.to_string());
+        assert_eq!(b.uses, s.uses);
+        assert_eq!(b.final_cost, s.final_cost);
+        assert!((b.multiplier - s.multiplier).abs() < 1e-9);
+        assert!((b.multiplier_wrt_orig - s.multiplier_wrt_orig).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn incremental_index_matches_fresh_index() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let StepFixture {
+        programs,
+        tasks,
+        weights,
+        observer,
+    } = step_fixture(&input.train_programs, None);
+    let cfg = MultistepCompressionConfig::parse_from("compress -a3 -n3 --quiet".split_whitespace());
+    let step = |index: &mut Option<CorpusIndex>, programs: Option<&[ExprOwned]>, name: &str| {
+        compression_step_indexed(
+            index,
+            programs,
+            name,
+            &cfg,
+            &tasks,
+            &weights,
+            1,
+            &[],
+            &observer,
+            &CancellationHandle::new(),
+        )
+        .unwrap()
+    };
+
+    let mut index = None;
+    let first = step(&mut index, Some(&programs[..]), "fn_0");
+    assert!(index.is_some());
+    let rewritten = &first[0].rewritten;
+    let incremental = step(&mut index, Some(&rewritten[..]), "fn_1");
+    let fresh = step(&mut None, Some(&rewritten[..]), "fn_1");
+    // ties between equally good inventions can be broken differently since the zippers get numbered differently
+    let utilities = |results: &[CompressionStepResult]| {
+        results
+            .iter()
+            .map(|res| res.done.utility)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(utilities(&incremental), utilities(&fresh));
+
+    // an index seeded with the programs already in a set finds the same inventions as copying them in
+    let program_set = ProgramSet::parse(&input.train_programs).unwrap();
+    let mut seeded = Some(CorpusIndex::from_program_set(
+        &program_set,
+        &cfg.step.cost.expr_cost(),
+    ));
+    assert_eq!(
+        utilities(&step(&mut seeded, None, "fn_0")),
+        utilities(&first)
+    );
+    // but compressing without programs needs an index to hold them
+    assert!(matches!(
+        compression_step_indexed(
+            &mut None,
+            None,
+            "fn_0",
+            &cfg,
+            &tasks,
+            &weights,
+            1,
+            &[],
+            &observer,
+            &CancellationHandle::new(),
+        ),
+        Err(StitchError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn incremental_index_keeps_typings() {
+    let input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/list_with_revs.json"))
+        .unwrap();
+    let StepFixture {
+        programs,
+        tasks,
+        weights,
+        observer,
+    } = step_fixture(&input.train_programs, input.tasks.clone());
+    let name_mapping = input.name_mapping.clone().unwrap_or_default();
+    let mut cfg = MultistepCompressionConfig::parse_from(
+        "compress -a3 -n3 --quiet --typed".split_whitespace(),
+    );
+    cfg.step.apply_dreamcoder_types(&input.prim_types).unwrap();
+    let step = |index: &mut Option<CorpusIndex>,
+                programs: Option<&[ExprOwned]>,
+                name: &str,
+                cfg: &MultistepCompressionConfig| {
+        compression_step_indexed(
+            index,
+            programs,
+            name,
+            cfg,
+            &tasks,
+            &weights,
+            1,
+            &name_mapping,
+            &observer,
+            &CancellationHandle::new(),
+        )
+        .unwrap()
+    };
+
+    // named after the inventions the input already has
+    let name = |i: usize| format!("fn_{}", name_mapping.len() + i);
+    let mut index = None;
+    let first = step(&mut index, Some(&programs[..]), &name(0), &cfg);
+    assert!(!index.as_ref().unwrap().typings.is_empty());
+    // the next step knows the type of the new invention, which only shows up in the rewritten parts of the corpus
+    cfg.step
+        .prim_types
+        .types
+        .push((name(0), first[0].inv_type.as_ref().unwrap().to_string()));
+    let rewritten = &first[0].rewritten;
+    let incremental = step(&mut index, Some(&rewritten[..]), &name(1), &cfg);
+    let fresh = step(&mut None, Some(&rewritten[..]), &name(1), &cfg);
+    let utilities = |results: &[CompressionStepResult]| {
+        results
+            .iter()
+            .map(|res| res.done.utility)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(utilities(&incremental), utilities(&fresh));
+}
+
+#[test]
+fn max_worklist_approximate() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from("compress -i1 -a3".split_whitespace());
+    let output = run_compression(&input, &cfg);
+    let exact = &output["abstractions"][0];
+    assert_eq!(exact["evicted_patterns"].as_u64(), Some(0));
+
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i1 -a3 --max-worklist 2".split_whitespace(),
+    );
+    let output = run_compression(&input, &cfg);
+    let approximate = &output["abstractions"][0];
+    assert!(approximate["utility"].as_i64().unwrap() <= exact["utility"].as_i64().unwrap());
+    // the first expansion alone overflows a worklist of 2, and upper bounds are loose enough that something evicted
+    // could have beaten what was found
+    assert!(approximate["evicted_patterns"].as_u64().unwrap() > 0);
+    assert_eq!(approximate["proven_optimal"].as_bool(), Some(false));
+    assert!(
+        approximate["remaining_upper_bound"].as_i64().unwrap()
+            > approximate["utility"].as_i64().unwrap()
+    );
+}
+
+#[derive(Default)]
+struct CountingObserver {
+    progress: std::sync::atomic::AtomicUsize,
+    step_results: std::sync::atomic::AtomicUsize,
+}
+
+impl CompressionObserver for CountingObserver {
+    fn on_progress(&self, _progress: &SearchProgress) {
+        self.progress
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn on_step_result(&self, _result: &CompressionStepResult) {
+        self.step_results
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn observer_and_cancellation() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i3 -a3 --progress-interval 1".split_whitespace(),
+    );
+    let observer = std::sync::Arc::new(CountingObserver::default());
+    let (step_results, _) = multistep_compression_with_observer(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        None,
+        None,
+        None,
+        &cfg,
+        observer.clone(),
+        &CancellationHandle::new(),
+    )
+    .unwrap();
+    assert_eq!(step_results.len(), 3);
+    assert_eq!(
+        observer
+            .step_results
+            .load(std::sync::atomic::Ordering::Relaxed),
+        3
+    );
+    assert!(observer.progress.load(std::sync::atomic::Ordering::Relaxed) > 0);
+
+    // cancelling up front stops after the first step, without searching
+    let cancellation = CancellationHandle::new();
+    cancellation.cancel();
+    let (step_results, _) = multistep_compression_with_observer(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        None,
+        None,
+        None,
+        &cfg,
+        std::sync::Arc::new(NoObserver),
+        &cancellation,
+    )
+    .unwrap();
+    assert!(step_results.len() <= 1);
+}
+
 #[test]
 fn nuts_bolts_a3_i10() {
     compare_out_jsons(
@@ -651,8 +1588,609 @@ fn neurosym_metavariable_with_tag() {
     );
 }
 
+/// checks that `--hole-choice` only changes the order of the search and not its result, by comparing
+/// the abstractions found with `hole_choice` against those found with breadth first hole choice
+fn compare_hole_choice(hole_choice: &str) {
+    let corpora = [
+        ("data/basic/simple1.json", "-i1 -a1"),
+        ("data/basic/simple2.json", "-i1 -a1"),
+        ("data/basic/simple3.json", "-i1 -a1"),
+        ("data/basic/simple4.json", "-i1 -a1"),
+        ("data/basic/simple5.json", "-i1 -a1"),
+        ("data/basic/simple6.json", "-i1 -a2"),
+        ("data/basic/simple7.json", "-i1 -a2"),
+        ("data/basic/simple8.json", "-i1 -a1"),
+        ("data/basic/simple9.json", "-i1 -a2"),
+        ("data/basic/simple10.json", "-i1 -a2"),
+        ("data/basic/simple11.json", "-i1 -a2"),
+        ("data/basic/simple12.json", "-i1 -a2"),
+        ("data/basic/simple13.json", "-i1 -a2"),
+        ("data/basic/simple14.json", "-i1 -a2"),
+        ("data/basic/simple15.json", "-i1 -a2"),
+        ("data/basic/simple16.json", "-i1 -a3"),
+        ("data/basic/simple17.json", "-i1 -a3"),
+    ];
+    for (file, args) in corpora {
+        let input = InputFormat::ProgramsList
+            .load_programs_and_tasks(std::path::Path::new(file))
+            .unwrap();
+        let expected = run_compression(
+            &input,
+            &MultistepCompressionConfig::parse_from(
+                format!("compress {args} --hole-choice breadth-first").split_whitespace(),
+            ),
+        );
+        let actual = run_compression(
+            &input,
+            &MultistepCompressionConfig::parse_from(
+                format!("compress {args} --hole-choice {hole_choice}").split_whitespace(),
+            ),
+        );
+        assert_eq!(
+            actual["final_cost"], expected["final_cost"],
+            "{hole_choice} final_cost mismatch on {file}"
+        );
+        let abstractions = actual["abstractions"].as_array().unwrap();
+        let expected_abstractions = expected["abstractions"].as_array().unwrap();
+        assert_eq!(abstractions.len(), expected_abstractions.len());
+        for (a, e) in abstractions.iter().zip(expected_abstractions.iter()) {
+            assert_eq!(
+                a["utility"], e["utility"],
+                "{hole_choice} utility mismatch on {file}"
+            );
+            assert_eq!(
+                a["body"], e["body"],
+                "{hole_choice} body mismatch on {file}"
+            );
+        }
+    }
+}
+
+#[test]
+fn hole_choice_high_entropy() {
+    compare_hole_choice("high-entropy");
+}
+
+#[test]
+fn hole_choice_low_entropy() {
+    compare_hole_choice("low-entropy");
+}
+
+#[test]
+fn hole_choice_many_groups() {
+    compare_hole_choice("many-groups");
+}
+
+#[test]
+fn hole_choice_few_groups() {
+    compare_hole_choice("few-groups");
+}
+
 // todo disabled bc nondeterminism with 2 equal things on the first invention (usually threading prevents that, but here for some reason you always get the same result when running from commandline and a diff result when running from test)
 // #[test]
 // fn origami_3_a3_i10() {
 //     compare_out_jsons("data/dc/origami/iteration_3_1.json", "data/expected_outputs/origami_3-a3-i10.json", "-a3", 10, InputFormat::Dreamcoder);
 // }
+
+#[test]
+fn prune_library_no_worse() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i6 -a3 --prune-library --silent".split_whitespace(),
+    );
+    let out = run_compression(&input, &cfg);
+    let pruned = &out["pruned_library"];
+    let removed = pruned["removed"].as_array().unwrap();
+    let kept = pruned["abstractions"].as_array().unwrap();
+    assert_eq!(
+        removed.len() + kept.len(),
+        out["abstractions"].as_array().unwrap().len()
+    );
+    assert!(pruned["final_cost"].as_i64().unwrap() <= pruned["original_cost"].as_i64().unwrap());
+    // removed inventions have been inlined away everywhere
+    for name in removed {
+        let name = format!("{} ", name.as_str().unwrap());
+        assert!(kept
+            .iter()
+            .all(|inv| !inv["body"].as_str().unwrap().contains(&name)));
+        assert!(pruned["rewritten"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|p| !p.as_str().unwrap().contains(&name)));
+    }
+}
+
+#[test]
+fn refactor_library_uses_later_inventions() {
+    let cfg = CompressionStepConfig::parse_from("compress".split_whitespace());
+    let inv = |body: &str, arity: usize, name: &str| {
+        let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+        let idx = set.parse_extend(body).unwrap();
+        Invention::new(ExprOwned::new(set, idx), arity, name)
+    };
+    // fn_1 only came along later, but fn_0 is just fn_1 with an extra argument
+    let invs = vec![
+        inv("(foo (bar (baz #0 #1)))", 2, "fn_0"),
+        inv("(bar (baz #0 #1))", 2, "fn_1"),
+        inv("(fn_0 x #0)", 1, "fn_2"),
+    ];
+    let refactored = refactor_library(&invs, &cfg).unwrap();
+    assert_eq!(refactored[0].body.to_string(), "(foo (fn_1 #0 #1))");
+    assert_eq!(refactored[1].body.to_string(), "(bar (baz #0 #1))");
+    assert_eq!(refactored[2].body.to_string(), "(fn_0 x #0)");
+    assert_eq!(library_order(&refactored).unwrap(), vec![1, 0, 2]);
+    // inlining still gets back to the original bodies
+    let inlined = inline_inventions(&refactored).unwrap();
+    assert_eq!(inlined[0].to_string(), "(foo (bar (baz #0 #1)))");
+    assert_eq!(inlined[2].to_string(), "(foo (bar (baz x #0)))");
+
+    // a library whose inventions use each other has no order to inline them in
+    let cyclic = vec![
+        inv("(foo (fn_1 #0))", 1, "fn_0"),
+        inv("(bar (fn_0 #0))", 1, "fn_1"),
+        inv("(baz #0)", 1, "fn_2"),
+    ];
+    assert!(matches!(
+        library_order(&cyclic),
+        Err(StitchError::InvalidLibrary(_))
+    ));
+    assert!(matches!(
+        refactor_library(&cyclic, &cfg),
+        Err(StitchError::InvalidLibrary(_))
+    ));
+
+    // the variables a RevLet destructures its input into are bound in its body
+    let rev = vec![
+        inv(
+            "let $v1, $v2 = rev($inp0 = (cons $v1 $v2)) in (repeat $v1 #0)",
+            1,
+            "fn_0",
+        ),
+        inv("(fn_0 x)", 0, "fn_1"),
+    ];
+    assert_eq!(
+        inline_inventions(&rev).unwrap()[1].to_string(),
+        "let $v1, $v2 = rev($inp0 = (cons $v1 $v2)) in (repeat $v1 x)"
+    );
+
+    // but a named variable that nothing binds can't be inlined
+    let mut set = ExprSet::empty(Order::ChildFirst, false, false);
+    let x = set.parse_extend("x").unwrap();
+    let unbound = set.add(Node::NLinkVar(Symbol::from("x"), x));
+    let foo = set.parse_extend("foo").unwrap();
+    let body = set.add(Node::App(foo, unbound));
+    let unbound = vec![
+        Invention::new(ExprOwned::new(set, body), 0, "fn_0"),
+        inv("(bar fn_0)", 0, "fn_1"),
+    ];
+    assert!(matches!(
+        inline_inventions(&unbound),
+        Err(StitchError::InvalidLibrary(_))
+    ));
+}
+
+#[test]
+fn refactor_library_multistep() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let run = |args: &str| {
+        let cfg = MultistepCompressionConfig::parse_from(args.split_whitespace());
+        multistep_compression(
+            &input.train_programs,
+            input.tasks.clone(),
+            None,
+            None,
+            None,
+            Some(input.train_programs.clone()),
+            &cfg,
+        )
+        .unwrap()
+    };
+    let (found, out) = run("compress -i6 -a3 --silent");
+    let (refactored, refactored_out) = run("compress -i6 -a3 --silent --refactor-library");
+    assert!(refactored_out["refactored_abstractions"].is_array());
+
+    // refactoring only changes how the bodies are written, not what they expand to
+    let invs = |results: &[CompressionStepResult]| {
+        inline_inventions(
+            &results
+                .iter()
+                .map(|res| res.inv.clone())
+                .collect::<Vec<Invention>>(),
+        )
+        .unwrap()
+        .iter()
+        .map(|body| body.to_string())
+        .collect::<Vec<String>>()
+    };
+    assert_eq!(invs(&refactored), invs(&found));
+
+    // the held out numbers are those of the library as it was found, step by step
+    assert_eq!(refactored_out["test_final_cost"], out["test_final_cost"]);
+    assert_eq!(refactored_out["test_rewritten"], out["test_rewritten"]);
+    for (a, b) in refactored_out["abstractions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .zip(out["abstractions"].as_array().unwrap())
+    {
+        assert_eq!(a["test_final_cost"], b["test_final_cost"]);
+        assert_eq!(a["test_num_uses"], b["test_num_uses"]);
+    }
+}
+
+#[test]
+fn score_abstraction_matches_search() {
+    let input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap();
+    let cfg =
+        MultistepCompressionConfig::parse_from("compress -i1 -a3 --silent".split_whitespace());
+    let out = run_compression(&input, &cfg);
+    let found = &out["abstractions"][0];
+
+    let score = score_abstraction(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        found["body"].as_str().unwrap(),
+        &cfg,
+    )
+    .unwrap();
+    assert_eq!(score.body, found["body"].as_str().unwrap());
+    assert_eq!(score.utility as i64, found["utility"].as_i64().unwrap());
+    assert_eq!(score.usages as i64, found["num_uses"].as_i64().unwrap());
+    assert_eq!(score.arity, 2);
+    assert!(score.match_locations.iter().all(|loc| loc.args.len() == 2));
+    assert!(score.uses_by_task.iter().map(|(_, uses)| uses).sum::<i32>() > 0);
+
+    // a body that never matches only pays for its own size
+    let score = score_abstraction(
+        &input.train_programs,
+        input.tasks.clone(),
+        None,
+        "(not_a_prim #0)",
+        &cfg,
+    )
+    .unwrap();
+    assert!(score.match_locations.is_empty());
+    assert!(score.utility < 0);
+}
+
+#[test]
+fn errors_instead_of_panics() {
+    let cfg = MultistepCompressionConfig::parse_from("compress -i1".split_whitespace());
+
+    let err = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/basic/does_not_exist.json"))
+        .unwrap_err();
+    assert!(matches!(err, StitchError::Io { .. }));
+    // a programs list isn't a dreamcoder file
+    let err = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/cogsci/nuts-bolts.json"))
+        .unwrap_err();
+    assert!(matches!(err, StitchError::Json(_)));
+
+    let programs: Vec<String> = vec!["(foo bar)".into(), "(foo (bar)".into()];
+    let err = multistep_compression(&programs, None, None, None, None, None, &cfg).unwrap_err();
+    assert!(matches!(err, StitchError::Parse { program: 1, .. }));
+
+    let programs: Vec<String> = vec!["(foo bar)".into(), "(foo baz)".into()];
+    let tasks = Some(vec!["a".to_string()]);
+    let err = multistep_compression(&programs, tasks, None, None, None, None, &cfg).unwrap_err();
+    assert!(matches!(err, StitchError::InvalidConfig(_)));
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i1 --lookahead-beam 2 --batch-size 2".split_whitespace(),
+    );
+    let err = multistep_compression(&programs, None, None, None, None, None, &cfg).unwrap_err();
+    assert!(matches!(err, StitchError::InvalidConfig(_)));
+}
+
+#[test]
+fn weighted_programs_list() {
+    let input = InputFormat::WeightedProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/basic/weighted1.json"))
+        .unwrap();
+    assert_eq!(input.train_programs.len(), 3);
+    assert_eq!(
+        input.tasks,
+        Some(vec!["t0".to_string(), "t1".to_string(), "2".to_string()])
+    );
+    assert_eq!(input.weights, Some(vec![2.0, 1.0, 1.0]));
+
+    // a plain programs list isn't a weighted one
+    let err = InputFormat::WeightedProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/basic/simple1.json"))
+        .unwrap_err();
+    assert!(matches!(err, StitchError::Json(_)));
+
+    let cfg = MultistepCompressionConfig::parse_from("compress -i1 -a3".split_whitespace());
+    let (step_results, out) = multistep_compression(
+        &input.train_programs,
+        input.tasks.clone(),
+        input.weights.clone(),
+        None,
+        None,
+        None,
+        &cfg,
+    )
+    .unwrap();
+    let programs = parse_programs(&input.train_programs).unwrap();
+    let cost_fn = &cfg.step.cost.expr_cost();
+    let weighted_cost = min_cost(&programs, &input.weights, &input.tasks, cost_fn);
+    assert_eq!(out["original_cost"].as_i64().unwrap(), weighted_cost as i64);
+    assert!(weighted_cost > min_cost(&programs, &None, &input.tasks, cost_fn));
+
+    // rewriting with the weights gives the same programs as compression did
+    let invs: Vec<Invention> = step_results.iter().map(|r| r.inv.clone()).collect();
+    assert_eq!(invs.len(), 1);
+    let (rewritten, _, _) = rewrite_with_inventions(
+        &input.train_programs,
+        input.tasks.clone(),
+        input.weights.clone(),
+        &invs,
+        &cfg,
+    )
+    .unwrap();
+    assert_eq!(serde_json::json!(rewritten), out["rewritten"]);
+}
+
+#[test]
+fn dreamcoder_posterior_weights() {
+    let mut input = InputFormat::Dreamcoder
+        .load_programs_and_tasks(std::path::Path::new("data/dc/logo_iteration_1.json"))
+        .unwrap();
+    assert!(input.weights.is_none());
+    input.use_posterior_weights().unwrap();
+    let weights = input.weights.clone().unwrap();
+    let tasks = input.tasks.clone().unwrap();
+    assert_eq!(weights.len(), input.train_programs.len());
+    // the weights of each frontier sum to 1
+    let mut total_by_task: FxHashMap<&str, f32> = FxHashMap::default();
+    for (task, weight) in tasks.iter().zip(weights.iter()) {
+        assert!(*weight >= 0. && *weight <= 1.);
+        *total_by_task.entry(task).or_default() += weight;
+    }
+    assert!(total_by_task
+        .values()
+        .all(|total| (total - 1.).abs() < 1e-4));
+
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i1 -a3 --expected-cost --rewrite-check".split_whitespace(),
+    );
+    let out = run_compression(&input, &cfg);
+    let programs = parse_programs(&input.train_programs).unwrap();
+    let cost_fn = &cfg.step.cost.expr_cost();
+    let expected_cost = corpus_cost(&programs, &input.weights, &input.tasks, cost_fn, true);
+    assert_eq!(out["original_cost"].as_i64().unwrap(), expected_cost as i64);
+    // the expected cost of a frontier is at least that of its cheapest program
+    assert!(expected_cost >= min_cost(&programs, &input.weights, &input.tasks, cost_fn));
+
+    // a programs list has no posteriors to use
+    let mut input = InputFormat::ProgramsList
+        .load_programs_and_tasks(std::path::Path::new("data/basic/simple1.json"))
+        .unwrap();
+    assert!(matches!(
+        input.use_posterior_weights(),
+        Err(StitchError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn posterior_changes_chosen_abstraction() {
+    // each task has an "a" program and a "b" program of the same size, and the posterior decides which matters
+    let mut programs: Vec<String> = vec![];
+    let mut tasks: Vec<String> = vec![];
+    for i in 0..3 {
+        programs.push(format!("(foo (a (a (a (a x{i})))))"));
+        programs.push(format!("(foo (b (b (b (b x{i})))))"));
+        tasks.push(format!("t{i}"));
+        tasks.push(format!("t{i}"));
+    }
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i1 -a3 --expected-cost --rewrite-check --silent".split_whitespace(),
+    );
+    let body = |posterior_of_a: f32| {
+        let weights: Vec<f32> = (0..programs.len())
+            .map(|i| {
+                if i % 2 == 0 {
+                    posterior_of_a
+                } else {
+                    1. - posterior_of_a
+                }
+            })
+            .collect();
+        let (_, out) = multistep_compression(
+            &programs,
+            Some(tasks.clone()),
+            Some(weights),
+            None,
+            None,
+            None,
+            &cfg,
+        )
+        .unwrap();
+        out["abstractions"][0]["body"].as_str().unwrap().to_string()
+    };
+    let likely_a = body(0.9);
+    assert!(likely_a.contains("(a ") && !likely_a.contains("(b "));
+    let likely_b = body(0.1);
+    assert!(likely_b.contains("(b ") && !likely_b.contains("(a "));
+}
+
+#[test]
+fn jsonl_dedup() {
+    let mut input = InputFormat::Jsonl
+        .load_programs_and_tasks(std::path::Path::new("data/basic/dedup.jsonl"))
+        .unwrap();
+    // the programs stay in the set they were read into rather than being printed back out
+    assert!(input.train_programs.is_empty());
+    let programs = input.train_exprs().unwrap();
+    assert_eq!(
+        programs.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        vec!["(foo (a a a))", "(bar (b b b))", "(baz (c c c))"]
+    );
+    assert_eq!(input.tasks, None);
+    assert_eq!(input.weights, Some(vec![3.0, 2.0, 1.0]));
+
+    // merging copies into a weight doesn't change the cost of the corpus
+    let copies: Vec<String> = vec![
+        "(foo (a a a))".into(),
+        "(foo (a a a))".into(),
+        "(foo (a a a))".into(),
+        "(bar (b b b))".into(),
+        "(bar (b b b))".into(),
+        "(baz (c c c))".into(),
+    ];
+    let cfg = MultistepCompressionConfig::parse_from("compress -i1 -a3".split_whitespace());
+    let cost_fn = &cfg.step.cost.expr_cost();
+    assert_eq!(
+        min_cost(&programs, &input.weights, &None, cost_fn),
+        min_cost(&parse_programs(&copies).unwrap(), &None, &None, cost_fn)
+    );
+    let (_, out) = multistep_compression_parsed(
+        input.program_set.clone().unwrap(),
+        input.tasks.clone(),
+        input.weights.clone(),
+        None,
+        None,
+        None,
+        &cfg,
+        std::sync::Arc::new(NoObserver),
+        &CancellationHandle::new(),
+    )
+    .unwrap();
+    assert_eq!(out["abstractions"][0]["body"], "(#0 #0 #0)");
+
+    // going through strings gives the same result
+    input.expand_program_set();
+    assert!(input.program_set.is_none());
+    assert_eq!(
+        run_compression(&input, &cfg)["abstractions"],
+        out["abstractions"]
+    );
+
+    // copies within a task stay apart, and programs without a task never share a name with a real task
+    let input = InputFormat::Jsonl
+        .load_programs_and_tasks(std::path::Path::new("data/basic/dedup_tasks.jsonl"))
+        .unwrap();
+    assert_eq!(
+        input.program_set.as_ref().unwrap().strings(),
+        vec![
+            "(foo (a a a))",
+            "(foo (a a a))",
+            "(bar (b b b))",
+            "(baz (c c c))"
+        ]
+    );
+    assert_eq!(
+        input.tasks,
+        Some(vec!["3".into(), "3".into(), "2'".into(), "2".into()])
+    );
+    assert_eq!(input.weights, Some(vec![0.5, 2.0, 2.0, 1.0]));
+}
+
+#[test]
+fn named_lambdas() {
+    assert_eq!(
+        named_to_de_bruijn("(lambda (x y) (f x y))").unwrap(),
+        "(lam (lam (f $1 $0)))"
+    );
+    assert_eq!(
+        named_to_de_bruijn("(lambda x (g (lambda (y) (x y)) x))").unwrap(),
+        "(lam (g (lam ($1 $0)) $0))"
+    );
+    // inner names shadow outer ones, and unbound names are primitives
+    assert_eq!(
+        named_to_de_bruijn("(lambda (x) (lambda (x) (f x y)))").unwrap(),
+        "(lam (lam (f $0 y)))"
+    );
+    assert!(named_to_de_bruijn("(lambda (x y))").is_err());
+    assert!(named_to_de_bruijn("(lambda (x) (f x)").is_err());
+
+    assert_eq!(
+        de_bruijn_to_named("(lam (lam (f $1 $0)))").unwrap(),
+        "(lambda (x0 x1) (f x0 x1))"
+    );
+    // fresh names don't clash with primitives
+    assert_eq!(
+        de_bruijn_to_named("(lam (x0 $0))").unwrap(),
+        "(lambda (x1) (x0 x1))"
+    );
+    assert_eq!(
+        invention_to_named("(f #0 (lam (g $0 #1)))", 2).unwrap(),
+        "(lambda (x0 x1) (f x0 (lambda (x2) (g x2 x1))))"
+    );
+    let program = "(lam (foo (lam (bar $0 $1)) (lam (lam $1))))";
+    assert_eq!(
+        named_to_de_bruijn(&de_bruijn_to_named(program).unwrap()).unwrap(),
+        program
+    );
+
+    let programs: Vec<String> = vec![
+        "(lambda (x) (foo (x x x)))".into(),
+        "(lambda (y z) (bar (z z z) y))".into(),
+    ];
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i1 -a3 --named-lambdas".split_whitespace(),
+    );
+    let (_, out) = multistep_compression(&programs, None, None, None, None, None, &cfg).unwrap();
+    assert_eq!(
+        out["abstractions"][0]["body_named"],
+        invention_to_named(
+            out["abstractions"][0]["body"].as_str().unwrap(),
+            out["abstractions"][0]["arity"].as_u64().unwrap() as usize
+        )
+        .unwrap()
+    );
+    for (rewritten, named) in out["rewritten"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .zip(out["rewritten_named"].as_array().unwrap())
+    {
+        assert_eq!(
+            named_to_de_bruijn(named.as_str().unwrap()).unwrap(),
+            rewritten.as_str().unwrap()
+        );
+    }
+
+    // the refactored and pruned library get named versions too
+    let cfg = MultistepCompressionConfig::parse_from(
+        "compress -i1 -a3 --named-lambdas --refactor-library --prune-library".split_whitespace(),
+    );
+    let (_, out) = multistep_compression(&programs, None, None, None, None, None, &cfg).unwrap();
+    assert_eq!(
+        out["refactored_abstractions_named"]
+            .as_array()
+            .unwrap()
+            .len(),
+        out["refactored_abstractions"].as_array().unwrap().len()
+    );
+    let pruned = &out["pruned_library"];
+    for abstraction in pruned["abstractions"].as_array().unwrap() {
+        assert_eq!(
+            abstraction["body_named"],
+            invention_to_named(
+                abstraction["body"].as_str().unwrap(),
+                abstraction["arity"].as_u64().unwrap() as usize
+            )
+            .unwrap()
+        );
+    }
+    for (rewritten, named) in pruned["rewritten"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .zip(pruned["rewritten_named"].as_array().unwrap())
+    {
+        assert_eq!(
+            named_to_de_bruijn(named.as_str().unwrap()).unwrap(),
+            rewritten.as_str().unwrap()
+        );
+    }
+}